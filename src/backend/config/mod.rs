@@ -1,6 +1,16 @@
 use serde::Deserialize;
 use std::fs;
 
+use crate::shared_models::lane;
+use crate::shared_models::tenant::PUBLIC_TENANT;
+use crate::utils::circuit_breaker;
+use crate::utils::claim_check;
+use crate::utils::file_store;
+use crate::utils::policy::Policy;
+use crate::utils::reply_store;
+use crate::utils::retry;
+use crate::utils::topology::Topology;
+
 pub fn config_parser(path: &str) -> Config {
   println!("Parsing config {}", path);
 
@@ -19,6 +29,31 @@ pub struct Config {
   // pub apid: Apid,
   pub rabbit_mq: RabbitMq,
   pub redis: Redis,
+  pub psql: Psql,
+  #[serde(default)]
+  pub policy: Policy,
+  #[serde(default)]
+  pub dedup: Dedup,
+  #[serde(default)]
+  pub outbox: Outbox,
+  #[serde(default)]
+  pub metrics: Metrics,
+  #[serde(default)]
+  pub scheduler: Scheduler,
+  #[serde(default)]
+  pub circuit_breaker: circuit_breaker::Settings,
+  #[serde(default)]
+  pub retry: retry::Retries,
+  #[serde(default)]
+  pub replies: reply_store::Settings,
+  #[serde(default)]
+  pub topology: Topology,
+  #[serde(default)]
+  pub claim_check: claim_check::Settings,
+  #[serde(default)]
+  pub files: file_store::Settings,
+  #[serde(default)]
+  pub lanes: Lanes,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -38,3 +73,220 @@ pub struct Redis {
   pub port: u16,
   pub private_key: String,
 }
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Psql {
+  pub user: String,
+  pub password: String,
+  pub host: String,
+  pub port: String,
+  pub database: String,
+  pub sslmode: String,
+}
+
+impl Psql {
+  pub fn connection_string(&self) -> String {
+    format!(
+      "host={} port={} user={} password={} dbname={} sslmode={}",
+      self.host, self.port, self.user, self.password, self.database, self.sslmode
+    )
+  }
+}
+
+/// Redelivered messages are recognised by message id for `window` seconds.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Dedup {
+  #[serde(default = "default_dedup_window")]
+  pub window: u64,
+}
+
+impl Default for Dedup {
+  fn default() -> Self {
+    Dedup {
+      window: default_dedup_window(),
+    }
+  }
+}
+
+fn default_dedup_window() -> u64 {
+  600
+}
+
+/// The outbox relay polls every `interval` milliseconds while idle, publishing at most
+/// `batch` events per round. Events are claimed for `claim` seconds and kept `retention`
+/// seconds after publishing.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Outbox {
+  #[serde(default = "default_outbox_interval")]
+  pub interval: u64,
+  #[serde(default = "default_outbox_batch")]
+  pub batch: i64,
+  #[serde(default = "default_outbox_claim")]
+  pub claim: u64,
+  #[serde(default = "default_outbox_retention")]
+  pub retention: u64,
+}
+
+impl Default for Outbox {
+  fn default() -> Self {
+    Outbox {
+      interval: default_outbox_interval(),
+      batch: default_outbox_batch(),
+      claim: default_outbox_claim(),
+      retention: default_outbox_retention(),
+    }
+  }
+}
+
+fn default_outbox_interval() -> u64 {
+  500
+}
+
+fn default_outbox_batch() -> i64 {
+  100
+}
+
+fn default_outbox_claim() -> u64 {
+  30
+}
+
+fn default_outbox_retention() -> u64 {
+  86400
+}
+
+/// Requests of every priority lane are consumed from a queue of their own with `prefetch`
+/// unacknowledged messages, and lanes with work waiting are served in proportion to their
+/// `weight`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Lanes {
+  #[serde(default = "default_interactive_lane")]
+  pub interactive: Lane,
+  #[serde(default = "default_system_lane")]
+  pub system: Lane,
+  #[serde(default = "default_batch_lane")]
+  pub batch: Lane,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Lane {
+  pub weight: u32,
+  pub prefetch: u16,
+}
+
+impl Default for Lanes {
+  fn default() -> Self {
+    Lanes {
+      interactive: default_interactive_lane(),
+      system: default_system_lane(),
+      batch: default_batch_lane(),
+    }
+  }
+}
+
+impl Lanes {
+  pub fn get(&self, lane: lane::Lane) -> &Lane {
+    match lane {
+      lane::Lane::Interactive => &self.interactive,
+      lane::Lane::System => &self.system,
+      lane::Lane::Batch => &self.batch,
+    }
+  }
+}
+
+fn default_interactive_lane() -> Lane {
+  Lane {
+    weight: 6,
+    prefetch: 10,
+  }
+}
+
+fn default_system_lane() -> Lane {
+  Lane { weight: 3, prefetch: 5 }
+}
+
+fn default_batch_lane() -> Lane {
+  Lane { weight: 1, prefetch: 2 }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Metrics {
+  #[serde(default = "default_metrics_address")]
+  pub address: String,
+  #[serde(default = "default_metrics_endpoint")]
+  pub endpoint: String,
+}
+
+impl Default for Metrics {
+  fn default() -> Self {
+    Metrics {
+      address: default_metrics_address(),
+      endpoint: default_metrics_endpoint(),
+    }
+  }
+}
+
+fn default_metrics_address() -> String {
+  String::from("0.0.0.0:9100")
+}
+
+fn default_metrics_endpoint() -> String {
+  String::from("metrics")
+}
+
+/// Due schedules are looked up every `interval` milliseconds, at most `batch` at a time,
+/// and claimed for `claim` seconds while they are published. `cron` entries are
+/// registered on startup.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Scheduler {
+  #[serde(default = "default_scheduler_interval")]
+  pub interval: u64,
+  #[serde(default = "default_scheduler_batch")]
+  pub batch: i64,
+  #[serde(default = "default_scheduler_claim")]
+  pub claim: u64,
+  #[serde(default)]
+  pub cron: Vec<CronEntry>,
+}
+
+impl Default for Scheduler {
+  fn default() -> Self {
+    Scheduler {
+      interval: default_scheduler_interval(),
+      batch: default_scheduler_batch(),
+      claim: default_scheduler_claim(),
+      cron: Vec::new(),
+    }
+  }
+}
+
+fn default_scheduler_interval() -> u64 {
+  1000
+}
+
+fn default_scheduler_batch() -> i64 {
+  100
+}
+
+fn default_scheduler_claim() -> u64 {
+  60
+}
+
+/// Send a `method` request on `model` for `tenant` on every tick of the cron
+/// `expression`, as the scheduler with `roles`. `payload` is the request body.
+#[derive(Deserialize, Clone, Debug)]
+pub struct CronEntry {
+  pub name: String,
+  pub expression: String,
+  pub model: String,
+  pub method: String,
+  #[serde(default = "default_cron_tenant")]
+  pub tenant: String,
+  #[serde(default)]
+  pub roles: Vec<String>,
+  #[serde(default)]
+  pub payload: String,
+}
+
+fn default_cron_tenant() -> String {
+  String::from(PUBLIC_TENANT)
+}
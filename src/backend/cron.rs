@@ -0,0 +1,203 @@
+use std::str::FromStr;
+
+/// A cron expression with the usual five fields, `minute hour day-of-month month
+/// day-of-week`, evaluated in UTC. Fields take `*`, values, ranges, lists and steps such as
+/// `*/15` or `1-5`; `@hourly`, `@daily`, `@weekly`, `@monthly` and `@yearly` are accepted
+/// too. As in cron, a day matches when either day field matches if both are restricted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cron {
+  minutes: u64,
+  hours: u64,
+  days: u64,
+  months: u64,
+  weekdays: u64,
+  any_day: bool,
+  any_weekday: bool,
+}
+
+const MINUTES_PER_DAY: u64 = 24 * 60;
+/// Long enough to reach the next 29th of February.
+const SEARCH_DAYS: u64 = 366 * 8;
+
+impl FromStr for Cron {
+  type Err = String;
+
+  fn from_str(expression: &str) -> Result<Cron, String> {
+    let expression = match expression.trim() {
+      "@hourly" => "0 * * * *",
+      "@daily" => "0 0 * * *",
+      "@weekly" => "0 0 * * 0",
+      "@monthly" => "0 0 1 * *",
+      "@yearly" => "0 0 1 1 *",
+      other => other,
+    };
+    let fields: Vec<&str> = expression.split_whitespace().collect();
+    if fields.len() != 5 {
+      return Err(format!("cron expression {} must have 5 fields", expression));
+    }
+    let mut weekdays = parse_field(fields[4], 0, 7)?;
+    // 7 is sunday as well
+    if weekdays & 1 << 7 != 0 {
+      weekdays = (weekdays | 1) & !(1 << 7);
+    }
+    Ok(Cron {
+      minutes: parse_field(fields[0], 0, 59)?,
+      hours: parse_field(fields[1], 0, 23)?,
+      days: parse_field(fields[2], 1, 31)?,
+      months: parse_field(fields[3], 1, 12)?,
+      weekdays,
+      any_day: fields[2] == "*",
+      any_weekday: fields[4] == "*",
+    })
+  }
+}
+
+impl Cron {
+  /// The first matching minute strictly after `time`, in unix seconds.
+  pub fn next_after(&self, time: u64) -> Option<u64> {
+    let mut minute = time / 60 + 1;
+    let limit = minute + SEARCH_DAYS * MINUTES_PER_DAY;
+    while minute < limit {
+      let day = minute / MINUTES_PER_DAY;
+      if !self.matches_day(day) {
+        minute = (day + 1) * MINUTES_PER_DAY;
+        continue;
+      }
+      if self.hours & 1 << (minute % MINUTES_PER_DAY / 60) == 0 {
+        minute = (minute / 60 + 1) * 60;
+        continue;
+      }
+      if self.minutes & 1 << (minute % 60) != 0 {
+        return Some(minute * 60);
+      }
+      minute += 1;
+    }
+    None
+  }
+
+  fn matches_day(&self, day: u64) -> bool {
+    let (_, month, day_of_month) = civil_from_days(day as i64);
+    if self.months & 1 << month == 0 {
+      return false;
+    }
+    // 1970-01-01 was a thursday
+    let weekday = (day + 4) % 7;
+    let day_matches = self.days & 1 << day_of_month != 0;
+    let weekday_matches = self.weekdays & 1 << weekday != 0;
+    match (self.any_day, self.any_weekday) {
+      (false, false) => day_matches || weekday_matches,
+      _ => day_matches && weekday_matches,
+    }
+  }
+}
+
+fn parse_field(field: &str, min: u64, max: u64) -> Result<u64, String> {
+  let mut mask = 0;
+  for part in field.split(',') {
+    let (range, step) = match part.split_once('/') {
+      Some((range, step)) => match step.parse::<u64>() {
+        Ok(step) if step > 0 => (range, step),
+        _ => return Err(format!("invalid step in cron field {}", field)),
+      },
+      None => (part, 1),
+    };
+    let parse = |v: &str| {
+      v.parse::<u64>()
+        .map_err(|_| format!("invalid value {} in cron field {}", v, field))
+    };
+    let (low, high) = match range.split_once('-') {
+      _ if range == "*" => (min, max),
+      Some((low, high)) => (parse(low)?, parse(high)?),
+      // `5/10` runs from 5 to the end of the range
+      None if step > 1 => (parse(range)?, max),
+      None => (parse(range)?, parse(range)?),
+    };
+    if low < min || high > max || low > high {
+      return Err(format!("cron field {} is outside {}-{}", field, min, max));
+    }
+    for value in (low..=high).step_by(step as usize) {
+      mask |= 1 << value;
+    }
+  }
+  Ok(mask)
+}
+
+/// Year, month and day of a number of days since 1970-01-01.
+fn civil_from_days(days: i64) -> (i64, u64, u64) {
+  let z = days + 719468;
+  let era = z.div_euclid(146097);
+  let day_of_era = z - era * 146097;
+  let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+  let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+  let mp = (5 * day_of_year + 2) / 153;
+  let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u64;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 } as u64;
+  let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+  (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const DAY: u64 = 24 * 60 * 60;
+
+  fn next(expression: &str, time: u64) -> Option<u64> {
+    expression.parse::<Cron>().unwrap().next_after(time)
+  }
+
+  #[test]
+  fn steps_through_minutes() {
+    assert_eq!(next("*/15 * * * *", 0), Some(900));
+    assert_eq!(next("*/15 * * * *", 899), Some(900));
+    assert_eq!(next("*/15 * * * *", 900), Some(1800));
+    // `5/10` runs from 5 to the end of the range
+    assert_eq!(next("5/10 * * * *", 0), Some(300));
+    assert_eq!(next("5/10 * * * *", 56 * 60), Some(3600 + 300));
+  }
+
+  #[test]
+  fn shortcuts_match_their_expressions() {
+    assert_eq!("@daily".parse::<Cron>(), "0 0 * * *".parse::<Cron>());
+    assert_eq!(next("@hourly", 0), Some(3600));
+    assert_eq!(next("@daily", 0), Some(DAY));
+    assert_eq!("0 0 * * 7".parse::<Cron>(), "0 0 * * 0".parse::<Cron>());
+  }
+
+  #[test]
+  fn matches_weekdays_and_days_of_month() {
+    // 1970-01-01 was a thursday, the first monday is the 5th
+    assert_eq!(next("0 12 * * 1", 0), Some(4 * DAY + 12 * 3600));
+    // the 13th or any friday, whichever comes first
+    assert_eq!(next("0 0 13 * 5", 0), Some(DAY));
+    // the next 29th of february is in 1972
+    assert_eq!(next("0 0 29 2 *", 0), Some(789 * DAY));
+  }
+
+  #[test]
+  fn never_runs_on_days_that_do_not_exist() {
+    assert_eq!(next("0 0 31 2 *", 0), None);
+  }
+
+  #[test]
+  fn rejects_invalid_expressions() {
+    for expression in &[
+      "* * * *",
+      "60 * * * *",
+      "*/0 * * * *",
+      "5-1 * * * *",
+      "a * * * *",
+      "* * 0 * *",
+    ] {
+      assert!(expression.parse::<Cron>().is_err(), "{} parsed", expression);
+    }
+  }
+
+  #[test]
+  fn converts_days_to_dates() {
+    assert_eq!(civil_from_days(0), (1970, 1, 1));
+    assert_eq!(civil_from_days(-1), (1969, 12, 31));
+    assert_eq!(civil_from_days(789), (1972, 2, 29));
+    assert_eq!(civil_from_days(11016), (2000, 2, 29));
+  }
+}
@@ -0,0 +1,370 @@
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use thiserror::Error;
+use tokio_postgres::types::ToSql;
+use tokio_postgres::{Client, Row};
+use validator::Validate;
+
+use super::outbox::{self, OutboxEvent};
+use super::query::SqlQuery;
+use super::stream::StreamWriter;
+use crate::shared_models::crud::{self, Record, RecordId, Update};
+use crate::shared_models::event::{RecordCreated, RecordDeleted, RecordUpdated};
+use crate::shared_models::list::{ListQuery, Page, MAX_LIMIT};
+use crate::shared_models::request_response::{Request, Response};
+use crate::shared_models::stream::ExportProgress;
+use crate::shared_models::tenant::Tenant;
+use crate::utils::helpers;
+
+const SERVICE_USER: &str = "backend_service";
+
+/// A model served over the bus. Implementors get `create`, `get`, `list`, `update` and
+/// `delete` on the `<NAME>.request` routing key once registered with a `ModelRegistry`.
+pub trait Model: Serialize + DeserializeOwned + Validate {
+  const NAME: &'static str;
+  const TABLE: &'static str;
+  const FIELDS: &'static [&'static str];
+}
+
+pub struct ModelDef {
+  pub name: &'static str,
+  pub table: &'static str,
+  pub fields: &'static [&'static str],
+  validate: fn(Value) -> Result<Value, String>,
+}
+
+impl ModelDef {
+  pub fn of<T: Model>() -> ModelDef {
+    ModelDef {
+      name: T::NAME,
+      table: T::TABLE,
+      fields: T::FIELDS,
+      validate: validate_as::<T>,
+    }
+  }
+
+  /// Binding key matching requests for every tenant, `<model>.request.<tenant>`.
+  pub fn routing_key(&self) -> String {
+    format!("{}.request.*", self.name)
+  }
+
+  fn validate(&self, data: Value) -> Result<Value, CrudError> {
+    let data = (self.validate)(data).map_err(CrudError::Invalid)?;
+    if let Some(object) = data.as_object() {
+      if let Some(field) = object.keys().find(|k| !self.fields.contains(&k.as_str())) {
+        return Err(CrudError::Invalid(format!("unknown field {}", field)));
+      }
+    }
+    Ok(data)
+  }
+}
+
+fn validate_as<T: Model>(data: Value) -> Result<Value, String> {
+  let model: T = serde_json::from_value(data).map_err(|e| e.to_string())?;
+  model.validate().map_err(|e| e.to_string())?;
+  serde_json::to_value(&model).map_err(|e| e.to_string())
+}
+
+#[derive(Default)]
+pub struct ModelRegistry {
+  models: HashMap<String, ModelDef>,
+}
+
+impl ModelRegistry {
+  pub fn register<T: Model>(mut self) -> Self {
+    self.models.insert(T::NAME.to_string(), ModelDef::of::<T>());
+    self
+  }
+
+  pub fn get(&self, name: &str) -> Option<&ModelDef> {
+    self.models.get(name)
+  }
+
+  pub fn routing_keys(&self) -> Vec<String> {
+    self.models.values().map(|m| m.routing_key()).collect()
+  }
+
+  /// Create the backing table of every registered model if it does not exist yet.
+  pub async fn migrate(&self, db: &Client) -> Result<(), tokio_postgres::Error> {
+    for model in self.models.values() {
+      db.batch_execute(&format!(
+        "CREATE TABLE IF NOT EXISTS {table} (
+          id TEXT PRIMARY KEY,
+          data JSONB NOT NULL,
+          created_at BIGINT NOT NULL,
+          updated_at BIGINT NOT NULL
+        );
+        ALTER TABLE {table} ADD COLUMN IF NOT EXISTS tenant_id TEXT NOT NULL DEFAULT '';
+        CREATE INDEX IF NOT EXISTS {table}_tenant_id_idx ON {table} (tenant_id);",
+        table = model.table
+      ))
+      .await?;
+    }
+    Ok(())
+  }
+}
+
+#[derive(Error, Debug)]
+enum CrudError {
+  #[error("invalid payload: {0}")]
+  Invalid(String),
+  #[error("{0} not found")]
+  NotFound(String),
+  #[error("unknown method {0}")]
+  UnknownMethod(String),
+  #[error("cancelled")]
+  Cancelled,
+  #[error("database error: {0}")]
+  Database(#[from] tokio_postgres::Error),
+}
+
+impl CrudError {
+  fn status(&self) -> u16 {
+    match self {
+      CrudError::Invalid(_) => 400,
+      CrudError::NotFound(_) => 404,
+      CrudError::UnknownMethod(_) => 405,
+      CrudError::Cancelled => 409,
+      CrudError::Database(_) => 500,
+    }
+  }
+}
+
+impl From<serde_json::Error> for CrudError {
+  fn from(e: serde_json::Error) -> Self {
+    CrudError::Invalid(e.to_string())
+  }
+}
+
+pub fn is_write(method: &str) -> bool {
+  matches!(method, crud::CREATE | crud::UPDATE | crud::DELETE)
+}
+
+pub async fn handle_crud_request(db: &Client, model: &ModelDef, req: Request) -> Response {
+  let repo = Repository {
+    db,
+    model,
+    tenant: &req.tenant,
+  };
+  let result = match req.method.as_str() {
+    crud::CREATE => repo.create(&req.payload).await,
+    crud::GET => repo.get(&req.payload).await,
+    crud::LIST => repo.list(&req.payload).await,
+    crud::UPDATE => repo.update(&req.payload).await,
+    crud::DELETE => repo.delete(&req.payload).await,
+    method => Err(CrudError::UnknownMethod(method.to_string())),
+  };
+  to_response(result)
+}
+
+/// Stream every record of the tenant matching the list query in the request, one
+/// `partial` message per page followed by a `progress` message with the running count.
+pub async fn export(db: &Client, model: &ModelDef, req: Request, out: &mut StreamWriter) -> Response {
+  let repo = Repository {
+    db,
+    model,
+    tenant: &req.tenant,
+  };
+  to_response(export_pages(&repo, &req.payload, out).await)
+}
+
+async fn export_pages(repo: &Repository<'_>, payload: &[u8], out: &mut StreamWriter) -> Result<Value, CrudError> {
+  let mut query: ListQuery = if payload.is_empty() {
+    ListQuery::default()
+  } else {
+    serde_json::from_slice(payload)?
+  };
+  query.limit = MAX_LIMIT;
+  let mut exported = 0;
+  loop {
+    if out.cancelled() {
+      return Err(CrudError::Cancelled);
+    }
+    let page = repo.list_page(&query).await?;
+    exported += page.items.len() as u64;
+    out.partial(serde_json::to_vec(&page.items)?).await;
+    out.progress(serde_json::to_vec(&ExportProgress { exported })?).await;
+    match page.next_cursor {
+      Some(cursor) => query.cursor = Some(cursor),
+      None => break,
+    }
+  }
+  Ok(serde_json::to_value(ExportProgress { exported })?)
+}
+
+fn to_response(result: Result<Value, CrudError>) -> Response {
+  match result.and_then(|v| serde_json::to_vec(&v).map_err(CrudError::from)) {
+    Ok(payload) => Response::ok(SERVICE_USER, payload),
+    Err(e) => Response::error(SERVICE_USER, e.status(), e.to_string()),
+  }
+}
+
+/// Access to the records of one model, scoped to one tenant. Every statement filters on
+/// `tenant_id`, so a repository can never read or write another tenant's rows.
+struct Repository<'a> {
+  db: &'a Client,
+  model: &'a ModelDef,
+  tenant: &'a Tenant,
+}
+
+impl<'a> Repository<'a> {
+  async fn create(&self, payload: &[u8]) -> Result<Value, CrudError> {
+    let data = self.model.validate(serde_json::from_slice(payload)?)?;
+    let id = helpers::new_uuid();
+    let now = helpers::get_time() as i64;
+    let event = OutboxEvent::of(&RecordCreated {
+      model: self.model.name.to_string(),
+      tenant: self.tenant.clone(),
+      record: Record {
+        id: id.clone(),
+        data: data.clone(),
+        created_at: now as u64,
+        updated_at: now as u64,
+      },
+    })?;
+    let data = data.to_string();
+    let tenant = self.tenant.as_str();
+    let mut params: Vec<&(dyn ToSql + Sync)> = vec![&tenant, &id, &data, &now];
+    params.extend_from_slice(&event.params(&now));
+    let row = self
+      .db
+      .query_one(
+        &*format!(
+          "WITH changed AS (
+            INSERT INTO {} (tenant_id, id, data, created_at, updated_at) VALUES ($1, $2, $3::TEXT::JSONB, $4, $4)
+            RETURNING id, data, created_at, updated_at
+          ), event AS ({})
+          SELECT id, data::TEXT, created_at, updated_at FROM changed",
+          self.model.table,
+          outbox::insert_sql(5, &outbox::payload_param(5))
+        ),
+        &params,
+      )
+      .await?;
+    Ok(serde_json::to_value(row_to_record(&row)?)?)
+  }
+
+  async fn get(&self, payload: &[u8]) -> Result<Value, CrudError> {
+    let RecordId { id } = serde_json::from_slice(payload)?;
+    let row = self
+      .db
+      .query_opt(
+        &*format!(
+          "SELECT id, data::TEXT, created_at, updated_at FROM {} WHERE tenant_id = $1 AND id = $2",
+          self.model.table
+        ),
+        &[&self.tenant.as_str(), &id],
+      )
+      .await?
+      .ok_or(CrudError::NotFound(id))?;
+    Ok(serde_json::to_value(row_to_record(&row)?)?)
+  }
+
+  async fn list(&self, payload: &[u8]) -> Result<Value, CrudError> {
+    let query: ListQuery = if payload.is_empty() {
+      ListQuery::default()
+    } else {
+      serde_json::from_slice(payload)?
+    };
+    Ok(serde_json::to_value(self.list_page(&query).await?)?)
+  }
+
+  async fn list_page(&self, query: &ListQuery) -> Result<Page<Record>, CrudError> {
+    let query = SqlQuery::build(self.model, self.tenant, query).map_err(CrudError::Invalid)?;
+    let rows = self.db.query(&*query.sql, &query.params()).await?;
+    let mut items = rows.iter().map(row_to_record).collect::<Result<Vec<Record>, CrudError>>()?;
+    let next_cursor = if items.len() > query.limit {
+      items.truncate(query.limit);
+      Some(query.cursor_after(&rows[query.limit - 1])?)
+    } else {
+      None
+    };
+    Ok(Page { items, next_cursor })
+  }
+
+  async fn update(&self, payload: &[u8]) -> Result<Value, CrudError> {
+    let Update { id, data } = serde_json::from_slice(payload)?;
+    let data = self.model.validate(data)?;
+    let now = helpers::get_time() as i64;
+    // created_at is only known once the row is updated, it is filled in by the statement
+    let event = OutboxEvent::of(&RecordUpdated {
+      model: self.model.name.to_string(),
+      tenant: self.tenant.clone(),
+      record: Record {
+        id: id.clone(),
+        data: data.clone(),
+        created_at: 0,
+        updated_at: now as u64,
+      },
+    })?;
+    let data = data.to_string();
+    let tenant = self.tenant.as_str();
+    let mut params: Vec<&(dyn ToSql + Sync)> = vec![&tenant, &id, &data, &now];
+    params.extend_from_slice(&event.params(&now));
+    let row = self
+      .db
+      .query_opt(
+        &*format!(
+          "WITH changed AS (
+            UPDATE {} SET data = $3::TEXT::JSONB, updated_at = $4 WHERE tenant_id = $1 AND id = $2
+            RETURNING id, data, created_at, updated_at
+          ), event AS ({})
+          SELECT id, data::TEXT, created_at, updated_at FROM changed",
+          self.model.table,
+          outbox::insert_sql(
+            5,
+            &format!(
+              "jsonb_set({}, '{{record,created_at}}', to_jsonb(changed.created_at))",
+              outbox::payload_param(5)
+            )
+          )
+        ),
+        &params,
+      )
+      .await?
+      .ok_or(CrudError::NotFound(id))?;
+    Ok(serde_json::to_value(row_to_record(&row)?)?)
+  }
+
+  async fn delete(&self, payload: &[u8]) -> Result<Value, CrudError> {
+    let RecordId { id } = serde_json::from_slice(payload)?;
+    let now = helpers::get_time() as i64;
+    let event = OutboxEvent::of(&RecordDeleted {
+      model: self.model.name.to_string(),
+      tenant: self.tenant.clone(),
+      id: id.clone(),
+    })?;
+    let tenant = self.tenant.as_str();
+    let mut params: Vec<&(dyn ToSql + Sync)> = vec![&tenant, &id];
+    params.extend_from_slice(&event.params(&now));
+    let deleted = self
+      .db
+      .query_opt(
+        &*format!(
+          "WITH changed AS (
+            DELETE FROM {} WHERE tenant_id = $1 AND id = $2 RETURNING id
+          ), event AS ({})
+          SELECT id FROM changed",
+          self.model.table,
+          outbox::insert_sql(3, &outbox::payload_param(3))
+        ),
+        &params,
+      )
+      .await?;
+    if deleted.is_none() {
+      return Err(CrudError::NotFound(id));
+    }
+    Ok(serde_json::to_value(RecordId { id })?)
+  }
+}
+
+fn row_to_record(row: &Row) -> Result<Record, CrudError> {
+  let data: String = row.try_get(1)?;
+  Ok(Record {
+    id: row.try_get(0)?,
+    data: serde_json::from_str(&data)?,
+    created_at: row.try_get::<_, i64>(2)? as u64,
+    updated_at: row.try_get::<_, i64>(3)? as u64,
+  })
+}
@@ -0,0 +1,143 @@
+#[allow(unused_imports)]
+use log::{debug, error, info};
+use std::{sync::Arc, time::Duration};
+use tokio_postgres::Client;
+
+use super::config;
+use crate::utils::helpers;
+
+/// How often `wait_reply` looks for the reply of a message handled elsewhere.
+const WAIT_POLL: Duration = Duration::from_millis(200);
+
+pub enum Seen {
+  New,
+  /// Still being handled, or a streaming request whose replies are not kept.
+  InProgress,
+  /// Handled before, with the reply that was sent.
+  Replied(Vec<u8>),
+}
+
+/// Create the table of handled message ids if it does not exist yet.
+pub async fn migrate(db: &Client) -> Result<(), tokio_postgres::Error> {
+  db.batch_execute(
+    "CREATE TABLE IF NOT EXISTS processed_messages (
+      id TEXT PRIMARY KEY,
+      reply BYTEA,
+      claimed_at BIGINT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS processed_messages_claimed_at_idx ON processed_messages (claimed_at);",
+  )
+  .await
+}
+
+/// Message ids handled in the last `window` seconds, so a redelivered message is answered
+/// with the original reply instead of running twice. They are kept in postgres, a message
+/// is redelivered exactly when the instance handling it went away.
+#[derive(Clone)]
+pub struct Deduplicator {
+  db: Arc<Client>,
+  window: u64,
+}
+
+impl Deduplicator {
+  pub fn new(db: Arc<Client>, config: &config::Dedup) -> Deduplicator {
+    Deduplicator {
+      db,
+      window: config.window,
+    }
+  }
+
+  /// Look up a message id, claiming it when it is new. A redelivered message that was never
+  /// answered is claimed again, whoever held it is gone. Lets the message through when
+  /// postgres is unavailable.
+  pub async fn check(&self, id: &str, redelivered: bool) -> Seen {
+    let now = helpers::get_time() as i64;
+    let claimed = self
+      .db
+      .execute(
+        "INSERT INTO processed_messages (id, claimed_at) VALUES ($1, $2)
+        ON CONFLICT (id) DO UPDATE SET claimed_at = EXCLUDED.claimed_at
+        WHERE processed_messages.reply IS NULL AND $3",
+        &[&id, &now, &redelivered],
+      )
+      .await;
+    match claimed {
+      Ok(1) => return Seen::New,
+      Ok(_) => (),
+      Err(e) => {
+        error!("unable to claim message {}: {}", id, e);
+        return Seen::New;
+      }
+    }
+    match self.reply(id).await {
+      Ok(Some(reply)) => Seen::Replied(reply),
+      Ok(None) => Seen::InProgress,
+      Err(e) => {
+        error!("unable to look up message {}: {}", id, e);
+        Seen::New
+      }
+    }
+  }
+
+  pub async fn replied(&self, id: &str, reply: &[u8]) {
+    let result = self
+      .db
+      .execute("UPDATE processed_messages SET reply = $2 WHERE id = $1", &[&id, &reply])
+      .await;
+    if let Err(e) = result {
+      error!("unable to keep the reply to message {}: {}", id, e);
+    }
+  }
+
+  /// Forget a message id, so a redelivery is handled again.
+  pub async fn forget(&self, id: &str) {
+    if let Err(e) = self
+      .db
+      .execute("DELETE FROM processed_messages WHERE id = $1", &[&id])
+      .await
+    {
+      error!("unable to forget message {}: {}", id, e);
+    }
+  }
+
+  /// The reply to a message handled elsewhere, once there is one. `None` when it does not
+  /// come within `timeout`.
+  pub async fn wait_reply(&self, id: &str, timeout: Duration) -> Option<Vec<u8>> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    while tokio::time::Instant::now() < deadline {
+      match self.reply(id).await {
+        Ok(Some(reply)) => return Some(reply),
+        Ok(None) => (),
+        Err(e) => error!("unable to look up message {}: {}", id, e),
+      }
+      tokio::time::sleep(WAIT_POLL).await;
+    }
+    None
+  }
+
+  async fn reply(&self, id: &str) -> Result<Option<Vec<u8>>, tokio_postgres::Error> {
+    let row = self
+      .db
+      .query_opt("SELECT reply FROM processed_messages WHERE id = $1", &[&id])
+      .await?;
+    Ok(row.and_then(|row| row.get(0)))
+  }
+
+  /// Remove message ids older than the window, every minute.
+  pub async fn sweep(self) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+    loop {
+      interval.tick().await;
+      let before = helpers::get_time().saturating_sub(self.window) as i64;
+      match self
+        .db
+        .execute("DELETE FROM processed_messages WHERE claimed_at < $1", &[&before])
+        .await
+      {
+        Ok(0) => (),
+        Ok(n) => debug!("forgot {} handled messages", n),
+        Err(e) => error!("unable to sweep handled messages: {}", e),
+      }
+    }
+  }
+}
@@ -0,0 +1,220 @@
+use futures::future::{BoxFuture, FutureExt};
+use futures::StreamExt;
+use lapin::options::{BasicAckOptions, BasicNackOptions};
+use lapin::Consumer;
+#[allow(unused_imports)]
+use log::{debug, error, info};
+use std::collections::HashSet;
+use std::future::Future;
+use std::sync::Arc;
+use tokio_postgres::Client;
+
+use super::dedup::{Deduplicator, Seen};
+use super::scheduler::Scheduler;
+use crate::shared_models::event::Event;
+use crate::utils::helpers::matches_topic;
+use crate::utils::rabbitmq::{self, MqChannel};
+use crate::utils::topology::{Binding, Queue, Topology};
+
+/// What an event handler gets to work with besides the event itself.
+#[derive(Clone)]
+pub struct EventContext {
+  pub db: Arc<Client>,
+  pub mq: MqChannel,
+  /// For handling something later, e.g. retrying in a few minutes.
+  pub scheduler: Scheduler,
+}
+
+type Handler = Box<dyn Fn(EventContext, &[u8]) -> BoxFuture<'static, Result<(), String>> + Send + Sync>;
+
+struct Subscription {
+  key: String,
+  version: u16,
+  handle: Handler,
+}
+
+/// Domain events this service reacts to. Every subscription binds the service's durable
+/// event queue to `<subject>.<name>`.
+#[derive(Default)]
+pub struct Subscriptions {
+  subscriptions: Vec<Subscription>,
+}
+
+impl Subscriptions {
+  /// Handle events of type `E` whose subject matches `subject`, a routing key pattern such
+  /// as `user` or `*`.
+  pub fn on<E, F, Fut>(mut self, subject: &str, handler: F) -> Self
+  where
+    E: Event + Send + 'static,
+    F: Fn(EventContext, E) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<(), String>> + Send + 'static,
+  {
+    let handle: Handler = Box::new(move |ctx, data| match serde_json::from_slice::<E>(data) {
+      Ok(event) => handler(ctx, event).boxed(),
+      Err(e) => {
+        let e = format!("invalid {} event: {}", E::NAME, e);
+        async move { Err(e) }.boxed()
+      }
+    });
+    self.subscriptions.push(Subscription {
+      key: format!("{}.{}", subject, E::NAME),
+      version: E::VERSION,
+      handle,
+    });
+    self
+  }
+
+  pub fn routing_keys(&self) -> Vec<String> {
+    let keys: HashSet<&str> = self.subscriptions.iter().map(|s| s.key.as_str()).collect();
+    keys.into_iter().map(String::from).collect()
+  }
+
+  /// Run every handler subscribed to `key` that did not handle the event before, stopping
+  /// at the first failure. An event with a schema version newer than the one a handler was
+  /// written for is refused rather than misread.
+  async fn dispatch(
+    &self,
+    ctx: &EventContext,
+    key: &str,
+    version: Option<u16>,
+    data: &[u8],
+    progress: &Progress<'_>,
+  ) -> Result<(), String> {
+    let subscribed = self.subscriptions.iter().enumerate();
+    for (handler, subscription) in subscribed.filter(|(_, s)| matches_topic(&s.key, key)) {
+      if let Some(version) = version.filter(|v| *v > subscription.version) {
+        return Err(format!(
+          "{} has schema version {}, newer than the supported {}",
+          key, version, subscription.version
+        ));
+      }
+      if !progress.start(handler).await {
+        continue;
+      }
+      if let Err(e) = (subscription.handle)(ctx.clone(), data).await {
+        progress.failed(handler).await;
+        return Err(e);
+      }
+      progress.done(handler).await;
+    }
+    Ok(())
+  }
+}
+
+/// Which handlers are done with an event, kept by message id and handler position so a
+/// redelivered event only runs the handlers that did not finish. Events without a message
+/// id run every handler each time.
+struct Progress<'a> {
+  dedup: &'a Deduplicator,
+  message_id: Option<&'a str>,
+  redelivered: bool,
+}
+
+impl Progress<'_> {
+  fn id(&self, handler: usize) -> Option<String> {
+    self.message_id.map(|id| format!("{}#{}", id, handler))
+  }
+
+  /// Whether `handler` should run, claiming the event for it.
+  async fn start(&self, handler: usize) -> bool {
+    let id = match self.id(handler) {
+      Some(id) => id,
+      None => return true,
+    };
+    match self.dedup.check(&id, self.redelivered).await {
+      Seen::New => true,
+      Seen::InProgress | Seen::Replied(_) => {
+        info!("skipping event {} already handled by handler {}", id, handler);
+        false
+      }
+    }
+  }
+
+  async fn done(&self, handler: usize) {
+    if let Some(id) = self.id(handler) {
+      self.dedup.replied(&id, &[]).await;
+    }
+  }
+
+  async fn failed(&self, handler: usize) {
+    if let Some(id) = self.id(handler) {
+      self.dedup.forget(&id).await;
+    }
+  }
+}
+
+/// The durable event queue of `service`, `<service>.events`.
+pub fn queue_name(service: &str) -> String {
+  format!("{}.events", service)
+}
+
+/// The event queue of `service`, dead-lettering through `exchange` to `<queue>.dead` where
+/// failed events wait to be inspected or shovelled back. Configured settings for either
+/// queue take precedence.
+pub fn topology(service: &str, exchange: &str) -> Topology {
+  let queue = queue_name(service);
+  let dead = format!("{}.dead", queue);
+  Topology {
+    queues: vec![
+      Queue {
+        durable: true,
+        dead_letter_exchange: Some(exchange.to_string()),
+        dead_letter_routing_key: Some(dead.clone()),
+        ..Queue::named(&queue)
+      },
+      Queue {
+        durable: true,
+        ..Queue::named(&dead)
+      },
+    ],
+    bindings: vec![Binding {
+      exchange: exchange.to_string(),
+      queue: dead.clone(),
+      routing_key: dead,
+    }],
+    ..Topology::default()
+  }
+}
+
+/// Handle events from the service's event queue until the consumer closes. Events are
+/// published at least once, so handlers that already handled a message id are skipped. A
+/// failed event is requeued once, then dead-lettered.
+pub async fn consume(
+  subscriptions: Arc<Subscriptions>,
+  ctx: EventContext,
+  mut consumer: Consumer,
+  dedup: Deduplicator,
+) {
+  while let Some(msg) = consumer.next().await {
+    let (_, msg) = match msg {
+      Ok(msg) => msg,
+      Err(e) => {
+        error!("event consumer stopped: {}", e);
+        return;
+      }
+    };
+    let key = msg.routing_key.as_str();
+    let progress = Progress {
+      dedup: &dedup,
+      message_id: msg.properties.message_id().as_ref().map(|id| id.as_str()),
+      redelivered: msg.redelivered,
+    };
+    let version = rabbitmq::schema_version(&msg.properties);
+    match subscriptions.dispatch(&ctx, key, version, &msg.data, &progress).await {
+      Ok(()) => {
+        let _ = msg.ack(BasicAckOptions::default()).await;
+      }
+      Err(e) => {
+        error!("failed to handle event {}: {}", key, e);
+        // not requeued a second time, the queue dead-letters it instead
+        let requeue = !msg.redelivered;
+        let _ = msg
+          .nack(BasicNackOptions {
+            multiple: false,
+            requeue,
+          })
+          .await;
+      }
+    }
+  }
+}
@@ -0,0 +1,145 @@
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use thiserror::Error;
+use tokio_postgres::{Client, Row};
+
+use crate::shared_models::crud::{self, RecordId};
+use crate::shared_models::file::{self, FileRef};
+use crate::shared_models::request_response::{Request, Response};
+use crate::utils::file_store::FileStore;
+use crate::utils::helpers;
+
+const SERVICE_USER: &str = "backend_service";
+
+/// Binding key matching file requests for every tenant, `files.request.<tenant>`.
+pub fn routing_key() -> String {
+  format!("{}.request.*", file::SUBJECT)
+}
+
+/// Create the table of file metadata if it does not exist yet.
+pub async fn migrate(db: &Client) -> Result<(), tokio_postgres::Error> {
+  db.batch_execute(
+    "CREATE TABLE IF NOT EXISTS files (
+      id TEXT PRIMARY KEY,
+      tenant_id TEXT NOT NULL,
+      blob TEXT NOT NULL,
+      name TEXT NOT NULL,
+      content_type TEXT NOT NULL,
+      size BIGINT NOT NULL,
+      sha256 TEXT NOT NULL,
+      created_at BIGINT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS files_tenant_id_idx ON files (tenant_id);",
+  )
+  .await
+}
+
+#[derive(Error, Debug)]
+enum FileError {
+  #[error("invalid payload: {0}")]
+  Invalid(#[from] serde_json::Error),
+  #[error("file {0} not found")]
+  NotFound(String),
+  #[error("unknown method {0}")]
+  UnknownMethod(String),
+  #[error("database error: {0}")]
+  Database(#[from] tokio_postgres::Error),
+}
+
+impl FileError {
+  fn status(&self) -> u16 {
+    match self {
+      FileError::Invalid(_) => 400,
+      FileError::NotFound(_) => 404,
+      FileError::UnknownMethod(_) => 405,
+      FileError::Database(_) => 500,
+    }
+  }
+}
+
+/// Keep track of files the web gateway stored. Deleting a file also removes its content
+/// from `store`.
+pub async fn handle_file_request(db: &Client, store: Option<&FileStore>, req: Request) -> Response {
+  let tenant = req.tenant.as_str();
+  let result = match req.method.as_str() {
+    crud::CREATE => create(db, tenant, &req.payload).await,
+    crud::GET => get(db, tenant, &req.payload).await,
+    crud::DELETE => delete(db, store, tenant, &req.payload).await,
+    method => Err(FileError::UnknownMethod(method.to_string())),
+  };
+  match result.and_then(|file| serde_json::to_vec(&file).map_err(FileError::from)) {
+    Ok(payload) => Response::ok(SERVICE_USER, payload),
+    Err(e) => Response::error(SERVICE_USER, e.status(), e.to_string()),
+  }
+}
+
+async fn create(db: &Client, tenant: &str, payload: &[u8]) -> Result<FileRef, FileError> {
+  let mut file: FileRef = serde_json::from_slice(payload)?;
+  file.created_at = helpers::get_time();
+  db.execute(
+    "INSERT INTO files (id, tenant_id, blob, name, content_type, size, sha256, created_at)
+    VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+    &[
+      &file.id,
+      &tenant,
+      &file.blob,
+      &file.name,
+      &file.content_type,
+      &(file.size as i64),
+      &file.sha256,
+      &(file.created_at as i64),
+    ],
+  )
+  .await?;
+  Ok(file)
+}
+
+async fn get(db: &Client, tenant: &str, payload: &[u8]) -> Result<FileRef, FileError> {
+  let RecordId { id } = serde_json::from_slice(payload)?;
+  let row = db
+    .query_opt(
+      "SELECT id, blob, name, content_type, size, sha256, created_at FROM files
+      WHERE id = $1 AND tenant_id = $2",
+      &[&id, &tenant],
+    )
+    .await?;
+  row.map(|row| to_file(&row)).ok_or(FileError::NotFound(id))
+}
+
+async fn delete(db: &Client, store: Option<&FileStore>, tenant: &str, payload: &[u8]) -> Result<FileRef, FileError> {
+  let RecordId { id } = serde_json::from_slice(payload)?;
+  let row = db
+    .query_opt(
+      "DELETE FROM files WHERE id = $1 AND tenant_id = $2
+      RETURNING id, blob, name, content_type, size, sha256, created_at",
+      &[&id, &tenant],
+    )
+    .await?;
+  let file = row.map(|row| to_file(&row)).ok_or(FileError::NotFound(id))?;
+  let store = store.map(|files| files.store.clone());
+  let blob = file.blob.clone();
+  match store {
+    Some(store) => {
+      if let Err(e) = tokio::task::spawn_blocking(move || store.delete(&blob))
+        .await
+        .unwrap_or_else(|e| Err(e.to_string()))
+      {
+        error!("unable to remove the content of file {}: {}", file.id, e);
+      }
+    }
+    None => warn!("no file store configured, the content of file {} stays", file.id),
+  }
+  Ok(file)
+}
+
+fn to_file(row: &Row) -> FileRef {
+  FileRef {
+    id: row.get(0),
+    blob: row.get(1),
+    name: row.get(2),
+    content_type: row.get(3),
+    size: row.get::<_, i64>(4) as u64,
+    sha256: row.get(5),
+    created_at: row.get::<_, i64>(6) as u64,
+  }
+}
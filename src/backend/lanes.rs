@@ -0,0 +1,132 @@
+use lapin::{message::Delivery, Consumer};
+#[allow(unused_imports)]
+use log::{debug, error, info};
+use std::{collections::HashMap, sync::Arc, thread};
+use tokio::sync::{
+  mpsc::{self, error::TryRecvError, Receiver, Sender},
+  Notify,
+};
+
+use super::config;
+use crate::shared_models::lane::Lane;
+use crate::utils::topology::{Queue, Topology};
+
+/// Queue of `lane` for the requests of the service consuming `queue`.
+pub fn queue_name(queue: &str, lane: Lane) -> String {
+  format!("{}.{}", queue, lane.name())
+}
+
+/// The request queue `queue`, for keys without a lane, and its lane queues, ordering by the
+/// priority of every lane. Configured settings take precedence.
+pub fn topology(queue: &str) -> Topology {
+  let names = std::iter::once(queue.to_string()).chain(Lane::ALL.iter().map(|l| queue_name(queue, *l)));
+  Topology {
+    queues: names
+      .map(|name| Queue {
+        max_priority: Some(Lane::MAX_PRIORITY),
+        ..Queue::named(&name)
+      })
+      .collect(),
+    ..Topology::default()
+  }
+}
+
+struct LaneQueue {
+  lane: Lane,
+  weight: u32,
+  rx: Receiver<Delivery>,
+  closed: bool,
+}
+
+/// Deliveries of every lane, taken in weighted round robin: while several lanes have work
+/// waiting, each takes up to `weight` messages in turn. An idle lane leaves its share to
+/// the others, so batch work still runs at full speed when nothing else does.
+pub struct LaneConsumer {
+  lanes: Vec<LaneQueue>,
+  senders: HashMap<Lane, Sender<Delivery>>,
+  notify: Arc<Notify>,
+  current: usize,
+  served: u32,
+}
+
+impl LaneConsumer {
+  pub fn new(settings: &config::Lanes) -> LaneConsumer {
+    let mut lanes = Vec::new();
+    let mut senders = HashMap::new();
+    for lane in Lane::ALL.iter() {
+      let lane_settings = settings.get(*lane);
+      let (tx, rx) = mpsc::channel(lane_settings.prefetch.max(1) as usize);
+      senders.insert(*lane, tx);
+      lanes.push(LaneQueue {
+        lane: *lane,
+        weight: lane_settings.weight.max(1),
+        rx,
+        closed: false,
+      });
+    }
+    LaneConsumer {
+      lanes,
+      senders,
+      notify: Arc::new(Notify::new()),
+      current: 0,
+      served: 0,
+    }
+  }
+
+  /// Hand the deliveries of `consumer` to `lane`. A lane may be fed by several consumers.
+  pub fn feed(&mut self, lane: Lane, consumer: Consumer) {
+    // the lane stops once every consumer feeding it is gone
+    let tx = match self.senders.get(&lane) {
+      Some(tx) => tx.clone(),
+      None => return,
+    };
+    let notify = self.notify.clone();
+    thread::spawn(move || {
+      for delivery in consumer.into_iter() {
+        match delivery {
+          Ok((_, delivery)) => {
+            if tx.blocking_send(delivery).is_err() {
+              break;
+            }
+            notify.notify_one();
+          }
+          Err(e) => {
+            error!("{} lane consumer failed: {}", lane.name(), e);
+            break;
+          }
+        }
+      }
+      notify.notify_one();
+    });
+  }
+
+  /// Stop handing out senders, so the lanes close once their consumers are gone.
+  pub fn start(&mut self) {
+    self.senders.clear();
+  }
+
+  /// The next delivery and its lane, `None` once every lane is closed.
+  pub async fn next(&mut self) -> Option<(Lane, Delivery)> {
+    loop {
+      for _ in 0..=self.lanes.len() {
+        let queue = &mut self.lanes[self.current];
+        if self.served < queue.weight && !queue.closed {
+          match queue.rx.try_recv() {
+            Ok(delivery) => {
+              self.served += 1;
+              return Some((queue.lane, delivery));
+            }
+            Err(TryRecvError::Disconnected) => queue.closed = true,
+            Err(TryRecvError::Empty) => (),
+          }
+        }
+        self.current = (self.current + 1) % self.lanes.len();
+        self.served = 0;
+      }
+      if self.lanes.iter().all(|q| q.closed) {
+        return None;
+      }
+      self.notify.notified().await;
+    }
+  }
+}
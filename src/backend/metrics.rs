@@ -0,0 +1,28 @@
+#[allow(unused_imports)]
+use log::{debug, error, info};
+use prometheus::{Encoder, TextEncoder};
+use std::net::SocketAddr;
+use warp::Filter;
+
+use super::config;
+
+/// Serve every metric of the default prometheus registry on `GET /<endpoint>`.
+pub async fn serve(config: config::Metrics) {
+  let address: SocketAddr = match config.address.parse() {
+    Ok(address) => address,
+    Err(e) => panic!("invalid metrics address {}: {}", config.address, e),
+  };
+  let route = warp::get()
+    .and(warp::path(config.endpoint))
+    .and(warp::path::end())
+    .map(|| {
+      let encoder = TextEncoder::new();
+      let mut buffer = Vec::new();
+      if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        error!("unable to encode metrics: {}", e);
+      }
+      warp::reply::with_header(buffer, "Content-Type", encoder.format_type().to_string())
+    });
+  info!("serving metrics on {}", address);
+  warp::serve(route).run(address).await;
+}
@@ -1,9 +1,22 @@
 use std::collections::HashSet;
+
 mod config;
+mod cron;
+pub mod crud;
+mod dedup;
+mod events;
+mod files;
+mod lanes;
+mod metrics;
+mod models;
+mod outbox;
+mod query;
 pub mod run;
+mod scheduler;
+mod stream;
 
 // Define Message keys
-const BACKEND_REQUEST: &str = "backend.request";
+const BACKEND_REQUEST: &str = "backend.request.*";
 
 const SERVICEKEYS: [&str; 1] = [BACKEND_REQUEST];
 
@@ -0,0 +1,14 @@
+use super::crud::ModelRegistry;
+use super::events::Subscriptions;
+
+mod user;
+
+/// Every model served by the CRUD service. Add new models here.
+pub fn registry() -> ModelRegistry {
+  ModelRegistry::default().register::<user::User>()
+}
+
+/// Domain events the service reacts to. Add new subscriptions here.
+pub fn subscriptions() -> Subscriptions {
+  Subscriptions::default().on("user", user::on_created)
+}
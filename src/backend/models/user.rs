@@ -0,0 +1,27 @@
+#[allow(unused_imports)]
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+
+use super::super::crud::Model;
+use super::super::events::EventContext;
+use crate::shared_models::event::RecordCreated;
+
+#[derive(Serialize, Deserialize, Validate, Debug)]
+pub struct User {
+  #[validate(length(min = 1, max = 128))]
+  pub name: String,
+  #[validate(email)]
+  pub email: String,
+}
+
+impl Model for User {
+  const NAME: &'static str = "user";
+  const TABLE: &'static str = "users";
+  const FIELDS: &'static [&'static str] = &["name", "email"];
+}
+
+pub async fn on_created(_ctx: EventContext, event: RecordCreated) -> Result<(), String> {
+  info!("user {} created on tenant {}", event.record.id, event.tenant);
+  Ok(())
+}
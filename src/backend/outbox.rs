@@ -0,0 +1,199 @@
+#[allow(unused_imports)]
+use log::{debug, error, info};
+use prometheus::{register_int_gauge, IntGauge};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_postgres::types::ToSql;
+use tokio_postgres::Client;
+
+use super::config;
+use crate::shared_models::event::Event;
+use crate::utils::helpers;
+use crate::utils::rabbitmq::MqChannel;
+
+const TABLE: &str = "outbox";
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// An event waiting in the outbox, written by the same statement as the change it
+/// describes and published later by the relay.
+pub struct OutboxEvent {
+  pub id: String,
+  pub routing_key: String,
+  pub name: String,
+  pub version: i32,
+  pub payload: String,
+}
+
+impl OutboxEvent {
+  pub fn of<E: Event>(event: &E) -> Result<OutboxEvent, serde_json::Error> {
+    Ok(OutboxEvent {
+      id: helpers::new_uuid(),
+      routing_key: event.routing_key(),
+      name: E::NAME.to_string(),
+      version: E::VERSION as i32,
+      payload: serde_json::to_string(event)?,
+    })
+  }
+
+  /// Statement parameters matching `insert_sql`, followed by the time of the change.
+  pub fn params<'a>(&'a self, now: &'a i64) -> [&'a (dyn ToSql + Sync); 6] {
+    [
+      &self.id,
+      &self.routing_key,
+      &self.name,
+      &self.version,
+      &self.payload,
+      now,
+    ]
+  }
+}
+
+/// `INSERT` for a `WITH changed AS (...)` statement, adding the event once for every row of
+/// `changed` so the outbox row and the change commit together. The parameters of
+/// `OutboxEvent::params` start at `$first`; `payload` is the SQL expression of the event
+/// body, see `payload_param`.
+pub fn insert_sql(first: usize, payload: &str) -> String {
+  format!(
+    "INSERT INTO {} (id, routing_key, event, version, payload, created_at)
+    SELECT ${}::TEXT, ${}::TEXT, ${}::TEXT, ${}::INT, {}, ${}::BIGINT FROM changed",
+    TABLE,
+    first,
+    first + 1,
+    first + 2,
+    first + 3,
+    payload,
+    first + 5
+  )
+}
+
+/// The event body as passed in `OutboxEvent::params`.
+pub fn payload_param(first: usize) -> String {
+  format!("${}::TEXT::JSONB", first + 4)
+}
+
+pub async fn migrate(db: &Client) -> Result<(), tokio_postgres::Error> {
+  db.batch_execute(&format!(
+    "CREATE TABLE IF NOT EXISTS {table} (
+      seq BIGSERIAL,
+      id TEXT PRIMARY KEY,
+      routing_key TEXT NOT NULL,
+      event TEXT NOT NULL,
+      version INT NOT NULL,
+      payload JSONB NOT NULL,
+      created_at BIGINT NOT NULL,
+      claimed_until BIGINT NOT NULL DEFAULT 0,
+      sent_at BIGINT
+    );
+    CREATE INDEX IF NOT EXISTS {table}_pending_idx ON {table} (seq) WHERE sent_at IS NULL;
+    CREATE INDEX IF NOT EXISTS {table}_sent_at_idx ON {table} (sent_at);",
+    table = TABLE
+  ))
+  .await
+}
+
+struct Metrics {
+  lag: IntGauge,
+  pending: IntGauge,
+}
+
+/// Publish pending outbox rows until the process exits. Rows are claimed for `claim`
+/// seconds so several backend instances can relay side by side, and marked sent only once
+/// the broker confirmed them, so an event is published at least once. Sent rows are
+/// deleted after `retention` seconds.
+pub async fn relay(db: Arc<Client>, mut mq: MqChannel, config: config::Outbox) {
+  let metrics = Metrics {
+    lag: register_int_gauge!(
+      "outbox_relay_lag_seconds",
+      "Age of the oldest outbox event not published yet"
+    )
+    .unwrap(),
+    pending: register_int_gauge!("outbox_pending_events", "Outbox events not published yet").unwrap(),
+  };
+  mq.enable_confirms();
+  let interval = Duration::from_millis(config.interval);
+  let mut cleaned_at = Instant::now();
+  loop {
+    let relayed = match relay_batch(&db, &mut mq, &config).await {
+      Ok(relayed) => relayed,
+      Err(e) => {
+        error!("outbox relay failed: {}", e);
+        0
+      }
+    };
+    if let Err(e) = measure(&db, &metrics).await {
+      error!("unable to measure outbox lag: {}", e);
+    }
+    if cleaned_at.elapsed() >= CLEANUP_INTERVAL {
+      cleaned_at = Instant::now();
+      let before = helpers::get_time().saturating_sub(config.retention) as i64;
+      match db
+        .execute(&*format!("DELETE FROM {} WHERE sent_at < $1", TABLE), &[&before])
+        .await
+      {
+        Ok(deleted) => debug!("removed {} sent outbox events", deleted),
+        Err(e) => error!("unable to clean up the outbox: {}", e),
+      }
+    }
+    // keep going while there is a backlog
+    if relayed < config.batch {
+      tokio::time::sleep(interval).await;
+    }
+  }
+}
+
+async fn relay_batch(db: &Client, mq: &mut MqChannel, config: &config::Outbox) -> Result<i64, tokio_postgres::Error> {
+  let now = helpers::get_time() as i64;
+  let claim_until = now + config.claim as i64;
+  let mut rows = db
+    .query(
+      &*format!(
+        "UPDATE {table} SET claimed_until = $2 WHERE id IN (
+          SELECT id FROM {table} WHERE sent_at IS NULL AND claimed_until < $1
+          ORDER BY seq LIMIT $3 FOR UPDATE SKIP LOCKED
+        ) RETURNING seq, id, routing_key, event, version, payload::TEXT",
+        table = TABLE
+      ),
+      &[&now, &claim_until, &config.batch],
+    )
+    .await?;
+  rows.sort_by_key(|row| row.get::<_, i64>(0));
+  let mut relayed = 0;
+  for row in rows {
+    let id: String = row.try_get(1)?;
+    let routing_key: String = row.try_get(2)?;
+    let name: String = row.try_get(3)?;
+    let version: i32 = row.try_get(4)?;
+    let payload: String = row.try_get(5)?;
+    if let Err(e) = mq
+      .publish_event_confirmed(&routing_key, &name, version as u16, &id, payload.into_bytes())
+      .await
+    {
+      // keeps the order of the remaining events, they are retried once the claim expires
+      error!("unable to publish outbox event {}: {}", id, e);
+      break;
+    }
+    db.execute(
+      &*format!("UPDATE {} SET sent_at = $2 WHERE id = $1", TABLE),
+      &[&id, &(helpers::get_time() as i64)],
+    )
+    .await?;
+    relayed += 1;
+  }
+  Ok(relayed)
+}
+
+async fn measure(db: &Client, metrics: &Metrics) -> Result<(), tokio_postgres::Error> {
+  let row = db
+    .query_one(
+      &*format!("SELECT COUNT(*), MIN(created_at) FROM {} WHERE sent_at IS NULL", TABLE),
+      &[],
+    )
+    .await?;
+  let pending: i64 = row.try_get(0)?;
+  let oldest: Option<i64> = row.try_get(1)?;
+  metrics.pending.set(pending);
+  metrics
+    .lag
+    .set(oldest.map_or(0, |at| (helpers::get_time() as i64 - at).max(0)));
+  Ok(())
+}
@@ -0,0 +1,353 @@
+use tokio_postgres::{types::ToSql, Row};
+
+use super::crud::ModelDef;
+use crate::shared_models::list::{FilterOp, ListQuery, Sort, SortDirection, MAX_LIMIT};
+use crate::shared_models::tenant::Tenant;
+use crate::utils::helpers;
+
+/// Columns every model table has besides the fields stored in `data`.
+const COLUMNS: [&str; 3] = ["id", "created_at", "updated_at"];
+/// Index of the first sort column in the rows of a `SqlQuery`.
+const SORT_OFFSET: usize = 4;
+
+enum Column {
+  Text(String),
+  BigInt(&'static str),
+}
+
+impl Column {
+  fn expr(&self) -> &str {
+    match self {
+      Column::Text(expr) => expr,
+      Column::BigInt(expr) => expr,
+    }
+  }
+
+  /// The value of this column as the text a cursor stores.
+  fn text(&self) -> String {
+    match self {
+      Column::Text(expr) => expr.clone(),
+      Column::BigInt(expr) => format!("{}::TEXT", expr),
+    }
+  }
+
+  fn param(&self, value: &str) -> Result<Param, String> {
+    match self {
+      Column::Text(_) => Ok(Param::Text(value.to_string())),
+      Column::BigInt(name) => value
+        .parse()
+        .map(Param::BigInt)
+        .map_err(|_| format!("{} expects an integer, got {}", name, value)),
+    }
+  }
+}
+
+pub enum Param {
+  Text(String),
+  BigInt(i64),
+}
+
+impl Param {
+  pub fn as_sql(&self) -> &(dyn ToSql + Sync) {
+    match self {
+      Param::Text(v) => v,
+      Param::BigInt(v) => v,
+    }
+  }
+}
+
+/// A list query translated to SQL against a model table. Fetches one row more than the
+/// requested limit so the caller can tell whether there is a next page. Every row carries
+/// the text of its sort columns after the record columns, so cursors hold exactly what
+/// postgres compares.
+pub struct SqlQuery {
+  pub sql: String,
+  pub params: Vec<Param>,
+  pub limit: usize,
+}
+
+impl SqlQuery {
+  pub fn build(model: &ModelDef, tenant: &Tenant, query: &ListQuery) -> Result<SqlQuery, String> {
+    let mut params: Vec<Param> = vec![Param::Text(tenant.to_string())];
+    let mut conditions: Vec<String> = vec!["tenant_id = $1".to_string()];
+
+    for filter in &query.filters {
+      let column = column(model, &filter.field)?;
+      let op = match (filter.op, &column) {
+        (FilterOp::Eq, _) => "=",
+        (FilterOp::Ne, _) => "<>",
+        (FilterOp::Lt, _) => "<",
+        (FilterOp::Lte, _) => "<=",
+        (FilterOp::Gt, _) => ">",
+        (FilterOp::Gte, _) => ">=",
+        (FilterOp::Contains, Column::Text(_)) => {
+          params.push(Param::Text(escape_like(&filter.value)));
+          conditions.push(format!(
+            "{} LIKE '%' || ${} || '%' ESCAPE '\\'",
+            column.expr(),
+            params.len()
+          ));
+          continue;
+        }
+        (FilterOp::Contains, Column::BigInt(name)) => return Err(format!("contains is not supported on {}", name)),
+      };
+      params.push(column.param(&filter.value)?);
+      conditions.push(format!("{} {} ${}", column.expr(), op, params.len()));
+    }
+
+    let mut sort = query.sort.clone();
+    if sort.is_empty() {
+      sort.push(Sort {
+        field: "created_at".to_string(),
+        direction: SortDirection::Asc,
+      });
+    }
+    if !sort.iter().any(|s| s.field == "id") {
+      sort.push(Sort {
+        field: "id".to_string(),
+        direction: SortDirection::Asc,
+      });
+    }
+    let columns = sort
+      .iter()
+      .map(|s| column(model, &s.field))
+      .collect::<Result<Vec<Column>, String>>()?;
+
+    if let Some(cursor) = &query.cursor {
+      let values = decode_cursor(cursor)?;
+      if values.len() != columns.len() {
+        return Err("cursor does not match the requested sort order".to_string());
+      }
+      // keyset pagination: (a > x) OR (a = x AND b > y) OR ...
+      let mut indexes = Vec::new();
+      for (column, value) in columns.iter().zip(values.iter()) {
+        params.push(column.param(value)?);
+        indexes.push(params.len());
+      }
+      let mut keyset = Vec::new();
+      for (i, (column, s)) in columns.iter().zip(sort.iter()).enumerate() {
+        let mut parts: Vec<String> = columns
+          .iter()
+          .zip(indexes.iter())
+          .take(i)
+          .map(|(c, idx)| format!("{} = ${}", c.expr(), idx))
+          .collect();
+        let op = match s.direction {
+          SortDirection::Asc => ">",
+          SortDirection::Desc => "<",
+        };
+        parts.push(format!("{} {} ${}", column.expr(), op, indexes[i]));
+        keyset.push(format!("({})", parts.join(" AND ")));
+      }
+      conditions.push(format!("({})", keyset.join(" OR ")));
+    }
+
+    let limit = query.limit.clamp(1, MAX_LIMIT) as usize;
+    let sort_columns: Vec<String> = columns.iter().map(|c| c.text()).collect();
+    let mut sql = format!(
+      "SELECT id, data::TEXT, created_at, updated_at, {} FROM {} WHERE {}",
+      sort_columns.join(", "),
+      model.table,
+      conditions.join(" AND ")
+    );
+    let order: Vec<String> = sort
+      .iter()
+      .zip(columns.iter())
+      .map(|(s, c)| match s.direction {
+        SortDirection::Asc => format!("{} ASC", c.expr()),
+        SortDirection::Desc => format!("{} DESC", c.expr()),
+      })
+      .collect();
+    sql.push_str(&format!(" ORDER BY {} LIMIT {}", order.join(", "), limit + 1));
+
+    Ok(SqlQuery { sql, params, limit })
+  }
+
+  pub fn params(&self) -> Vec<&(dyn ToSql + Sync)> {
+    self.params.iter().map(|p| p.as_sql()).collect()
+  }
+
+  /// The cursor pointing after `row` in this query's sort order.
+  pub fn cursor_after(&self, row: &Row) -> Result<String, tokio_postgres::Error> {
+    let values = (SORT_OFFSET..row.len())
+      .map(|i| row.try_get(i))
+      .collect::<Result<Vec<String>, _>>()?;
+    Ok(encode_cursor(&values))
+  }
+}
+
+/// The column a field is filtered and sorted on. Fields of the model data are compared as
+/// text, also when they hold numbers: "10" sorts before "9".
+fn column(model: &ModelDef, field: &str) -> Result<Column, String> {
+  match field {
+    "id" => Ok(Column::Text("id".to_string())),
+    "created_at" => Ok(Column::BigInt("created_at")),
+    "updated_at" => Ok(Column::BigInt("updated_at")),
+    f if model.fields.contains(&f) => Ok(Column::Text(format!("COALESCE(data->>'{}', '')", f))),
+    f => Err(format!(
+      "unknown field {}, expected one of {}",
+      f,
+      COLUMNS.iter().chain(model.fields.iter()).cloned().collect::<Vec<_>>().join(", ")
+    )),
+  }
+}
+
+/// `value` matching itself literally in a LIKE pattern with `ESCAPE '\'`.
+fn escape_like(value: &str) -> String {
+  let mut escaped = String::with_capacity(value.len());
+  for c in value.chars() {
+    if matches!(c, '\\' | '%' | '_') {
+      escaped.push('\\');
+    }
+    escaped.push(c);
+  }
+  escaped
+}
+
+fn encode_cursor(values: &[String]) -> String {
+  helpers::to_hex(&serde_json::to_vec(values).unwrap())
+}
+
+fn decode_cursor(cursor: &str) -> Result<Vec<String>, String> {
+  helpers::from_hex(cursor)
+    .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+    .ok_or_else(|| "invalid cursor".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::backend::crud::Model;
+  use crate::shared_models::list::{Filter, SortDirection};
+  use serde::{Deserialize, Serialize};
+  use validator::Validate;
+
+  #[derive(Serialize, Deserialize, Validate)]
+  struct Note {
+    name: String,
+  }
+
+  impl Model for Note {
+    const NAME: &'static str = "note";
+    const TABLE: &'static str = "notes";
+    const FIELDS: &'static [&'static str] = &["name"];
+  }
+
+  fn build(query: &ListQuery) -> Result<SqlQuery, String> {
+    SqlQuery::build(&ModelDef::of::<Note>(), &Tenant::parse("acme").unwrap(), query)
+  }
+
+  #[test]
+  fn sorts_by_creation_and_id_by_default() {
+    let query = build(&ListQuery::default()).unwrap();
+    assert_eq!(
+      query.sql,
+      "SELECT id, data::TEXT, created_at, updated_at, created_at::TEXT, id FROM notes \
+       WHERE tenant_id = $1 ORDER BY created_at ASC, id ASC LIMIT 21"
+    );
+    assert_eq!(query.params.len(), 1);
+    assert_eq!(query.limit, 20);
+  }
+
+  #[test]
+  fn selects_the_text_postgres_compares_for_data_fields() {
+    let query = build(&ListQuery {
+      sort: vec![Sort {
+        field: "name".to_string(),
+        direction: SortDirection::Desc,
+      }],
+      ..ListQuery::default()
+    })
+    .unwrap();
+    assert!(query
+      .sql
+      .starts_with("SELECT id, data::TEXT, created_at, updated_at, COALESCE(data->>'name', ''), id FROM notes"));
+    assert!(query.sql.ends_with("ORDER BY COALESCE(data->>'name', '') DESC, id ASC LIMIT 21"));
+  }
+
+  #[test]
+  fn translates_filters() {
+    let query = build(&ListQuery {
+      filters: vec![
+        Filter {
+          field: "name".to_string(),
+          op: FilterOp::Contains,
+          value: "bo".to_string(),
+        },
+        Filter {
+          field: "created_at".to_string(),
+          op: FilterOp::Gte,
+          value: "1600000000".to_string(),
+        },
+      ],
+      ..ListQuery::default()
+    })
+    .unwrap();
+    assert!(query.sql.contains(
+      "WHERE tenant_id = $1 AND COALESCE(data->>'name', '') LIKE '%' || $2 || '%' ESCAPE '\\' \
+       AND created_at >= $3 ORDER BY"
+    ));
+    assert!(matches!(query.params[1], Param::Text(ref v) if v == "bo"));
+    assert!(matches!(query.params[2], Param::BigInt(1600000000)));
+  }
+
+  #[test]
+  fn matches_wildcards_literally() {
+    let query = build(&ListQuery {
+      filters: vec![Filter {
+        field: "name".to_string(),
+        op: FilterOp::Contains,
+        value: r"50%_off\".to_string(),
+      }],
+      ..ListQuery::default()
+    })
+    .unwrap();
+    assert!(matches!(query.params[1], Param::Text(ref v) if v == r"50\%\_off\\"));
+  }
+
+  #[test]
+  fn rejects_invalid_filters() {
+    let filter = |field: &str, op, value: &str| ListQuery {
+      filters: vec![Filter {
+        field: field.to_string(),
+        op,
+        value: value.to_string(),
+      }],
+      ..ListQuery::default()
+    };
+    assert!(build(&filter("created_at", FilterOp::Eq, "yesterday")).is_err());
+    assert!(build(&filter("created_at", FilterOp::Contains, "1")).is_err());
+    assert!(build(&filter("password", FilterOp::Eq, "x")).is_err());
+  }
+
+  #[test]
+  fn continues_after_the_cursor() {
+    let query = build(&ListQuery {
+      cursor: Some(encode_cursor(&["5".to_string(), "abc".to_string()])),
+      ..ListQuery::default()
+    })
+    .unwrap();
+    assert!(query
+      .sql
+      .contains("WHERE tenant_id = $1 AND ((created_at > $2) OR (created_at = $2 AND id > $3)) ORDER BY"));
+    assert!(matches!(query.params[1], Param::BigInt(5)));
+    assert!(matches!(query.params[2], Param::Text(ref v) if v == "abc"));
+  }
+
+  #[test]
+  fn rejects_cursors_of_another_query() {
+    let cursor = |cursor: String| ListQuery {
+      cursor: Some(cursor),
+      ..ListQuery::default()
+    };
+    assert!(build(&cursor(encode_cursor(&["5".to_string()]))).is_err());
+    assert!(build(&cursor(encode_cursor(&["x".to_string(), "abc".to_string()]))).is_err());
+    assert!(build(&cursor("not a cursor".to_string())).is_err());
+  }
+
+  #[test]
+  fn cursors_round_trip() {
+    let values = vec!["1.50".to_string(), "true".to_string(), "{\"a\": 1}".to_string()];
+    assert_eq!(decode_cursor(&encode_cursor(&values)).unwrap(), values);
+  }
+}
@@ -1,16 +1,56 @@
+use futures::executor::block_on;
 use lapin::message::Delivery;
 use lapin::options::BasicAckOptions;
 #[allow(unused_imports)]
 use log::{debug, error, info};
-use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tokio_postgres::{Client, NoTls};
 
 use super::config;
+use super::crud::{self, ModelRegistry};
+use super::dedup::{self, Deduplicator, Seen};
+use super::events::{self, EventContext};
+use super::files;
+use super::lanes::{self, LaneConsumer};
+use super::metrics;
+use super::models;
+use super::outbox;
+use super::scheduler::{self, Scheduler};
+use super::stream::{JobRecords, Running, StreamWriter};
+use crate::shared_models::audit;
+use crate::shared_models::file;
+use crate::shared_models::job;
+use crate::shared_models::lane::Lane;
 use crate::shared_models::request_response;
-use crate::utils::rabbitmq::{MqChannel, MqLogin};
+use crate::shared_models::stream;
+use crate::utils::circuit_breaker::Breakers;
+use crate::utils::claim_check::ClaimCheck;
+use crate::utils::file_store::FileStore;
+use crate::utils::policy::{Denial, Policy};
+use crate::utils::rabbitmq::{self, MqChannel, MqLogin};
+use crate::utils::reply_store::ReplyStore;
+use crate::utils::runtime::Tokio;
+use crate::utils::service_client::ServiceClient;
+
+/// How long a duplicate of a message handled elsewhere waits for its reply, as long as
+/// callers wait for one.
+const DUPLICATE_WAIT: Duration = Duration::from_secs(20);
 
 pub struct AppState {
   pub mq: MqChannel,
+  pub db: Arc<Client>,
+  pub models: Arc<ModelRegistry>,
+  pub policy: Arc<Policy>,
+  pub running: Running,
+  pub jobs: JobRecords,
+  pub dedup: Deduplicator,
+  /// Client for calling other services from a handler.
+  pub services: ServiceClient,
+  pub scheduler: Scheduler,
+  /// Content of uploaded files, `None` when no file store is configured.
+  pub files: Option<FileStore>,
 }
 
 #[tokio::main]
@@ -27,6 +67,11 @@ pub async fn start_server() {
         .takes_value(true)
         .help("Relative location of config file"),
     )
+    .arg(
+      clap::Arg::with_name("dry-run")
+        .long("dry-run")
+        .help("Print how the broker differs from the configured topology and exit"),
+    )
     .get_matches();
   let _config = matches.value_of("config").unwrap_or("etc/api_worker/config.toml");
 
@@ -34,56 +79,353 @@ pub async fn start_server() {
   let app_config = config::config_parser(_config);
   debug!("{:?}", app_config);
   let mq_config = app_config.rabbit_mq;
+  let policy = Arc::new(app_config.policy);
 
   let rabbit_mq_login = MqLogin {
+    user: mq_config.user.clone(),
+    password: mq_config.password.clone(),
+    host: mq_config.host.clone(),
+    port: mq_config.port.clone(),
+  };
+
+  // a dry run only looks at the broker, so it happens before anything is migrated
+  let replies = ReplyStore::new(app_config.replies.clone(), prometheus::default_registry());
+  let mut channel: MqChannel = MqChannel::connect(rabbit_mq_login, replies.clone(), Arc::new(Tokio::current()));
+  let lane_queues: Vec<String> = Lane::ALL
+    .iter()
+    .map(|l| lanes::queue_name(&mq_config.queue, *l))
+    .collect();
+  let topology = app_config
+    .topology
+    .including(&mq_config.exchange, &[])
+    .with_defaults(lanes::topology(&mq_config.queue))
+    .with_defaults(events::topology(&mq_config.queue, &mq_config.exchange));
+  if matches.is_present("dry-run") {
+    for change in channel.diff_topology(&topology) {
+      println!("{}", change);
+    }
+    return;
+  }
+
+  // Connect to postgres and create the tables of the registered models
+  let (client, connection) = match tokio_postgres::connect(&app_config.psql.connection_string(), NoTls).await {
+    Ok(conn) => conn,
+    Err(e) => panic!("unable to connect to postgres. Error: {}", e),
+  };
+  tokio::spawn(async move {
+    if let Err(e) = connection.await {
+      error!("postgres connection error: {}", e);
+    }
+  });
+  let db = Arc::new(client);
+  let model_registry = Arc::new(models::registry());
+  if let Err(e) = model_registry.migrate(&db).await {
+    panic!("unable to migrate model tables. Error: {}", e);
+  }
+  if let Err(e) = outbox::migrate(&db).await {
+    panic!("unable to migrate the outbox table. Error: {}", e);
+  }
+  if let Err(e) = scheduler::migrate(&db).await {
+    panic!("unable to migrate the schedule table. Error: {}", e);
+  }
+  if let Err(e) = files::migrate(&db).await {
+    panic!("unable to migrate the files table. Error: {}", e);
+  }
+  if let Err(e) = dedup::migrate(&db).await {
+    panic!("unable to migrate the processed messages table. Error: {}", e);
+  }
+  let scheduler = Scheduler::new(db.clone());
+  for entry in &app_config.scheduler.cron {
+    if let Err(e) = scheduler.schedule_entry(entry).await {
+      panic!("unable to register cron schedule {}. Error: {}", entry.name, e);
+    }
+  }
+  tokio::spawn(metrics::serve(app_config.metrics.clone()));
+
+  channel.declare_topology(&topology);
+  channel.use_exchange(&mq_config.exchange);
+  channel.use_claim_check(ClaimCheck::open(
+    &app_config.claim_check,
+    &app_config.psql.connection_string(),
+  ));
+  let file_store = FileStore::open(&app_config.files, &app_config.psql.connection_string());
+  let request_keys: Vec<String> = super::get_service_keys()
+    .into_iter()
+    .chain(model_registry.routing_keys())
+    .chain(std::iter::once(files::routing_key()))
+    .collect();
+  // Every lane has a queue and a channel of its own, so each gets its own prefetch. Keys
+  // without a lane, from callers that predate lanes, go to the default lane
+  let mut lane_consumer = LaneConsumer::new(&app_config.lanes);
+  for key in &request_keys {
+    channel.bind_queue(&mq_config.queue, &mq_config.exchange, key);
+  }
+  let mut unlaned = channel.fork();
+  unlaned.use_queue(&mq_config.queue);
+  let prefetch = app_config.lanes.get(Lane::default()).prefetch;
+  lane_consumer.feed(Lane::default(), unlaned.consume_with_prefetch(prefetch));
+  for (lane, queue) in Lane::ALL.iter().zip(&lane_queues) {
+    for key in &request_keys {
+      channel.bind_queue(queue, &mq_config.exchange, &lane.key(key));
+    }
+    let mut lane_channel = channel.fork();
+    lane_channel.use_queue(queue);
+    let prefetch = app_config.lanes.get(*lane).prefetch;
+    lane_consumer.feed(*lane, lane_channel.consume_with_prefetch(prefetch));
+  }
+  lane_consumer.start();
+
+  // Replies to calls made from handlers arrive on an exclusive queue of this instance with
+  // a separate consumer, so a handler waiting for a reply does not hold up the request queue.
+  // Job cancels go to every instance through the same queue, the job may run on any of them
+  let running = Running::default();
+  let jobs = JobRecords::connect(&app_config.redis).await;
+  let mut reply_channel = channel.clone();
+  let reply_queue = reply_channel.register_reply_queue(&topology.reply_queue(), &mq_config.exchange);
+  reply_channel.bind_queue(&reply_queue, &mq_config.exchange, job::CANCEL);
+  let mut consuming_channel = reply_channel.clone();
+  let cancels = running.clone();
+  thread::spawn(move || {
+    block_on(consuming_channel.start_consuming(|key, data| match key {
+      job::CANCEL => {
+        let id = String::from_utf8_lossy(data);
+        if cancels.cancel(&id) {
+          info!("cancelled job {}", id);
+        }
+      }
+      key => debug!("ignoring event {} on the reply queue", key),
+    }));
+  });
+  let breakers = Breakers::new(app_config.circuit_breaker.clone(), prometheus::default_registry());
+  reply_channel.use_lane(Lane::System);
+  // scheduled requests go out in confirm mode on a channel of their own, their replies end
+  // up on the reply queue of this instance and are dropped
+  let scheduler_channel = reply_channel.fork();
+  let services = ServiceClient::new(reply_channel, breakers, app_config.retry.clone());
+
+  let dedup = Deduplicator::new(db.clone(), &app_config.dedup);
+  tokio::spawn(dedup.clone().sweep());
+
+  // Domain events from other services wait in a durable queue of our own
+  let subscriptions = Arc::new(models::subscriptions());
+  let mut event_channel = channel.clone();
+  let event_consumer = event_channel.subscribe(
+    &events::queue_name(&mq_config.queue),
+    &mq_config.exchange,
+    &subscriptions.routing_keys(),
+  );
+  let event_context = EventContext {
+    db: db.clone(),
+    mq: channel.clone(),
+    scheduler: scheduler.clone(),
+  };
+  tokio::spawn(events::consume(
+    subscriptions,
+    event_context,
+    event_consumer,
+    dedup.clone(),
+  ));
+
+  // Events written to the outbox together with the change they describe are published
+  // on a channel of their own, since it runs in confirm mode
+  let relay_login = MqLogin {
     user: mq_config.user,
     password: mq_config.password,
     host: mq_config.host,
     port: mq_config.port,
   };
+  let mut relay_channel = MqChannel::connect(relay_login, replies, Arc::new(Tokio::current()));
+  relay_channel.use_exchange(&mq_config.exchange);
+  tokio::spawn(outbox::relay(db.clone(), relay_channel, app_config.outbox));
+  tokio::spawn(scheduler::run(db.clone(), scheduler_channel, app_config.scheduler));
 
-  let mq_data: HashMap<String, Vec<u8>> = HashMap::new();
-  let mq_messages = Arc::new(RwLock::new(mq_data));
-  let mut channel: MqChannel = MqChannel::connect(rabbit_mq_login, mq_messages);
-  channel.register_exchange(&mq_config.exchange);
-  channel.register_queue(&mq_config.queue);
-  let routing_keys = super::get_service_keys();
-  for key in routing_keys {
-    channel.bind_queue(&mq_config.queue, &mq_config.exchange, &key);
-  }
-  let consumer = channel.consume();
-
-  while let Some(msg) = consumer.clone().into_iter().next() {
-    let state = AppState { mq: channel.clone() };
-    let (_, msg) = msg.expect("error in consumer");
-    let _ = msg.ack(BasicAckOptions::default()).await;
-    handle_incomming_msg(msg, state).await;
+  while let Some((_, msg)) = lane_consumer.next().await {
+    let state = AppState {
+      mq: channel.clone(),
+      db: db.clone(),
+      models: model_registry.clone(),
+      policy: policy.clone(),
+      running: running.clone(),
+      jobs: jobs.clone(),
+      dedup: dedup.clone(),
+      services: services.clone(),
+      scheduler: scheduler.clone(),
+      files: file_store.clone(),
+    };
+    // acked once handled, so a crash redelivers the message and dedup catches the rest. A
+    // message that panics its handler is acked as well, redelivering it would only repeat that
+    let acker = msg.acker.clone();
+    if let Err(e) = tokio::spawn(handle_incomming_msg(msg, state)).await {
+      error!("dropping message that failed its handler: {}", e);
+    }
+    let _ = acker.ack(BasicAckOptions::default()).await;
   }
 }
 
 async fn handle_incomming_msg(msg: Delivery, mut state: AppState) {
-  info!("incomming Message: Routing_key {}", msg.routing_key.as_str());
-  let route: Vec<_> = msg.routing_key.as_str().split('.').collect();
-  let payload: request_response::Request = bincode::deserialize(&msg.data).unwrap();
-  let _resp: request_response::Response = match route[0] {
-    "backend" => handle_backend_requests(payload),
-    "" | &_ => handle_bad_requests(payload),
+  let (routing_key, lane) = Lane::split(msg.routing_key.as_str());
+  info!("incomming Message: Routing_key {} on lane {}", routing_key, lane.name());
+  let data = match state.mq.check_in(&msg.properties, &msg.data).await {
+    Ok(data) => data,
+    Err(e) => {
+      error!("unable to read the payload of {}: {}", routing_key, e);
+      let resp = request_response::Response::error("backend_service", 500, e);
+      state.mq.reply(msg.properties, bincode::serialize(&resp).unwrap()).await;
+      return;
+    }
+  };
+  // a request from an incompatible caller, or with a tenant id that does not validate, is
+  // answered like any other bad request instead of taking the consumer down
+  let payload: request_response::Request = match bincode::deserialize(&data) {
+    Ok(payload) => payload,
+    Err(e) => {
+      error!("invalid request on {}: {}", routing_key, e);
+      let resp = request_response::Response::error("backend_service", 400, format!("invalid request: {}", e));
+      state.mq.reply(msg.properties, bincode::serialize(&resp).unwrap()).await;
+      return;
+    }
+  };
+  let message_id = msg.properties.message_id().as_ref().map(|id| id.as_str().to_string());
+  if let Some(id) = &message_id {
+    match state.dedup.check(id, msg.redelivered).await {
+      Seen::New => (),
+      Seen::InProgress if rabbitmq::streamed_kind(&msg.properties).is_some() => {
+        info!("skipping duplicate streaming request {}", id);
+        return;
+      }
+      Seen::InProgress => {
+        // a copy of a request handled elsewhere right now, it gets the same reply
+        info!("waiting for the reply to duplicate message {}", id);
+        let (dedup, mut mq, id) = (state.dedup.clone(), state.mq.clone(), id.clone());
+        tokio::spawn(async move {
+          let reply = match dedup.wait_reply(&id, DUPLICATE_WAIT).await {
+            Some(reply) => reply,
+            None => {
+              let resp = request_response::Response::error("backend_service", 409, String::from("already in progress"));
+              bincode::serialize(&resp).unwrap()
+            }
+          };
+          mq.reply(msg.properties, reply).await;
+        });
+        return;
+      }
+      Seen::Replied(reply) => {
+        info!("replaying reply to duplicate message {}", id);
+        state.mq.reply(msg.properties, reply).await;
+        return;
+      }
+    }
+  }
+  let route: Vec<_> = routing_key.split('.').collect();
+  let authorized = state
+    .policy
+    .authorize_method(&payload.principal, &payload.tenant, route[0], &payload.method);
+  if rabbitmq::streamed_kind(&msg.properties).is_some() {
+    let out = StreamWriter::new(state.mq.clone(), msg.properties.clone(), state.running.clone());
+    let routing_key = routing_key.to_string();
+    tokio::spawn(handle_stream(state, routing_key, payload, authorized, out));
+    return;
+  }
+  let _resp: request_response::Response = match authorized {
+    Err(denial) => handle_denied(&mut state, payload, denial).await,
+    Ok(()) => dispatch(&mut state, &route, payload).await,
   };
   let resp: Vec<u8> = bincode::serialize(&_resp).unwrap();
+  if let Some(id) = &message_id {
+    state.dedup.replied(id, &resp).await;
+  }
   state.mq.reply(msg.properties, resp.to_vec()).await;
 }
 
+/// Streaming requests run on their own task so a long export does not hold up the queue.
+async fn handle_stream(
+  mut state: AppState,
+  routing_key: String,
+  payload: request_response::Request,
+  authorized: Result<(), Denial>,
+  mut out: StreamWriter,
+) {
+  let route: Vec<&str> = routing_key.split('.').collect();
+  // a job cancelled while it waited in the queue is not started at all
+  if out.is_job() && state.jobs.cancelled(out.id()).await {
+    info!("skipping cancelled job {}", out.id());
+    let resp = request_response::Response::error("backend_service", 409, String::from("cancelled"));
+    return out.finish(resp).await;
+  }
+  out.started().await;
+  let resp = match authorized {
+    Err(denial) => handle_denied(&mut state, payload, denial).await,
+    Ok(()) if payload.method == stream::EXPORT => match state.models.get(route[0]) {
+      _ if route.get(2).map_or(true, |t| *t != payload.tenant.as_str()) => handle_tenant_mismatch(payload),
+      Some(def) => crud::export(&state.db, def, payload, &mut out).await,
+      None => handle_bad_requests(payload),
+    },
+    Ok(()) => dispatch(&mut state, &route, payload).await,
+  };
+  out.finish(resp).await;
+}
+
 fn handle_bad_requests(req: request_response::Request) -> request_response::Response {
   request_response::Response {
     response_user: String::from("backend_service"),
+    status: 400,
     payload: req.payload,
     error: Some(String::from("invalid request topic")),
   }
 }
 
+async fn dispatch(
+  state: &mut AppState,
+  route: &[&str],
+  payload: request_response::Request,
+) -> request_response::Response {
+  match route[0] {
+    "backend" => handle_backend_requests(payload),
+    _ if route.get(2).map_or(true, |t| *t != payload.tenant.as_str()) => handle_tenant_mismatch(payload),
+    file::SUBJECT => files::handle_file_request(&state.db, state.files.as_ref(), payload).await,
+    model => match state.models.get(model) {
+      Some(def) => {
+        let writes = crud::is_write(&payload.method);
+        let tenant = payload.tenant.clone();
+        let resp = crud::handle_crud_request(&state.db, def, payload).await;
+        if writes && resp.error.is_none() {
+          // lets the web gateways invalidate cached reads of this model and push the
+          // change to websocket clients of the tenant
+          state
+            .mq
+            .publish(&format!("{}.changed.{}", def.name, tenant), resp.payload.clone())
+            .await;
+        }
+        resp
+      }
+      None => handle_bad_requests(payload),
+    },
+  }
+}
+
+async fn handle_denied(
+  state: &mut AppState,
+  req: request_response::Request,
+  denial: Denial,
+) -> request_response::Response {
+  error!("{} for user {} on tenant {}", denial, req.principal.user_id, req.tenant);
+  let event = denial.audit("backend_service", &req.principal, &req.tenant);
+  state
+    .mq
+    .publish(audit::DENIED, serde_json::to_vec(&event).unwrap())
+    .await;
+  request_response::Response::error("backend_service", 403, denial.to_string())
+}
+
+fn handle_tenant_mismatch(req: request_response::Request) -> request_response::Response {
+  error!("routing key tenant does not match request tenant {}", req.tenant);
+  request_response::Response::error("backend_service", 403, String::from("tenant mismatch"))
+}
+
 fn handle_backend_requests(_req: request_response::Request) -> request_response::Response {
   request_response::Response {
     response_user: String::from("backend_service"),
+    status: 200,
     payload: "Hello from the backend".as_bytes().to_vec(),
     error: None,
   }
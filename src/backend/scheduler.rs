@@ -0,0 +1,224 @@
+#[allow(unused_imports)]
+use log::{debug, error, info};
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio_postgres::Client;
+
+use super::config;
+use super::cron::Cron;
+use crate::shared_models::lane::Lane;
+use crate::shared_models::principal::Principal;
+use crate::shared_models::request_response::Request;
+use crate::shared_models::tenant::Tenant;
+use crate::utils::helpers;
+use crate::utils::rabbitmq::MqChannel;
+
+const TABLE: &str = "scheduled_messages";
+/// User and principal of the requests of cron entries from the config.
+const SCHEDULER_USER: &str = "scheduler";
+
+#[derive(Error, Debug)]
+pub enum ScheduleError {
+  #[error("invalid cron expression: {0}")]
+  InvalidCron(String),
+  #[error("invalid tenant: {0}")]
+  InvalidTenant(String),
+  #[error("database error: {0}")]
+  Database(#[from] tokio_postgres::Error),
+}
+
+/// Requests sent on the bus later, once or on a cron schedule, on the system lane. Schedules
+/// live in postgres, so they survive restarts and are shared by every backend instance.
+#[derive(Clone)]
+pub struct Scheduler {
+  db: Arc<Client>,
+}
+
+impl Scheduler {
+  pub fn new(db: Arc<Client>) -> Scheduler {
+    Scheduler { db }
+  }
+
+  /// Send `req` on `key` once, after `delay`. Returns the id of the schedule.
+  #[allow(dead_code)]
+  pub async fn schedule_in(&self, key: &str, req: &Request, delay: Duration) -> Result<String, ScheduleError> {
+    self.schedule_at(key, req, helpers::get_time() + delay.as_secs()).await
+  }
+
+  /// Send `req` on `key` once, at `at` in unix seconds.
+  #[allow(dead_code)]
+  pub async fn schedule_at(&self, key: &str, req: &Request, at: u64) -> Result<String, ScheduleError> {
+    let id = helpers::new_uuid();
+    let payload = bincode::serialize(req).unwrap();
+    self
+      .db
+      .execute(
+        &*format!(
+          "INSERT INTO {} (id, routing_key, payload, next_run, created_at) VALUES ($1, $2, $3, $4, $5)",
+          TABLE
+        ),
+        &[&id, &key, &payload, &(at as i64), &(helpers::get_time() as i64)],
+      )
+      .await?;
+    Ok(id)
+  }
+
+  /// Send `req` on `key` on every tick of `expression`. Schedules are named, so
+  /// registering the same name again replaces it, keeping its next run unless the
+  /// expression changed.
+  pub async fn schedule_cron(
+    &self,
+    name: &str,
+    expression: &str,
+    key: &str,
+    req: &Request,
+  ) -> Result<(), ScheduleError> {
+    let cron: Cron = expression.parse().map_err(ScheduleError::InvalidCron)?;
+    let payload = bincode::serialize(req).unwrap();
+    let now = helpers::get_time();
+    let next_run = cron
+      .next_after(now)
+      .ok_or_else(|| ScheduleError::InvalidCron(format!("{} never runs", expression)))?;
+    self
+      .db
+      .execute(
+        &*format!(
+          "INSERT INTO {table} (id, routing_key, payload, cron, next_run, created_at) VALUES ($1, $2, $3, $4, $5, $6)
+          ON CONFLICT (id) DO UPDATE SET routing_key = EXCLUDED.routing_key, payload = EXCLUDED.payload,
+            next_run = CASE WHEN {table}.cron IS DISTINCT FROM EXCLUDED.cron THEN EXCLUDED.next_run
+              ELSE {table}.next_run END,
+            cron = EXCLUDED.cron",
+          table = TABLE
+        ),
+        &[&name, &key, &payload, &expression, &(next_run as i64), &(now as i64)],
+      )
+      .await?;
+    Ok(())
+  }
+
+  /// Register a cron entry of the config, a request on the model's routing key for the
+  /// entry's tenant.
+  pub async fn schedule_entry(&self, entry: &config::CronEntry) -> Result<(), ScheduleError> {
+    let tenant = Tenant::parse(&entry.tenant).map_err(ScheduleError::InvalidTenant)?;
+    let key = format!("{}.request.{}", entry.model, tenant);
+    let req = Request {
+      request_user: String::from(SCHEDULER_USER),
+      principal: Principal {
+        user_id: String::from(SCHEDULER_USER),
+        tenant: Some(tenant.clone()),
+        roles: entry.roles.clone(),
+      },
+      tenant,
+      model: entry.model.clone(),
+      method: entry.method.clone(),
+      payload: entry.payload.clone().into_bytes(),
+    };
+    self.schedule_cron(&entry.name, &entry.expression, &key, &req).await
+  }
+
+  /// Remove a schedule. Returns false when it does not exist or a one-off already ran.
+  #[allow(dead_code)]
+  pub async fn cancel(&self, id: &str) -> Result<bool, ScheduleError> {
+    let deleted = self
+      .db
+      .execute(&*format!("DELETE FROM {} WHERE id = $1", TABLE), &[&id])
+      .await?;
+    Ok(deleted > 0)
+  }
+}
+
+pub async fn migrate(db: &Client) -> Result<(), tokio_postgres::Error> {
+  db.batch_execute(&format!(
+    "CREATE TABLE IF NOT EXISTS {table} (
+      id TEXT PRIMARY KEY,
+      routing_key TEXT NOT NULL,
+      payload BYTEA NOT NULL,
+      cron TEXT,
+      next_run BIGINT NOT NULL,
+      claimed_until BIGINT NOT NULL DEFAULT 0,
+      created_at BIGINT NOT NULL
+    );
+    ALTER TABLE {table} ADD COLUMN IF NOT EXISTS claimed_until BIGINT NOT NULL DEFAULT 0;
+    CREATE INDEX IF NOT EXISTS {table}_next_run_idx ON {table} (next_run);",
+    table = TABLE
+  ))
+  .await
+}
+
+/// Publish due requests every `interval` milliseconds until the process exits. A due
+/// schedule is claimed for `claim` seconds, so only one instance publishes it, and moved to
+/// its next run, or deleted for one-offs, once the broker confirmed it. A tick that failed
+/// to publish is tried again when the claim expires, and a tick published twice carries the
+/// same message id, so backends handle it once. Ticks missed while no instance was running
+/// fire once.
+pub async fn run(db: Arc<Client>, mut mq: MqChannel, config: config::Scheduler) {
+  mq.enable_confirms();
+  mq.use_lane(Lane::System);
+  let interval = Duration::from_millis(config.interval);
+  loop {
+    if let Err(e) = fire_due(&db, &mut mq, &config).await {
+      error!("scheduler failed: {}", e);
+    }
+    tokio::time::sleep(interval).await;
+  }
+}
+
+async fn fire_due(db: &Client, mq: &mut MqChannel, config: &config::Scheduler) -> Result<(), tokio_postgres::Error> {
+  let now = helpers::get_time();
+  let claim_until = (now + config.claim) as i64;
+  let rows = db
+    .query(
+      &*format!(
+        "UPDATE {table} SET claimed_until = $2 WHERE id IN (
+          SELECT id FROM {table} WHERE next_run <= $1 AND claimed_until < $1
+          ORDER BY next_run LIMIT $3 FOR UPDATE SKIP LOCKED
+        ) RETURNING id, routing_key, payload, cron, next_run",
+        table = TABLE
+      ),
+      &[&(now as i64), &claim_until, &config.batch],
+    )
+    .await?;
+  for row in rows {
+    let id: String = row.try_get(0)?;
+    let routing_key: String = row.try_get(1)?;
+    let payload: Vec<u8> = row.try_get(2)?;
+    let cron: Option<String> = row.try_get(3)?;
+    let next_run: i64 = row.try_get(4)?;
+    let following = match cron.as_deref().map(str::parse::<Cron>) {
+      Some(Ok(cron)) => cron.next_after(now),
+      Some(Err(e)) => {
+        error!("dropping schedule {} with invalid cron expression: {}", id, e);
+        None
+      }
+      None => None,
+    };
+    debug!("firing schedule {} on {}", id, routing_key);
+    let message_id = format!("{}@{}", id, next_run);
+    if let Err(e) = mq.publish_request_confirmed(&routing_key, payload, &message_id).await {
+      error!("unable to fire schedule {}, retrying once its claim expires: {}", id, e);
+      continue;
+    }
+    // unless the schedule was registered again in the meantime
+    match following {
+      Some(following) => {
+        db.execute(
+          &*format!(
+            "UPDATE {} SET next_run = $3, claimed_until = 0 WHERE id = $1 AND next_run = $2",
+            TABLE
+          ),
+          &[&id, &next_run, &(following as i64)],
+        )
+        .await?
+      }
+      None => {
+        db.execute(
+          &*format!("DELETE FROM {} WHERE id = $1 AND next_run = $2", TABLE),
+          &[&id, &next_run],
+        )
+        .await?
+      }
+    };
+  }
+  Ok(())
+}
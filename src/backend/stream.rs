@@ -0,0 +1,158 @@
+use lapin::BasicProperties;
+#[allow(unused_imports)]
+use log::{debug, error, info};
+use std::{
+  collections::HashMap,
+  sync::{Arc, RwLock},
+};
+
+use super::config;
+use crate::shared_models::job::{self, JobState};
+use crate::shared_models::request_response::Response;
+use crate::shared_models::stream::{StreamEvent, StreamMessage};
+use crate::utils::rabbitmq::MqChannel;
+
+/// Streaming requests running on this instance by correlation id, with a flag telling
+/// whether the caller cancelled them.
+#[derive(Clone, Default)]
+pub struct Running {
+  requests: Arc<RwLock<HashMap<String, bool>>>,
+}
+
+impl Running {
+  /// Flag a running request as cancelled. Returns false if it does not run here.
+  pub fn cancel(&self, id: &str) -> bool {
+    match self.requests.write().unwrap().get_mut(id) {
+      Some(cancelled) => {
+        *cancelled = true;
+        true
+      }
+      None => false,
+    }
+  }
+
+  fn is_cancelled(&self, id: &str) -> bool {
+    self.requests.read().unwrap().get(id).copied().unwrap_or(false)
+  }
+}
+
+/// Reads the job records the web gateway keeps in redis. A job cancelled while it was
+/// still queued never reaches this instance's `Running`, its record is what tells.
+#[derive(Clone)]
+pub struct JobRecords {
+  redis: redis::aio::MultiplexedConnection,
+}
+
+impl JobRecords {
+  pub async fn connect(config: &config::Redis) -> JobRecords {
+    let url = format!("redis://{}:{}/", config.host, config.port);
+    let connection = match redis::Client::open(url) {
+      Ok(client) => client.get_multiplexed_tokio_connection().await,
+      Err(e) => Err(e),
+    };
+    match connection {
+      Ok(redis) => JobRecords { redis },
+      Err(e) => panic!("unable to connect to redis. Error: {}", e),
+    }
+  }
+
+  /// Whether job `id` was cancelled. Jobs whose record cannot be read run.
+  pub async fn cancelled(&self, id: &str) -> bool {
+    let state: Result<Option<String>, _> = redis::cmd("HGET")
+      .arg(job::record_key(id))
+      .arg("state")
+      .query_async(&mut self.redis.clone())
+      .await;
+    match state {
+      Ok(state) => state.as_deref().and_then(JobState::parse) == Some(JobState::Cancelled),
+      Err(e) => {
+        error!("unable to read the record of job {}: {}", id, e);
+        false
+      }
+    }
+  }
+}
+
+/// Sends the messages of a streamed reply back to the caller of a streaming request,
+/// numbering them in order.
+pub struct StreamWriter {
+  mq: MqChannel,
+  props: BasicProperties,
+  id: String,
+  running: Running,
+  seq: u64,
+}
+
+impl StreamWriter {
+  pub fn new(mq: MqChannel, props: BasicProperties, running: Running) -> StreamWriter {
+    let id = props
+      .correlation_id()
+      .as_ref()
+      .map(|id| id.as_str().to_string())
+      .unwrap_or_default();
+    running.requests.write().unwrap().insert(id.clone(), false);
+    StreamWriter {
+      mq,
+      props,
+      id,
+      running,
+      seq: 0,
+    }
+  }
+
+  pub fn id(&self) -> &str {
+    &self.id
+  }
+
+  pub fn is_job(&self) -> bool {
+    self
+      .props
+      .kind()
+      .as_ref()
+      .map_or(false, |kind| kind.as_str() == job::KIND)
+  }
+
+  /// Tell the caller the request was picked up.
+  pub async fn started(&mut self) {
+    self.send(StreamEvent::Progress, 200, b"{}".to_vec()).await
+  }
+
+  /// Whether the caller cancelled the request. Long operations should check this between
+  /// steps and stop early.
+  pub fn cancelled(&self) -> bool {
+    self.running.is_cancelled(&self.id)
+  }
+
+  pub async fn progress(&mut self, payload: Vec<u8>) {
+    self.send(StreamEvent::Progress, 200, payload).await
+  }
+
+  pub async fn partial(&mut self, payload: Vec<u8>) {
+    self.send(StreamEvent::Partial, 200, payload).await
+  }
+
+  /// End the stream with the outcome of the operation.
+  pub async fn finish(mut self, res: Response) {
+    self.running.requests.write().unwrap().remove(&self.id);
+    match res.error {
+      Some(e) => {
+        let payload = serde_json::to_vec(&e).unwrap();
+        self.send(StreamEvent::Error, res.status, payload).await
+      }
+      None => self.send(StreamEvent::Final, res.status, res.payload).await,
+    }
+  }
+
+  async fn send(&mut self, event: StreamEvent, status: u16, payload: Vec<u8>) {
+    self.seq += 1;
+    let msg = StreamMessage {
+      seq: self.seq,
+      event,
+      status,
+      payload,
+    };
+    debug!("stream message {} ({})", msg.seq, event.name());
+    let data = bincode::serialize(&msg).unwrap();
+    self.mq.reply_stream(&self.props, data).await;
+  }
+}
@@ -4,6 +4,9 @@ use backend::run::start_server;
 pub mod shared_models;
 pub mod utils;
 
+#[macro_use]
+extern crate validator_derive;
+
 fn main() {
   env_logger::init();
   start_server();
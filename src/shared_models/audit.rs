@@ -0,0 +1,14 @@
+//! Audit events published on the bus under `audit.<action>`, JSON encoded.
+use serde::{Deserialize, Serialize};
+
+pub const DENIED: &str = "audit.denied";
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuditEvent {
+  pub service: String,
+  pub user_id: String,
+  pub tenant: String,
+  pub target: String,
+  pub reason: String,
+  pub time: u64,
+}
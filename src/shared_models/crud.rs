@@ -0,0 +1,31 @@
+//! Payloads exchanged with the backend CRUD service on `<model>.request` routing keys.
+//!
+//! Records carry arbitrary JSON documents, so unlike the other messages these payloads
+//! are encoded with `serde_json` rather than `bincode`.
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+pub const CREATE: &str = "create";
+pub const GET: &str = "get";
+pub const LIST: &str = "list";
+pub const UPDATE: &str = "update";
+pub const DELETE: &str = "delete";
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Record {
+  pub id: String,
+  pub data: Value,
+  pub created_at: u64,
+  pub updated_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RecordId {
+  pub id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Update {
+  pub id: String,
+  pub data: Value,
+}
@@ -0,0 +1,73 @@
+//! Domain events, published fire-and-forget on `<subject>.<name>` routing keys, e.g.
+//! `user.created`. Bodies are JSON; the `schema_version` header carries `Event::VERSION`
+//! so subscribers can reject events newer than they understand.
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use super::crud::Record;
+use super::tenant::Tenant;
+
+pub const SCHEMA_VERSION_HEADER: &str = "schema_version";
+pub const EVENT_HEADER: &str = "event";
+
+pub trait Event: Serialize + DeserializeOwned {
+  /// Last word of the routing key.
+  const NAME: &'static str;
+  /// Bumped whenever a field changes meaning or a required field is added. Adding an
+  /// optional field does not need a new version.
+  const VERSION: u16;
+
+  /// What the event is about, the leading words of the routing key.
+  fn subject(&self) -> String;
+
+  fn routing_key(&self) -> String {
+    format!("{}.{}", self.subject(), Self::NAME)
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RecordCreated {
+  pub model: String,
+  pub tenant: Tenant,
+  pub record: Record,
+}
+
+impl Event for RecordCreated {
+  const NAME: &'static str = "created";
+  const VERSION: u16 = 1;
+
+  fn subject(&self) -> String {
+    self.model.clone()
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RecordUpdated {
+  pub model: String,
+  pub tenant: Tenant,
+  pub record: Record,
+}
+
+impl Event for RecordUpdated {
+  const NAME: &'static str = "updated";
+  const VERSION: u16 = 1;
+
+  fn subject(&self) -> String {
+    self.model.clone()
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RecordDeleted {
+  pub model: String,
+  pub tenant: Tenant,
+  pub id: String,
+}
+
+impl Event for RecordDeleted {
+  const NAME: &'static str = "deleted";
+  const VERSION: u16 = 1;
+
+  fn subject(&self) -> String {
+    self.model.clone()
+  }
+}
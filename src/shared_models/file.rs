@@ -0,0 +1,20 @@
+//! Files uploaded through the web gateway. The content is streamed into the file store
+//! and backends only ever see a `FileRef`, on `files.request.<tenant>` routing keys with
+//! the CRUD methods `create`, `get` and `delete`. Payloads are JSON.
+use serde::{Deserialize, Serialize};
+
+pub const SUBJECT: &str = "files";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FileRef {
+  pub id: String,
+  /// Id of the content in the file store.
+  pub blob: String,
+  pub name: String,
+  pub content_type: String,
+  pub size: u64,
+  /// Hex encoded SHA-256 of the content.
+  pub sha256: String,
+  #[serde(default)]
+  pub created_at: u64,
+}
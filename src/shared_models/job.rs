@@ -0,0 +1,57 @@
+//! Asynchronous jobs. A job request is a streaming request (see `stream`) published with
+//! the AMQP `type` property set to `KIND`; the web gateway folds the streamed replies into
+//! a job record instead of serving them as events.
+use serde::{Deserialize, Serialize};
+
+pub const KIND: &str = "job";
+
+/// Routing key of the event asking backends to stop a job. The payload is the job id. Every
+/// backend instance gets it on its own queue, since any of them may run the job.
+pub const CANCEL: &str = "job.cancel";
+
+/// Routing key the replies to a job are sent with. It leads to the durable queue every
+/// gateway instance shares, so progress survives the instance that enqueued the job.
+pub const REPLY_KEY: &str = "reply.web_service";
+
+/// Redis hash of the record of job `id`, kept by the web gateway. Backends read its
+/// `state` to skip jobs cancelled while still queued.
+pub fn record_key(id: &str) -> String {
+  format!("{}:{}", KIND, id)
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+  Queued,
+  Running,
+  Succeeded,
+  Failed,
+  Cancelled,
+}
+
+impl JobState {
+  pub fn name(&self) -> &'static str {
+    match self {
+      JobState::Queued => "queued",
+      JobState::Running => "running",
+      JobState::Succeeded => "succeeded",
+      JobState::Failed => "failed",
+      JobState::Cancelled => "cancelled",
+    }
+  }
+
+  pub fn parse(state: &str) -> Option<JobState> {
+    match state {
+      "queued" => Some(JobState::Queued),
+      "running" => Some(JobState::Running),
+      "succeeded" => Some(JobState::Succeeded),
+      "failed" => Some(JobState::Failed),
+      "cancelled" => Some(JobState::Cancelled),
+      _ => None,
+    }
+  }
+
+  pub fn is_final(&self) -> bool {
+    matches!(self, JobState::Succeeded | JobState::Failed | JobState::Cancelled)
+  }
+}
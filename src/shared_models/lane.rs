@@ -0,0 +1,120 @@
+//! Priority lanes for requests. A request is published with the lane of its caller as the
+//! fourth word of its routing key, `<subject>.request.<tenant>.<lane>`, and as its AMQP
+//! priority, so backends can consume every lane from a queue of its own and batch work
+//! does not hold up interactive calls.
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Lane {
+  /// Requests made while a user waits for the answer, like HTTP calls.
+  Interactive,
+  /// Calls between services and scheduled work.
+  System,
+  /// Background jobs and bulk work.
+  Batch,
+}
+
+impl Default for Lane {
+  fn default() -> Self {
+    Lane::Interactive
+  }
+}
+
+impl Lane {
+  pub const ALL: [Lane; 3] = [Lane::Interactive, Lane::System, Lane::Batch];
+  /// The highest priority of any lane, the `x-max-priority` of request queues.
+  pub const MAX_PRIORITY: u8 = 9;
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      Lane::Interactive => "interactive",
+      Lane::System => "system",
+      Lane::Batch => "batch",
+    }
+  }
+
+  pub fn parse(lane: &str) -> Option<Lane> {
+    match lane {
+      "interactive" => Some(Lane::Interactive),
+      "system" => Some(Lane::System),
+      "batch" => Some(Lane::Batch),
+      _ => None,
+    }
+  }
+
+  /// AMQP priority of the requests in this lane.
+  pub fn priority(&self) -> u8 {
+    match self {
+      Lane::Interactive => Lane::MAX_PRIORITY,
+      Lane::System => 5,
+      Lane::Batch => 1,
+    }
+  }
+
+  /// `key` routed to this lane.
+  pub fn key(&self, key: &str) -> String {
+    format!("{}.{}", key, self.name())
+  }
+
+  /// Split the lane off a request routing key. The lane is only looked for as the fourth
+  /// word, so a tenant named like a lane stays the tenant. Keys without a lane belong to
+  /// the default lane.
+  pub fn split(key: &str) -> (&str, Lane) {
+    let lane = key
+      .match_indices('.')
+      .nth(2)
+      .and_then(|(end, _)| Some((end, Lane::parse(&key[end + 1..])?)));
+    match lane {
+      Some((end, lane)) => (&key[..end], lane),
+      None => (key, Lane::default()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn splits_the_lane_off_the_fourth_word() {
+    assert_eq!(
+      Lane::split("user.request.acme.batch"),
+      ("user.request.acme", Lane::Batch)
+    );
+    assert_eq!(
+      Lane::split("user.request.acme.system"),
+      ("user.request.acme", Lane::System)
+    );
+    assert_eq!(
+      Lane::split("user.request.acme"),
+      ("user.request.acme", Lane::Interactive)
+    );
+  }
+
+  #[test]
+  fn keeps_tenants_named_like_lanes() {
+    assert_eq!(
+      Lane::split("user.request.batch"),
+      ("user.request.batch", Lane::Interactive)
+    );
+    assert_eq!(
+      Lane::split("user.request.system.batch"),
+      ("user.request.system", Lane::Batch)
+    );
+    assert_eq!(
+      Lane::split("user.request.acme.unknown"),
+      ("user.request.acme.unknown", Lane::Interactive)
+    );
+  }
+
+  #[test]
+  fn key_and_split_round_trip() {
+    for lane in Lane::ALL.iter() {
+      assert_eq!(
+        Lane::split(&lane.key("files.request.acme")),
+        ("files.request.acme", *lane)
+      );
+    }
+  }
+}
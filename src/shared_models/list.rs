@@ -0,0 +1,78 @@
+//! Query and page types shared by every list style RPC.
+//!
+//! Pagination is cursor based: a page carries an opaque `next_cursor` which is passed back
+//! unchanged to fetch the following page. Like the CRUD payloads these are JSON encoded.
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_LIMIT: u32 = 20;
+pub const MAX_LIMIT: u32 = 100;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ListQuery {
+  pub limit: u32,
+  pub cursor: Option<String>,
+  pub sort: Vec<Sort>,
+  pub filters: Vec<Filter>,
+}
+
+impl Default for ListQuery {
+  fn default() -> Self {
+    ListQuery {
+      limit: DEFAULT_LIMIT,
+      cursor: None,
+      sort: Vec::new(),
+      filters: Vec::new(),
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Sort {
+  pub field: String,
+  pub direction: SortDirection,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum SortDirection {
+  Asc,
+  Desc,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Filter {
+  pub field: String,
+  pub op: FilterOp,
+  pub value: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum FilterOp {
+  Eq,
+  Ne,
+  Lt,
+  Lte,
+  Gt,
+  Gte,
+  Contains,
+}
+
+impl FilterOp {
+  pub fn parse(op: &str) -> Option<FilterOp> {
+    match op {
+      "eq" => Some(FilterOp::Eq),
+      "ne" => Some(FilterOp::Ne),
+      "lt" => Some(FilterOp::Lt),
+      "lte" => Some(FilterOp::Lte),
+      "gt" => Some(FilterOp::Gt),
+      "gte" => Some(FilterOp::Gte),
+      "contains" => Some(FilterOp::Contains),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Page<T> {
+  pub items: Vec<T>,
+  pub next_cursor: Option<String>,
+}
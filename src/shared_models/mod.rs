@@ -1 +1,11 @@
+pub mod audit;
+pub mod crud;
+pub mod event;
+pub mod file;
+pub mod job;
+pub mod lane;
+pub mod list;
+pub mod principal;
 pub mod request_response;
+pub mod stream;
+pub mod tenant;
@@ -0,0 +1,24 @@
+use serde::{Deserialize, Serialize};
+
+use super::tenant::Tenant;
+
+pub const ANONYMOUS: &str = "anonymous";
+
+/// The caller a request is made for, as authenticated by the web gateway.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Principal {
+  pub user_id: String,
+  /// Tenant the caller belongs to, if known.
+  pub tenant: Option<Tenant>,
+  pub roles: Vec<String>,
+}
+
+impl Principal {
+  pub fn anonymous() -> Principal {
+    Principal {
+      user_id: ANONYMOUS.to_string(),
+      tenant: None,
+      roles: Vec::new(),
+    }
+  }
+}
@@ -1,16 +1,44 @@
 use serde::{Deserialize, Serialize};
 
+use super::principal::Principal;
+use super::tenant::Tenant;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Request {
   pub request_user: String,
+  pub principal: Principal,
+  /// Tenant the request acts on behalf of, resolved by the web gateway.
+  pub tenant: Tenant,
   pub model: String,
   pub method: String,
   pub payload: Vec<u8>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Response {
   pub response_user: String,
+  /// HTTP style status code, used by the web gateway as the response status.
+  pub status: u16,
   pub payload: Vec<u8>,
   pub error: Option<String>,
 }
+
+impl Response {
+  pub fn ok(response_user: &str, payload: Vec<u8>) -> Response {
+    Response {
+      response_user: response_user.to_string(),
+      status: 200,
+      payload,
+      error: None,
+    }
+  }
+
+  pub fn error(response_user: &str, status: u16, error: String) -> Response {
+    Response {
+      response_user: response_user.to_string(),
+      status,
+      payload: Vec::new(),
+      error: Some(error),
+    }
+  }
+}
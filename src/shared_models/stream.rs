@@ -0,0 +1,51 @@
+//! Streamed replies for backend operations that outlive the RPC timeout.
+//!
+//! A streaming request is published with the AMQP `type` property set to `KIND`. The
+//! backend answers with any number of `StreamMessage`s on the caller's reply queue, all
+//! carrying the request's correlation id, and ends with a `Final` or `Error` message.
+use serde::{Deserialize, Serialize};
+
+pub const KIND: &str = "stream";
+
+/// CRUD method that streams every record of a model page by page.
+pub const EXPORT: &str = "export";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamEvent {
+  Progress,
+  Partial,
+  Final,
+  Error,
+}
+
+impl StreamEvent {
+  pub fn name(&self) -> &'static str {
+    match self {
+      StreamEvent::Progress => "progress",
+      StreamEvent::Partial => "partial",
+      StreamEvent::Final => "final",
+      StreamEvent::Error => "error",
+    }
+  }
+
+  pub fn is_last(&self) -> bool {
+    matches!(self, StreamEvent::Final | StreamEvent::Error)
+  }
+}
+
+/// One message of a stream. `seq` starts at 1 and increases by one per message, so
+/// receivers can resume after the last sequence number they saw. Payloads are JSON.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StreamMessage {
+  pub seq: u64,
+  pub event: StreamEvent,
+  pub status: u16,
+  pub payload: Vec<u8>,
+}
+
+/// Payload of the `progress` and `final` messages of an export.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ExportProgress {
+  pub exported: u64,
+}
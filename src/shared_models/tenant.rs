@@ -0,0 +1,51 @@
+use serde::{Deserialize, Serialize};
+use std::{convert::TryFrom, fmt};
+
+pub const PUBLIC_TENANT: &str = "public";
+
+/// A validated tenant (company) id. Tenant ids end up in routing keys, redis keys and SQL
+/// parameters, so they are restricted to ascii alphanumerics, `-` and `_`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(try_from = "String", into = "String")]
+pub struct Tenant(String);
+
+impl Tenant {
+  pub fn parse(id: &str) -> Result<Tenant, String> {
+    if id.is_empty() || id.len() > 64 {
+      return Err(format!("invalid tenant id length {}", id.len()));
+    }
+    if !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+      return Err(format!("invalid tenant id {}", id));
+    }
+    Ok(Tenant(id.to_string()))
+  }
+
+  /// Tenant used for requests that do not act on tenant owned data.
+  pub fn public() -> Tenant {
+    Tenant(PUBLIC_TENANT.to_string())
+  }
+
+  pub fn as_str(&self) -> &str {
+    &self.0
+  }
+}
+
+impl TryFrom<String> for Tenant {
+  type Error = String;
+
+  fn try_from(id: String) -> Result<Self, Self::Error> {
+    Tenant::parse(&id)
+  }
+}
+
+impl From<Tenant> for String {
+  fn from(tenant: Tenant) -> Self {
+    tenant.0
+  }
+}
+
+impl fmt::Display for Tenant {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.0)
+  }
+}
@@ -0,0 +1,309 @@
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use prometheus::{IntCounterVec, IntGaugeVec, Opts, Registry};
+use serde::Deserialize;
+use std::{
+  collections::HashMap,
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
+};
+use thiserror::Error;
+
+/// A circuit trips after `error_threshold` failed or `timeout_threshold` timed out calls in
+/// a row and fails fast for `open_for` seconds. It then lets `half_open_probes` calls
+/// through; a successful probe closes it again, a failed one reopens it. At most
+/// `max_in_flight` calls per routing key run at once.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Settings {
+  #[serde(default = "default_error_threshold")]
+  pub error_threshold: usize,
+  #[serde(default = "default_timeout_threshold")]
+  pub timeout_threshold: usize,
+  #[serde(default = "default_open_for")]
+  pub open_for: u64,
+  #[serde(default = "default_half_open_probes")]
+  pub half_open_probes: usize,
+  #[serde(default = "default_max_in_flight")]
+  pub max_in_flight: usize,
+}
+
+impl Default for Settings {
+  fn default() -> Self {
+    Settings {
+      error_threshold: default_error_threshold(),
+      timeout_threshold: default_timeout_threshold(),
+      open_for: default_open_for(),
+      half_open_probes: default_half_open_probes(),
+      max_in_flight: default_max_in_flight(),
+    }
+  }
+}
+
+fn default_error_threshold() -> usize {
+  5
+}
+
+fn default_timeout_threshold() -> usize {
+  3
+}
+
+fn default_open_for() -> u64 {
+  30
+}
+
+fn default_half_open_probes() -> usize {
+  1
+}
+
+fn default_max_in_flight() -> usize {
+  100
+}
+
+#[derive(Error, Debug, Clone, Copy, PartialEq)]
+pub enum Rejected {
+  #[error("circuit open")]
+  Open,
+  #[error("too many calls in flight")]
+  Full,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+  Closed,
+  Open,
+  HalfOpen,
+}
+
+impl State {
+  pub fn name(&self) -> &'static str {
+    match self {
+      State::Closed => "closed",
+      State::Open => "open",
+      State::HalfOpen => "half_open",
+    }
+  }
+
+  /// Value of the state gauge.
+  fn level(&self) -> i64 {
+    match self {
+      State::Closed => 0,
+      State::HalfOpen => 1,
+      State::Open => 2,
+    }
+  }
+}
+
+#[derive(Default)]
+struct Circuit {
+  errors: usize,
+  timeouts: usize,
+  opened_at: Option<Instant>,
+  in_flight: usize,
+  probes: usize,
+}
+
+impl Circuit {
+  fn state(&self, open_for: Duration) -> State {
+    match self.opened_at {
+      None => State::Closed,
+      Some(at) if at.elapsed() < open_for => State::Open,
+      Some(_) => State::HalfOpen,
+    }
+  }
+}
+
+#[derive(Clone, Copy)]
+enum Outcome {
+  Success,
+  Error,
+  Timeout,
+}
+
+struct Metrics {
+  state: IntGaugeVec,
+  in_flight: IntGaugeVec,
+  rejected: IntCounterVec,
+}
+
+/// Circuit breakers and bulkheads for outgoing RPCs, one per routing key. Keys are
+/// reduced to their first two words, so `user.request.<tenant>` shares one circuit for
+/// every tenant.
+#[derive(Clone)]
+pub struct Breakers {
+  settings: Settings,
+  circuits: Arc<Mutex<HashMap<String, Circuit>>>,
+  metrics: Arc<Metrics>,
+}
+
+impl Breakers {
+  /// Circuit state, in-flight and rejected call metrics are registered with `registry`.
+  pub fn new(settings: Settings, registry: &Registry) -> Breakers {
+    let metrics = Metrics {
+      state: IntGaugeVec::new(
+        Opts::new(
+          "rpc_circuit_state",
+          "Circuit state per routing key, 0 closed, 1 half open, 2 open",
+        ),
+        &["key"],
+      )
+      .unwrap(),
+      in_flight: IntGaugeVec::new(Opts::new("rpc_in_flight", "RPCs waiting for a reply"), &["key"]).unwrap(),
+      rejected: IntCounterVec::new(
+        Opts::new(
+          "rpc_rejected_total",
+          "RPCs refused by an open circuit or a full bulkhead",
+        ),
+        &["key", "reason"],
+      )
+      .unwrap(),
+    };
+    for collector in vec![
+      Box::new(metrics.state.clone()) as Box<dyn prometheus::core::Collector>,
+      Box::new(metrics.in_flight.clone()),
+      Box::new(metrics.rejected.clone()),
+    ] {
+      if let Err(e) = registry.register(collector) {
+        error!("unable to register circuit breaker metrics: {}", e);
+      }
+    }
+    Breakers {
+      settings,
+      circuits: Arc::new(Mutex::new(HashMap::new())),
+      metrics: Arc::new(metrics),
+    }
+  }
+
+  /// Ask to call `key`. The returned permit must be completed with the outcome of the call.
+  pub fn acquire(&self, key: &str) -> Result<Permit, Rejected> {
+    let key = circuit_key(key);
+    let open_for = Duration::from_secs(self.settings.open_for);
+    let mut circuits = self.circuits.lock().unwrap();
+    let circuit = circuits.entry(key.clone()).or_default();
+    let state = circuit.state(open_for);
+    let rejected = match state {
+      State::Open => Some(Rejected::Open),
+      State::HalfOpen if circuit.probes >= self.settings.half_open_probes => Some(Rejected::Open),
+      _ if circuit.in_flight >= self.settings.max_in_flight => Some(Rejected::Full),
+      _ => None,
+    };
+    if let Some(rejected) = rejected {
+      let reason = match rejected {
+        Rejected::Open => "open",
+        Rejected::Full => "full",
+      };
+      self.metrics.rejected.with_label_values(&[&key, reason]).inc();
+      return Err(rejected);
+    }
+    let probe = state == State::HalfOpen;
+    if probe {
+      circuit.probes += 1;
+    }
+    circuit.in_flight += 1;
+    self
+      .metrics
+      .in_flight
+      .with_label_values(&[&key])
+      .set(circuit.in_flight as i64);
+    self.metrics.state.with_label_values(&[&key]).set(state.level());
+    Ok(Permit {
+      breakers: self.clone(),
+      key,
+      probe,
+      done: false,
+    })
+  }
+
+  /// State of every circuit seen so far, by key.
+  pub fn states(&self) -> Vec<(String, State)> {
+    let open_for = Duration::from_secs(self.settings.open_for);
+    let circuits = self.circuits.lock().unwrap();
+    let mut states: Vec<(String, State)> = circuits.iter().map(|(k, c)| (k.clone(), c.state(open_for))).collect();
+    states.sort_by(|a, b| a.0.cmp(&b.0));
+    states
+  }
+
+  fn complete(&self, key: &str, probe: bool, outcome: Option<Outcome>) {
+    let mut circuits = self.circuits.lock().unwrap();
+    let circuit = circuits.entry(key.to_string()).or_default();
+    circuit.in_flight = circuit.in_flight.saturating_sub(1);
+    if probe {
+      circuit.probes = circuit.probes.saturating_sub(1);
+    }
+    match outcome {
+      Some(Outcome::Success) => {
+        if circuit.opened_at.is_some() {
+          info!("circuit {} closed", key);
+        }
+        *circuit = Circuit {
+          in_flight: circuit.in_flight,
+          probes: circuit.probes,
+          ..Circuit::default()
+        };
+      }
+      Some(failure) => {
+        match failure {
+          Outcome::Timeout => circuit.timeouts += 1,
+          _ => circuit.errors += 1,
+        }
+        let tripped =
+          circuit.errors >= self.settings.error_threshold || circuit.timeouts >= self.settings.timeout_threshold;
+        if probe || (circuit.opened_at.is_none() && tripped) {
+          warn!(
+            "circuit {} open after {} errors and {} timeouts",
+            key, circuit.errors, circuit.timeouts
+          );
+          circuit.opened_at = Some(Instant::now());
+        }
+      }
+      // dropped without an outcome, e.g. the caller went away
+      None => (),
+    }
+    let state = circuit.state(Duration::from_secs(self.settings.open_for));
+    self
+      .metrics
+      .in_flight
+      .with_label_values(&[key])
+      .set(circuit.in_flight as i64);
+    self.metrics.state.with_label_values(&[key]).set(state.level());
+  }
+}
+
+/// A granted call. Report how it went with `success`, `error` or `timeout`; dropping it
+/// only frees its bulkhead slot.
+pub struct Permit {
+  breakers: Breakers,
+  key: String,
+  probe: bool,
+  done: bool,
+}
+
+impl Permit {
+  pub fn success(self) {
+    self.finish(Outcome::Success)
+  }
+
+  pub fn error(self) {
+    self.finish(Outcome::Error)
+  }
+
+  pub fn timeout(self) {
+    self.finish(Outcome::Timeout)
+  }
+
+  fn finish(mut self, outcome: Outcome) {
+    self.done = true;
+    self.breakers.complete(&self.key, self.probe, Some(outcome));
+  }
+}
+
+impl Drop for Permit {
+  fn drop(&mut self) {
+    if !self.done {
+      self.breakers.complete(&self.key, self.probe, None);
+    }
+  }
+}
+
+fn circuit_key(key: &str) -> String {
+  key.split('.').take(2).collect::<Vec<&str>>().join(".")
+}
@@ -0,0 +1,544 @@
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+use std::{
+  fmt, fs,
+  io::{self, Read, Seek, SeekFrom, Write},
+  path::PathBuf,
+  sync::{mpsc, Arc, Mutex},
+  thread,
+  time::{Duration, SystemTime},
+};
+use uuid::Uuid;
+
+use crate::utils::helpers;
+
+/// Payloads over `threshold` bytes are put in `store` and the message carries a reference
+/// instead. Blobs are removed `ttl` seconds after they were stored, checked every
+/// `sweep_interval` seconds. Without a store every payload travels inline.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Settings {
+  #[serde(default)]
+  pub store: Option<StoreKind>,
+  /// Directory of the `filesystem` store, shared by every service.
+  #[serde(default = "default_path")]
+  pub path: String,
+  #[serde(default = "default_threshold")]
+  pub threshold: usize,
+  #[serde(default = "default_ttl")]
+  pub ttl: u64,
+  #[serde(default = "default_sweep_interval")]
+  pub sweep_interval: u64,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum StoreKind {
+  Filesystem,
+  /// Postgres large objects, in the database of the service.
+  Postgres,
+}
+
+impl Default for Settings {
+  fn default() -> Self {
+    Settings {
+      store: None,
+      path: default_path(),
+      threshold: default_threshold(),
+      ttl: default_ttl(),
+      sweep_interval: default_sweep_interval(),
+    }
+  }
+}
+
+fn default_path() -> String {
+  String::from("var/blobs")
+}
+
+fn default_threshold() -> usize {
+  256 * 1024
+}
+
+fn default_ttl() -> u64 {
+  86400
+}
+
+fn default_sweep_interval() -> u64 {
+  300
+}
+
+/// Where a payload went, sent in place of the payload.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlobRef {
+  pub id: String,
+  /// Hex encoded SHA-256 of the payload.
+  pub sha256: String,
+  pub size: u64,
+}
+
+/// Storage for payloads too large to send inline. Blobs are written once and never
+/// changed; they may be read any number of times until they expire, since requests are
+/// retried and broadcasts are read by every receiver.
+pub trait BlobStore: Send + Sync {
+  fn put(&self, id: &str, data: &[u8]) -> Result<(), String>;
+  /// `None` when there is no blob `id`, or it has expired.
+  fn get(&self, id: &str) -> Result<Option<Vec<u8>>, String>;
+  /// Write blob `id` piece by piece, for content too large to hold in memory. The blob
+  /// exists once the writer is finished.
+  fn create(&self, id: &str) -> Result<Box<dyn BlobWriter>, String>;
+  /// At most `len` bytes of blob `id` from `offset` on.
+  fn read_range(&self, id: &str, offset: u64, len: u64) -> Result<Option<Vec<u8>>, String>;
+  fn delete(&self, id: &str) -> Result<(), String>;
+  /// Remove blobs stored more than `ttl` ago, returning how many were removed.
+  fn sweep(&self, ttl: Duration) -> Result<usize, String>;
+}
+
+/// Writers dropped before they are finished drop what was written, so a failed or
+/// abandoned write leaves nothing behind.
+pub trait BlobWriter: Send {
+  fn write(&mut self, chunk: &[u8]) -> Result<(), String>;
+  fn finish(self: Box<Self>) -> Result<(), String>;
+  /// Drop what was written so far, as dropping the writer does.
+  fn abort(self: Box<Self>) {}
+}
+
+/// Open a store of `kind`. Filesystem stores keep their blobs under `path`, postgres
+/// stores list them in `table` of `database`.
+pub fn open_store(kind: StoreKind, path: &str, database: &str, table: &str) -> Result<Arc<dyn BlobStore>, String> {
+  Ok(match kind {
+    StoreKind::Filesystem => Arc::new(FilesystemStore::open(path)?),
+    StoreKind::Postgres => Arc::new(PostgresStore::connect(database, table)?),
+  })
+}
+
+#[derive(Clone)]
+pub struct ClaimCheck {
+  store: Arc<dyn BlobStore>,
+  threshold: usize,
+}
+
+impl fmt::Debug for ClaimCheck {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "ClaimCheck {{ threshold: {} }}", self.threshold)
+  }
+}
+
+impl ClaimCheck {
+  /// Open the configured store and start sweeping it, `None` when no store is configured.
+  /// `database` is the connection string of the postgres store.
+  pub fn open(settings: &Settings, database: &str) -> Option<ClaimCheck> {
+    let store = match open_store(settings.store?, &settings.path, database, "blobs") {
+      Ok(store) => store,
+      Err(e) => panic!("unable to open blob store. Error: {}", e),
+    };
+    let sweeper = store.clone();
+    let (interval, ttl) = (
+      Duration::from_secs(settings.sweep_interval),
+      Duration::from_secs(settings.ttl),
+    );
+    thread::spawn(move || loop {
+      thread::sleep(interval);
+      match sweeper.sweep(ttl) {
+        Ok(0) => (),
+        Ok(removed) => info!("removed {} expired blobs", removed),
+        Err(e) => error!("unable to sweep blob store: {}", e),
+      }
+    });
+    Some(ClaimCheck {
+      store,
+      threshold: settings.threshold,
+    })
+  }
+
+  /// Store `data` if it is too large to send inline, returning the reference to send
+  /// instead.
+  pub fn check_out(&self, data: &[u8]) -> Result<Option<BlobRef>, String> {
+    if data.len() <= self.threshold {
+      return Ok(None);
+    }
+    let blob = BlobRef {
+      id: helpers::new_uuid(),
+      sha256: sha256(data),
+      size: data.len() as u64,
+    };
+    self.store.put(&blob.id, data)?;
+    debug!("stored {} byte payload as blob {}", blob.size, blob.id);
+    Ok(Some(blob))
+  }
+
+  /// The payload `blob` refers to, checked against its size and hash.
+  pub fn check_in(&self, blob: &BlobRef) -> Result<Vec<u8>, String> {
+    let data = match self.store.get(&blob.id)? {
+      Some(data) => data,
+      None => return Err(format!("blob {} does not exist or has expired", blob.id)),
+    };
+    if data.len() as u64 != blob.size || sha256(&data) != blob.sha256 {
+      return Err(format!("blob {} does not match its reference", blob.id));
+    }
+    Ok(data)
+  }
+}
+
+fn sha256(data: &[u8]) -> String {
+  helpers::to_hex(&openssl::sha::sha256(data))
+}
+
+/// One file per blob in a directory. The modification time of a file is when its blob
+/// was stored. Blob ids are uuids, anything else could name a path outside the directory.
+pub struct FilesystemStore {
+  dir: PathBuf,
+}
+
+impl FilesystemStore {
+  pub fn open(path: &str) -> Result<FilesystemStore, String> {
+    fs::create_dir_all(path).map_err(|e| e.to_string())?;
+    Ok(FilesystemStore {
+      dir: PathBuf::from(path),
+    })
+  }
+
+  fn path(&self, id: &str) -> Result<PathBuf, String> {
+    match Uuid::parse_str(id) {
+      Ok(uuid) if uuid.to_string() == id => Ok(self.dir.join(id)),
+      _ => Err(format!("invalid blob id {:?}", id)),
+    }
+  }
+
+  fn partial_path(&self, id: &str) -> Result<PathBuf, String> {
+    self.path(id)?;
+    Ok(self.dir.join(format!("{}.partial", id)))
+  }
+}
+
+impl BlobStore for FilesystemStore {
+  fn put(&self, id: &str, data: &[u8]) -> Result<(), String> {
+    // written aside and renamed, so readers never see part of a blob
+    let partial = self.partial_path(id)?;
+    fs::write(&partial, data).map_err(|e| e.to_string())?;
+    fs::rename(&partial, self.path(id)?).map_err(|e| e.to_string())
+  }
+
+  fn get(&self, id: &str) -> Result<Option<Vec<u8>>, String> {
+    match fs::read(self.path(id)?) {
+      Ok(data) => Ok(Some(data)),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+      Err(e) => Err(e.to_string()),
+    }
+  }
+
+  fn create(&self, id: &str) -> Result<Box<dyn BlobWriter>, String> {
+    let partial = self.partial_path(id)?;
+    let file = fs::File::create(&partial).map_err(|e| e.to_string())?;
+    Ok(Box::new(FileWriter {
+      file,
+      partial,
+      path: self.path(id)?,
+      finished: false,
+    }))
+  }
+
+  fn read_range(&self, id: &str, offset: u64, len: u64) -> Result<Option<Vec<u8>>, String> {
+    let mut file = match fs::File::open(self.path(id)?) {
+      Ok(file) => file,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+      Err(e) => return Err(e.to_string()),
+    };
+    file.seek(SeekFrom::Start(offset)).map_err(|e| e.to_string())?;
+    let mut data = Vec::new();
+    file.take(len).read_to_end(&mut data).map_err(|e| e.to_string())?;
+    Ok(Some(data))
+  }
+
+  fn delete(&self, id: &str) -> Result<(), String> {
+    match fs::remove_file(self.path(id)?) {
+      Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.to_string()),
+      _ => Ok(()),
+    }
+  }
+
+  fn sweep(&self, ttl: Duration) -> Result<usize, String> {
+    let mut removed = 0;
+    for entry in fs::read_dir(&self.dir).map_err(|e| e.to_string())? {
+      let entry = entry.map_err(|e| e.to_string())?;
+      let expired = entry
+        .metadata()
+        .and_then(|m| m.modified())
+        .map(|modified| modified.elapsed().map_or(false, |age| age > ttl))
+        .unwrap_or(false);
+      if expired && fs::remove_file(entry.path()).is_ok() {
+        removed += 1;
+      }
+    }
+    Ok(removed)
+  }
+}
+
+struct FileWriter {
+  file: fs::File,
+  partial: PathBuf,
+  path: PathBuf,
+  finished: bool,
+}
+
+impl BlobWriter for FileWriter {
+  fn write(&mut self, chunk: &[u8]) -> Result<(), String> {
+    self.file.write_all(chunk).map_err(|e| e.to_string())
+  }
+
+  fn finish(mut self: Box<Self>) -> Result<(), String> {
+    self.file.sync_all().map_err(|e| e.to_string())?;
+    fs::rename(&self.partial, &self.path).map_err(|e| e.to_string())?;
+    self.finished = true;
+    Ok(())
+  }
+}
+
+impl Drop for FileWriter {
+  fn drop(&mut self) {
+    if !self.finished {
+      let _ = fs::remove_file(&self.partial);
+    }
+  }
+}
+
+type Job = Box<dyn FnOnce(&mut postgres::Client) + Send>;
+
+/// Blobs as postgres large objects, listed in a table of their own. The blocking client
+/// runs on a thread of its own, so the store can be used from any runtime.
+#[derive(Clone)]
+pub struct PostgresStore {
+  jobs: Arc<Mutex<mpsc::Sender<Job>>>,
+  table: String,
+}
+
+impl PostgresStore {
+  pub fn connect(database: &str, table: &str) -> Result<PostgresStore, String> {
+    let (jobs, queue) = mpsc::channel::<Job>();
+    let (ready, connected) = mpsc::channel();
+    let database = database.to_string();
+    let migration = format!(
+      "CREATE TABLE IF NOT EXISTS {table} (
+        id TEXT PRIMARY KEY,
+        oid OID NOT NULL,
+        created_at BIGINT NOT NULL
+      );
+      CREATE INDEX IF NOT EXISTS {table}_created_at_idx ON {table} (created_at);",
+      table = table
+    );
+    thread::spawn(move || {
+      let mut client = match postgres::Client::connect(&database, postgres::NoTls) {
+        Ok(client) => client,
+        Err(e) => return ready.send(Err(e.to_string())).unwrap_or(()),
+      };
+      let migrated = client.batch_execute(&migration).map_err(|e| e.to_string());
+      let failed = migrated.is_err();
+      let _ = ready.send(migrated);
+      if failed {
+        return;
+      }
+      for job in queue {
+        job(&mut client);
+      }
+    });
+    match connected.recv() {
+      Ok(Ok(())) => Ok(PostgresStore {
+        jobs: Arc::new(Mutex::new(jobs)),
+        table: table.to_string(),
+      }),
+      Ok(Err(e)) => Err(e),
+      Err(_) => Err(String::from("blob store thread stopped")),
+    }
+  }
+
+  fn run<T, F>(&self, f: F) -> Result<T, String>
+  where
+    T: Send + 'static,
+    F: FnOnce(&mut postgres::Client) -> Result<T, postgres::Error> + Send + 'static,
+  {
+    let (done, result) = mpsc::channel();
+    self.submit(Box::new(move |client| {
+      let _ = done.send(f(client).map_err(|e| e.to_string()));
+    }))?;
+    result.recv().map_err(|_| String::from("blob store thread stopped"))?
+  }
+
+  /// Queue `job` without waiting for it, for callers that must not block.
+  fn submit(&self, job: Job) -> Result<(), String> {
+    self
+      .jobs
+      .lock()
+      .unwrap()
+      .send(job)
+      .map_err(|_| String::from("blob store thread stopped"))
+  }
+}
+
+impl BlobStore for PostgresStore {
+  fn put(&self, id: &str, data: &[u8]) -> Result<(), String> {
+    let (id, data) = (id.to_string(), data.to_vec());
+    let now = helpers::get_time() as i64;
+    let sql = format!(
+      "INSERT INTO {} (id, oid, created_at) VALUES ($1, lo_from_bytea(0, $2), $3)",
+      self.table
+    );
+    self.run(move |client| client.execute(sql.as_str(), &[&id, &data, &now]).map(|_| ()))
+  }
+
+  fn get(&self, id: &str) -> Result<Option<Vec<u8>>, String> {
+    let id = id.to_string();
+    let sql = format!("SELECT lo_get(oid) FROM {} WHERE id = $1", self.table);
+    self.run(move |client| {
+      let row = client.query_opt(sql.as_str(), &[&id])?;
+      Ok(row.map(|row| row.get(0)))
+    })
+  }
+
+  fn create(&self, id: &str) -> Result<Box<dyn BlobWriter>, String> {
+    let oid: u32 = self.run(|client| client.query_one("SELECT lo_create(0)", &[]).map(|row| row.get(0)))?;
+    Ok(Box::new(PostgresWriter {
+      store: self.clone(),
+      id: id.to_string(),
+      oid,
+      written: 0,
+      finished: false,
+    }))
+  }
+
+  fn read_range(&self, id: &str, offset: u64, len: u64) -> Result<Option<Vec<u8>>, String> {
+    let id = id.to_string();
+    let (offset, len) = (offset as i64, len.min(i32::MAX as u64) as i32);
+    let sql = format!("SELECT lo_get(oid, $2, $3) FROM {} WHERE id = $1", self.table);
+    self.run(move |client| {
+      let row = client.query_opt(sql.as_str(), &[&id, &offset, &len])?;
+      Ok(row.map(|row| row.get(0)))
+    })
+  }
+
+  fn delete(&self, id: &str) -> Result<(), String> {
+    let id = id.to_string();
+    let sql = format!(
+      "WITH gone AS (DELETE FROM {} WHERE id = $1 RETURNING oid) SELECT lo_unlink(oid) FROM gone",
+      self.table
+    );
+    self.run(move |client| client.query(sql.as_str(), &[&id]).map(|_| ()))
+  }
+
+  fn sweep(&self, ttl: Duration) -> Result<usize, String> {
+    let before = SystemTime::now()
+      .checked_sub(ttl)
+      .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+      .map_or(0, |d| d.as_secs() as i64);
+    let sql = format!(
+      "WITH expired AS (DELETE FROM {} WHERE created_at < $1 RETURNING oid)
+      SELECT lo_unlink(oid) FROM expired",
+      self.table
+    );
+    self.run(move |client| {
+      let rows = client.query(sql.as_str(), &[&before])?;
+      Ok(rows.len())
+    })
+  }
+}
+
+/// Writes a large object that is only listed once finished.
+struct PostgresWriter {
+  store: PostgresStore,
+  id: String,
+  oid: u32,
+  written: i64,
+  finished: bool,
+}
+
+impl BlobWriter for PostgresWriter {
+  fn write(&mut self, chunk: &[u8]) -> Result<(), String> {
+    let (oid, offset, data) = (self.oid, self.written, chunk.to_vec());
+    self
+      .store
+      .run(move |client| client.execute("SELECT lo_put($1, $2, $3)", &[&oid, &offset, &data]))?;
+    self.written += chunk.len() as i64;
+    Ok(())
+  }
+
+  fn finish(mut self: Box<Self>) -> Result<(), String> {
+    let (id, oid) = (self.id.clone(), self.oid);
+    let now = helpers::get_time() as i64;
+    let sql = format!(
+      "INSERT INTO {} (id, oid, created_at) VALUES ($1, $2, $3)",
+      self.store.table
+    );
+    self
+      .store
+      .run(move |client| client.execute(sql.as_str(), &[&id, &oid, &now]).map(|_| ()))?;
+    self.finished = true;
+    Ok(())
+  }
+}
+
+impl Drop for PostgresWriter {
+  // queued rather than run, writers are dropped on async workers too
+  fn drop(&mut self) {
+    if self.finished {
+      return;
+    }
+    let (id, oid) = (self.id.clone(), self.oid);
+    let unlinked = self.store.submit(Box::new(move |client| {
+      if let Err(e) = client.execute("SELECT lo_unlink($1)", &[&oid]) {
+        error!("unable to remove unfinished blob {}: {}", id, e);
+      }
+    }));
+    if let Err(e) = unlinked {
+      error!("unable to remove unfinished blob {}: {}", self.id, e);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn store() -> (FilesystemStore, PathBuf) {
+    let dir = std::env::temp_dir().join(format!("blobs-{}", helpers::new_uuid()));
+    (FilesystemStore::open(dir.to_str().unwrap()).unwrap(), dir)
+  }
+
+  #[test]
+  fn rejects_ids_that_are_not_uuids() {
+    let (store, dir) = store();
+    for id in &["../escape", "/etc/passwd", "", "a/b", "not-a-uuid"] {
+      assert!(store.put(id, b"data").is_err(), "{} accepted", id);
+      assert!(store.get(id).is_err(), "{} accepted", id);
+      assert!(store.create(id).is_err(), "{} accepted", id);
+      assert!(store.read_range(id, 0, 1).is_err(), "{} accepted", id);
+      assert!(store.delete(id).is_err(), "{} accepted", id);
+    }
+    let _ = fs::remove_dir_all(dir);
+  }
+
+  #[test]
+  fn stores_and_reads_back_blobs() {
+    let (store, dir) = store();
+    let id = helpers::new_uuid();
+    store.put(&id, b"hello world").unwrap();
+    assert_eq!(store.get(&id).unwrap(), Some(b"hello world".to_vec()));
+    assert_eq!(store.read_range(&id, 6, 100).unwrap(), Some(b"world".to_vec()));
+    store.delete(&id).unwrap();
+    assert_eq!(store.get(&id).unwrap(), None);
+    let _ = fs::remove_dir_all(dir);
+  }
+
+  #[test]
+  fn dropped_writers_leave_nothing_behind() {
+    let (store, dir) = store();
+    let id = helpers::new_uuid();
+    let mut writer = store.create(&id).unwrap();
+    writer.write(b"part of it").unwrap();
+    drop(writer);
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+    let mut writer = store.create(&id).unwrap();
+    writer.write(b"all of it").unwrap();
+    writer.finish().unwrap();
+    assert_eq!(store.get(&id).unwrap(), Some(b"all of it".to_vec()));
+    let _ = fs::remove_dir_all(dir);
+  }
+}
@@ -0,0 +1,77 @@
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+use std::{fmt, sync::Arc};
+
+use crate::utils::claim_check::{self, BlobStore, StoreKind};
+
+/// Files uploaded through the web gateway are kept in `store` until deleted, apart from
+/// the claim-check blobs, which expire. Uploads are limited to `max_size` bytes and, when
+/// `allowed_types` is not empty, to the listed content types, `image/*` style wildcards
+/// included.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Settings {
+  #[serde(default)]
+  pub store: Option<StoreKind>,
+  #[serde(default = "default_path")]
+  pub path: String,
+  #[serde(default = "default_max_size")]
+  pub max_size: u64,
+  #[serde(default)]
+  pub allowed_types: Vec<String>,
+}
+
+impl Default for Settings {
+  fn default() -> Self {
+    Settings {
+      store: None,
+      path: default_path(),
+      max_size: default_max_size(),
+      allowed_types: Vec::new(),
+    }
+  }
+}
+
+fn default_path() -> String {
+  String::from("var/files")
+}
+
+fn default_max_size() -> u64 {
+  100 * 1024 * 1024
+}
+
+#[derive(Clone)]
+pub struct FileStore {
+  pub store: Arc<dyn BlobStore>,
+  pub settings: Settings,
+}
+
+impl fmt::Debug for FileStore {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "FileStore {{ settings: {:?} }}", self.settings)
+  }
+}
+
+impl FileStore {
+  /// Open the configured store, `None` when no store is configured. `database` is the
+  /// connection string of the postgres store.
+  pub fn open(settings: &Settings, database: &str) -> Option<FileStore> {
+    let store = match claim_check::open_store(settings.store?, &settings.path, database, "file_blobs") {
+      Ok(store) => store,
+      Err(e) => panic!("unable to open file store. Error: {}", e),
+    };
+    Some(FileStore {
+      store,
+      settings: settings.clone(),
+    })
+  }
+
+  pub fn allows(&self, content_type: &str) -> bool {
+    let allowed = &self.settings.allowed_types;
+    allowed.is_empty()
+      || allowed.iter().any(|t| match t.strip_suffix("/*") {
+        Some(kind) => content_type.split('/').next() == Some(kind),
+        None => t.eq_ignore_ascii_case(content_type),
+      })
+  }
+}
@@ -15,3 +15,39 @@ pub fn get_time_in_msec() -> u128 {
 pub fn new_uuid() -> String {
   Uuid::new_v4().to_string()
 }
+
+#[allow(dead_code)]
+pub fn to_hex(data: &[u8]) -> String {
+  data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[allow(dead_code)]
+pub fn from_hex(data: &str) -> Option<Vec<u8>> {
+  if data.len() % 2 != 0 {
+    return None;
+  }
+  (0..data.len())
+    .step_by(2)
+    .map(|i| u8::from_str_radix(data.get(i..i + 2)?, 16).ok())
+    .collect()
+}
+
+/// Check a topic against an AMQP style pattern, where `*` matches one word and `#` zero or
+/// more words.
+#[allow(dead_code)]
+pub fn matches_topic(pattern: &str, topic: &str) -> bool {
+  let pattern: Vec<&str> = pattern.split('.').collect();
+  let topic: Vec<&str> = topic.split('.').collect();
+  matches_words(&pattern, &topic)
+}
+
+fn matches_words(pattern: &[&str], topic: &[&str]) -> bool {
+  match pattern.split_first() {
+    None => topic.is_empty(),
+    Some((&"#", rest)) => (0..=topic.len()).any(|i| matches_words(rest, &topic[i..])),
+    Some((p, rest)) => match topic.split_first() {
+      Some((t, topic)) if *p == "*" || p == t => matches_words(rest, topic),
+      _ => false,
+    },
+  }
+}
@@ -1,2 +1,11 @@
+pub mod circuit_breaker;
+pub mod claim_check;
+pub mod file_store;
 pub mod helpers;
+pub mod policy;
 pub mod rabbitmq;
+pub mod reply_store;
+pub mod retry;
+pub mod runtime;
+pub mod service_client;
+pub mod topology;
@@ -0,0 +1,167 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::shared_models::audit::AuditEvent;
+use crate::shared_models::principal::Principal;
+use crate::shared_models::tenant::Tenant;
+use crate::utils::helpers;
+
+/// Role based access policy shared by the web gateway (http routes) and the backend
+/// (`model.method` pairs). A request is allowed when any role of the caller grants it.
+/// With `enabled = false` every request is allowed.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct Policy {
+  #[serde(default)]
+  pub enabled: bool,
+  #[serde(default)]
+  pub roles: Vec<Role>,
+}
+
+/// `methods` are `model.method` patterns and `routes` are `METHOD /path` patterns, where `*`
+/// matches a whole segment or, at the end of a path, anything below it. With `own_tenant`
+/// the role only applies to requests on the caller's own tenant.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Role {
+  pub name: String,
+  #[serde(default)]
+  pub methods: Vec<String>,
+  #[serde(default)]
+  pub routes: Vec<String>,
+  #[serde(default)]
+  pub own_tenant: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+  NoRoles,
+  NotPermitted,
+  TenantMismatch,
+}
+
+impl Reason {
+  pub fn code(&self) -> &'static str {
+    match self {
+      Reason::NoRoles => "no_roles",
+      Reason::NotPermitted => "not_permitted",
+      Reason::TenantMismatch => "tenant_mismatch",
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct Denial {
+  pub reason: Reason,
+  pub target: String,
+}
+
+impl Denial {
+  pub fn audit(&self, service: &str, principal: &Principal, tenant: &Tenant) -> AuditEvent {
+    AuditEvent {
+      service: service.to_string(),
+      user_id: principal.user_id.clone(),
+      tenant: tenant.to_string(),
+      target: self.target.clone(),
+      reason: self.reason.code().to_string(),
+      time: helpers::get_time(),
+    }
+  }
+}
+
+impl fmt::Display for Denial {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}: access to {} denied", self.reason.code(), self.target)
+  }
+}
+
+impl Policy {
+  pub fn authorize_method(
+    &self,
+    principal: &Principal,
+    tenant: &Tenant,
+    model: &str,
+    method: &str,
+  ) -> Result<(), Denial> {
+    let target = format!("{}.{}", model, method);
+    self.authorize(principal, tenant, &target, |role| {
+      role.methods.iter().any(|p| matches_segments(p, &target, '.'))
+    })
+  }
+
+  pub fn authorize_route(
+    &self,
+    principal: &Principal,
+    tenant: &Tenant,
+    http_method: &str,
+    path: &str,
+  ) -> Result<(), Denial> {
+    let target = format!("{} {}", http_method, path);
+    self.authorize(principal, tenant, &target, |role| {
+      role.routes.iter().any(|p| matches_route(p, http_method, path))
+    })
+  }
+
+  fn authorize<F: Fn(&Role) -> bool>(
+    &self,
+    principal: &Principal,
+    tenant: &Tenant,
+    target: &str,
+    grants: F,
+  ) -> Result<(), Denial> {
+    if !self.enabled {
+      return Ok(());
+    }
+    let roles: Vec<&Role> = self
+      .roles
+      .iter()
+      .filter(|r| principal.roles.contains(&r.name))
+      .collect();
+    if roles.is_empty() {
+      return Err(Denial {
+        reason: Reason::NoRoles,
+        target: target.to_string(),
+      });
+    }
+    let granting: Vec<&&Role> = roles.iter().filter(|r| grants(r)).collect();
+    if granting.is_empty() {
+      return Err(Denial {
+        reason: Reason::NotPermitted,
+        target: target.to_string(),
+      });
+    }
+    let own_tenant = principal.tenant.as_ref() == Some(tenant);
+    if granting.iter().any(|r| !r.own_tenant || own_tenant) {
+      Ok(())
+    } else {
+      Err(Denial {
+        reason: Reason::TenantMismatch,
+        target: target.to_string(),
+      })
+    }
+  }
+}
+
+fn matches_route(pattern: &str, http_method: &str, path: &str) -> bool {
+  let (method_pattern, path_pattern) = match pattern.split_once(' ') {
+    Some(parts) => parts,
+    None => ("*", pattern),
+  };
+  (method_pattern == "*" || method_pattern.eq_ignore_ascii_case(http_method))
+    && matches_segments(path_pattern.trim_start_matches('/'), path.trim_start_matches('/'), '/')
+}
+
+/// Segment wise match where `*` matches one segment and a trailing `*` matches the rest.
+fn matches_segments(pattern: &str, value: &str, separator: char) -> bool {
+  let pattern: Vec<&str> = pattern.split(separator).collect();
+  let value: Vec<&str> = value.split(separator).collect();
+  for (i, p) in pattern.iter().enumerate() {
+    if *p == "*" && i == pattern.len() - 1 {
+      return value.len() >= pattern.len() - 1;
+    }
+    match value.get(i) {
+      Some(v) if *p == "*" || p == v => continue,
+      _ => return false,
+    }
+  }
+  pattern.len() == value.len()
+}
@@ -1,21 +1,24 @@
 use lapin::{
   options::{
-    BasicAckOptions, BasicConsumeOptions, BasicPublishOptions, BasicQosOptions, ExchangeDeclareOptions,
-    QueueBindOptions, QueueDeclareOptions,
+    BasicAckOptions, BasicConsumeOptions, BasicPublishOptions, BasicQosOptions, ConfirmSelectOptions, QueueBindOptions,
   },
-  types::FieldTable,
-  types::ShortString,
-  BasicProperties, Channel, Connection, ConnectionProperties, Consumer, ExchangeKind,
+  publisher_confirm::{Confirmation, PublisherConfirm},
+  types::{AMQPValue, FieldTable, LongString, ShortString},
+  BasicProperties, Channel, Connection, ConnectionProperties, Consumer,
 };
 use log::{debug, error, info};
-use std::{
-  collections::HashMap,
-  str,
-  sync::{Arc, RwLock},
-  time::SystemTime,
-};
+use std::{str, sync::Arc, time::Duration};
+use tokio::sync::Notify;
 use uuid::Uuid;
 
+use crate::shared_models::event::{self, Event};
+use crate::shared_models::lane::Lane;
+use crate::shared_models::{job, stream};
+use crate::utils::claim_check::{BlobRef, ClaimCheck};
+use crate::utils::reply_store::ReplyStore;
+use crate::utils::runtime::{self, Runtime};
+use crate::utils::topology::{self, Change, Topology};
+
 #[allow(dead_code)]
 const MSG_EXPIRATION: &str = "20000";
 #[allow(dead_code)]
@@ -24,6 +27,12 @@ const ERROR: &str = "[ERROR]";
 const WARNING: &str = "[WARNING]";
 #[allow(dead_code)]
 const TIMEOUT: &str = "[TIMEOUT]";
+/// Headers of a message whose payload went to the blob store, see `claim_check`.
+const CLAIM_ID_HEADER: &str = "claim_check";
+const CLAIM_SHA256_HEADER: &str = "claim_check_sha256";
+const CLAIM_SIZE_HEADER: &str = "claim_check_size";
+/// Exchange published to until `use_exchange` picks the configured one.
+const DEFAULT_EXCHANGE: &str = "service";
 
 pub struct MqLogin {
   pub user: String,
@@ -36,12 +45,23 @@ pub struct MqLogin {
 pub struct MqChannel {
   pub channel: Channel,
   pub queue_name: String,
+  /// Exchange every request, reply and event is published to.
+  pub exchange: String,
+  /// Lane of the requests published on this channel.
+  pub lane: Lane,
+  connection: Arc<Connection>,
   user: String,
-  messages: Arc<RwLock<HashMap<String, Vec<u8>>>>,
+  /// Replies to our own requests, by correlation id.
+  replies: ReplyStore,
+  /// Blob store for payloads too large to send inline.
+  claims: Option<ClaimCheck>,
+  runtime: Arc<dyn Runtime>,
 }
 
 impl MqChannel {
-  pub fn connect(login: MqLogin, consumer: Arc<RwLock<HashMap<String, Vec<u8>>>>) -> MqChannel {
+  /// Connect to the broker. Waits and blocking work go through `runtime`, the one the
+  /// channel is used on.
+  pub fn connect(login: MqLogin, replies: ReplyStore, runtime: Arc<dyn Runtime>) -> MqChannel {
     let conn = match Connection::connect(
       &format!(
         "amqp://{}:{}@{}:{}/%2f",
@@ -61,41 +81,167 @@ impl MqChannel {
     MqChannel {
       channel,
       queue_name: "".to_string(),
+      exchange: DEFAULT_EXCHANGE.to_string(),
+      lane: Lane::default(),
+      connection: Arc::new(conn),
       user: login.user,
-      messages: consumer,
+      replies,
+      claims: None,
+      runtime,
     }
   }
+  /// Declare `exchange_name` as a durable, auto-delete topic exchange and publish to it.
+  #[allow(dead_code)]
   pub fn register_exchange(&mut self, exchange_name: &str) {
+    self.declare_exchange(&topology::Exchange::named(exchange_name));
+    self.use_exchange(exchange_name);
+  }
+  /// Declare `queue_name` without arguments and consume it.
+  #[allow(dead_code)]
+  pub fn register_queue(&mut self, queue_name: &str) {
+    self.declare_queue(&topology::Queue::named(queue_name));
+    self.use_queue(queue_name);
+  }
+  pub fn use_exchange(&mut self, exchange_name: &str) {
+    self.exchange = exchange_name.to_string()
+  }
+  pub fn use_queue(&mut self, queue_name: &str) {
+    self.queue_name = queue_name.to_string()
+  }
+  pub fn use_lane(&mut self, lane: Lane) {
+    self.lane = lane
+  }
+  pub fn runtime(&self) -> &dyn Runtime {
+    self.runtime.as_ref()
+  }
+  /// Send payloads over the threshold of `claims` through its blob store. Receivers need
+  /// the same store to read them.
+  pub fn use_claim_check(&mut self, claims: Option<ClaimCheck>) {
+    self.claims = claims
+  }
+  /// A copy on a new channel of the same connection, for a consumer with a prefetch of its
+  /// own. Clones share their channel and with it its prefetch.
+  pub fn fork(&self) -> MqChannel {
+    let channel = match self.connection.create_channel().wait() {
+      Ok(channel) => channel,
+      Err(e) => panic!("{} unable to open rabbitmq channel. Error: {}", ERROR, e),
+    };
+    MqChannel {
+      channel,
+      ..self.clone()
+    }
+  }
+  pub fn declare_exchange(&mut self, exchange: &topology::Exchange) {
     let _ = match self
       .channel
       .exchange_declare(
-        exchange_name,
-        ExchangeKind::Topic,
-        ExchangeDeclareOptions {
-          passive: false,
-          durable: true,
-          auto_delete: true,
-          internal: false,
-          nowait: false,
-        },
-        FieldTable::default(),
+        &exchange.name,
+        exchange.kind(),
+        exchange.options(false),
+        exchange.arguments(),
       )
       .wait()
     {
-      Ok(_) => info!("successfully declared exchange {}", &exchange_name),
-      Err(e) => panic!("{} unable to declare rabbitmq exchange: {}", ERROR, e),
+      Ok(_) => info!("successfully declared {}", exchange),
+      Err(e) => panic!("{} unable to declare rabbitmq exchange {}: {}", ERROR, exchange.name, e),
     };
   }
-  pub fn register_queue(&mut self, queue_name: &str) {
+  pub fn declare_queue(&mut self, queue: &topology::Queue) {
     let _ = match self
       .channel
-      .queue_declare(queue_name, QueueDeclareOptions::default(), FieldTable::default())
+      .queue_declare(&queue.name, queue.options(false), queue.arguments())
       .wait()
     {
-      Ok(_) => info!("successfully declared queue {}", queue_name),
-      Err(e) => panic!("{} unable to declare rabbitmq queue. Error: {}", ERROR, e),
+      Ok(_) => info!("successfully declared {}", queue),
+      Err(e) => panic!(
+        "{} unable to declare rabbitmq queue {}. Error: {}",
+        ERROR, queue.name, e
+      ),
     };
-    self.queue_name = queue_name.to_string()
+  }
+  /// Declare every exchange and queue of `topology`, then its bindings.
+  pub fn declare_topology(&mut self, topology: &Topology) {
+    for exchange in &topology.exchanges {
+      self.declare_exchange(exchange);
+    }
+    for queue in &topology.queues {
+      self.declare_queue(queue);
+    }
+    for binding in &topology.bindings {
+      self.bind_queue(&binding.queue, &binding.exchange, &binding.routing_key);
+    }
+  }
+  /// Compare `topology` with the broker without changing anything. Each check runs on a
+  /// channel of its own, since the broker closes the channel of a failed declare.
+  pub fn diff_topology(&self, topology: &Topology) -> Vec<Change> {
+    let mut changes = Vec::new();
+    for exchange in &topology.exchanges {
+      let declare = |channel: &Channel, passive: bool| {
+        channel
+          .exchange_declare(
+            &exchange.name,
+            exchange.kind(),
+            exchange.options(passive),
+            exchange.arguments(),
+          )
+          .wait()
+          .map(|_| ())
+      };
+      changes.push(self.diff(exchange.to_string(), declare));
+    }
+    for queue in &topology.queues {
+      let declare = |channel: &Channel, passive: bool| {
+        channel
+          .queue_declare(&queue.name, queue.options(passive), queue.arguments())
+          .wait()
+          .map(|_| ())
+      };
+      changes.push(self.diff(queue.to_string(), declare));
+    }
+    for binding in &topology.bindings {
+      changes.push(Change::Bind(binding.to_string()));
+    }
+    // a new one is declared by every instance on start
+    changes.push(Change::Create(topology.reply_queue().to_string()));
+    changes
+  }
+  /// A passive declare tells whether the entity exists. Declaring an existing entity again
+  /// with the same settings changes nothing, and fails when the settings differ.
+  fn diff<F: Fn(&Channel, bool) -> Result<(), lapin::Error>>(&self, what: String, declare: F) -> Change {
+    if let Err(e) = self.with_scratch_channel(|channel| declare(channel, true)) {
+      debug!("passive declare failed: {}", e);
+      return Change::Create(what);
+    }
+    match self.with_scratch_channel(|channel| declare(channel, false)) {
+      Ok(()) => Change::Unchanged(what),
+      Err(e) => Change::Conflict(what, e.to_string()),
+    }
+  }
+  fn with_scratch_channel<F: Fn(&Channel) -> Result<(), lapin::Error>>(&self, f: F) -> Result<(), lapin::Error> {
+    let channel = self.connection.create_channel().wait()?;
+    let result = f(&channel);
+    if result.is_ok() {
+      let _ = channel.close(200, "OK").wait();
+    }
+    result
+  }
+  /// Declare the reply queue of this instance, an exclusive queue named by the broker with
+  /// the settings of `queue`, see `Topology::reply_queue`, bound to the exchange by its own
+  /// name. The queue goes away with the connection, so replies never end up with another
+  /// instance.
+  pub fn register_reply_queue(&mut self, queue: &topology::Queue, exchange_name: &str) -> String {
+    let queue_name = match self
+      .channel
+      .queue_declare("", queue.options(false), queue.arguments())
+      .wait()
+    {
+      Ok(queue) => queue.name().as_str().to_string(),
+      Err(e) => panic!("{} unable to declare rabbitmq reply queue. Error: {}", ERROR, e),
+    };
+    info!("successfully declared reply queue {}", queue_name);
+    self.bind_queue(&queue_name, exchange_name, &queue_name);
+    self.queue_name = queue_name.clone();
+    queue_name
   }
   pub fn bind_queue(&mut self, queue_name: &str, exchange_name: &str, routing_key: &str) {
     match &self
@@ -122,13 +268,17 @@ impl MqChannel {
       Err(e) => panic!("{} unable to register queue consumers. Error: {}", ERROR, e),
     };
   }
+  /// Consume the reply queue. Messages carrying a correlation id are replies to our own
+  /// requests; anything else is an event and is handed to `on_event` with its routing key.
+  /// Streamed replies are handed to `on_event` as `<kind>.<correlation id>`, where kind is
+  /// `stream` or `job`.
   #[allow(dead_code)]
-  pub async fn start_consuming(&mut self) {
+  pub async fn start_consuming<F: Fn(&str, &[u8])>(&mut self, on_event: F) {
     let consumer = match self
       .channel
       .basic_consume(
         &self.queue_name,
-        "my_consumer",
+        &format!("{}_consumer", self.queue_name),
         BasicConsumeOptions {
           no_local: false,
           no_ack: false,
@@ -145,15 +295,38 @@ impl MqChannel {
 
     while let Some(msg) = consumer.clone().into_iter().next() {
       let (_, msg) = msg.expect("error in consumer");
-      let cid = msg.properties.correlation_id().clone().unwrap();
-      debug!("received message {}", cid.as_str());
-      self.insert_msg_to_local_queue(cid.as_str(), msg.data.clone());
+      let data = match self.check_in(&msg.properties, &msg.data).await {
+        Ok(data) => data,
+        Err(e) => {
+          error!("{} dropping message {}: {}", ERROR, msg.routing_key.as_str(), e);
+          let _ = msg.ack(BasicAckOptions::default()).await;
+          continue;
+        }
+      };
+      match (msg.properties.correlation_id(), streamed_kind(&msg.properties)) {
+        (Some(cid), Some(kind)) => {
+          debug!("received {} message {}", kind, cid.as_str());
+          on_event(&format!("{}.{}", kind, cid.as_str()), &data);
+        }
+        (Some(cid), None) => {
+          debug!("received message {}", cid.as_str());
+          self.replies.insert(cid.as_str(), data);
+        }
+        (None, _) => {
+          debug!("received event {}", msg.routing_key.as_str());
+          on_event(msg.routing_key.as_str(), &data);
+        }
+      };
       let _ = msg.ack(BasicAckOptions::default()).await;
     }
   }
   #[allow(dead_code)]
   pub fn consume(&mut self) -> Consumer {
-    let _ = match &self.channel.basic_qos(10, BasicQosOptions::default()).wait() {
+    self.consume_with_prefetch(10)
+  }
+  #[allow(dead_code)]
+  pub fn consume_with_prefetch(&mut self, prefetch: u16) -> Consumer {
+    let _ = match &self.channel.basic_qos(prefetch, BasicQosOptions::default()).wait() {
       Ok(_) => info!("registered QOS"),
       Err(e) => panic!("{} unable to register queue consumers. Error: {}", ERROR, e),
     };
@@ -161,7 +334,7 @@ impl MqChannel {
       .channel
       .basic_consume(
         &self.queue_name,
-        "my_consumer",
+        &format!("{}_consumer", self.queue_name),
         BasicConsumeOptions {
           no_local: false,
           no_ack: false,
@@ -177,95 +350,447 @@ impl MqChannel {
     };
     consumer
   }
+  /// Bind the event queue `queue_name`, declared with the topology, to every key in
+  /// `keys` and start consuming it. Events published while the service is down wait in the
+  /// queue.
+  #[allow(dead_code)]
+  pub fn subscribe(&mut self, queue_name: &str, exchange_name: &str, keys: &[String]) -> Consumer {
+    for key in keys {
+      self.bind_queue(queue_name, exchange_name, key);
+    }
+    match self
+      .channel
+      .basic_consume(
+        queue_name,
+        &format!("{}_consumer", queue_name),
+        BasicConsumeOptions::default(),
+        FieldTable::default(),
+      )
+      .wait()
+    {
+      Ok(consumer) => consumer,
+      Err(e) => panic!("{} unable to register queue consumers. Error: {}", ERROR, e),
+    }
+  }
   #[allow(dead_code)]
   pub async fn request_reply_with_timeout(mut self, key: &str, data: Vec<u8>, timeout: u64) -> Result<Vec<u8>, String> {
-    let now = SystemTime::now();
-    let id = Uuid::new_v4();
-    let correlation_id = ShortString::from(id.to_string());
+    let id = Uuid::new_v4().to_string();
     debug!("ID: {}", id);
-    let _ = self
-      .channel
-      .basic_publish(
-        "service",
+    self
+      .request_reply_attempt(
         key,
-        BasicPublishOptions::default(),
-        data.to_vec(),
+        data,
+        &Uuid::new_v4().to_string(),
+        &id,
+        Duration::from_secs(timeout),
+      )
+      .await
+  }
+  /// One attempt of a request. Retries of the same request pass the same `message_id`, so
+  /// the receiver can recognise them, and the same `correlation_id`, so a late reply to an
+  /// earlier attempt still counts while a later one waits.
+  #[allow(dead_code)]
+  pub async fn request_reply_attempt(
+    &mut self,
+    key: &str,
+    data: Vec<u8>,
+    message_id: &str,
+    correlation_id: &str,
+    timeout: Duration,
+  ) -> Result<Vec<u8>, String> {
+    let notify = Arc::new(Notify::new());
+    if !self.replies.register(correlation_id, timeout, &notify) {
+      return Err(format!("{} too many requests waiting for a reply", ERROR));
+    }
+    self
+      .publish_request_with_id(key, data, correlation_id, message_id)
+      .await;
+    // a reply that came in before waiting left a permit, so none is missed
+    let replies = &self.replies;
+    let reply = runtime::timeout(self.runtime.as_ref(), timeout, async {
+      loop {
+        if let Some(reply) = replies.take_first(correlation_id) {
+          return reply;
+        }
+        notify.notified().await;
+      }
+    })
+    .await;
+    self.replies.unregister(correlation_id);
+    reply.ok_or_else(|| {
+      format!(
+        "{} timeout while retrieving data from {} after {:?}",
+        ERROR, key, timeout,
+      )
+    })
+  }
+  /// Publish a request whose replies carry `correlation_id`. Replies are collected by the
+  /// consumer of this channel's queue for up to `ttl`, waking `notify` as they arrive, and
+  /// read with `take_replies`. Returns false when too many requests wait for replies already.
+  #[allow(dead_code)]
+  pub async fn publish_request(
+    &mut self,
+    key: &str,
+    data: Vec<u8>,
+    correlation_id: &str,
+    ttl: Duration,
+    notify: &Arc<Notify>,
+  ) -> bool {
+    if !self.replies.register(correlation_id, ttl, notify) {
+      return false;
+    }
+    self
+      .publish_request_with_id(key, data, correlation_id, &Uuid::new_v4().to_string())
+      .await;
+    true
+  }
+  async fn publish_request_with_id(&mut self, key: &str, data: Vec<u8>, correlation_id: &str, message_id: &str) {
+    let correlation_id = ShortString::from(correlation_id.to_string());
+    let (data, props) = self
+      .check_out(
+        data,
         BasicProperties::default()
           .with_user_id(ShortString::from(self.user.clone()))
+          .with_message_id(ShortString::from(message_id.to_string()))
           .with_correlation_id(correlation_id)
+          .with_priority(self.lane.priority())
           .with_expiration(ShortString::from(MSG_EXPIRATION.to_string()))
           .with_reply_to(ShortString::from(self.queue_name.clone())),
       )
+      .await;
+    let _ = self
+      .channel
+      .basic_publish(
+        &self.exchange,
+        &self.lane.key(key),
+        BasicPublishOptions::default(),
+        data,
+        props,
+      )
       .wait();
-
-    loop {
-      match now.elapsed() {
-        Ok(elapsed) => {
-          if elapsed.as_secs() > timeout {
-            break;
-          }
-        }
-        Err(e) => {
-          error!("{} retrieving elapsed time: {:?}", ERROR, e);
-          continue;
-        }
-      };
-      if !self.find_msg_in_local_queue(&id.to_string()) {
-        continue;
-      };
-
-      match self.get_msg_from_local_queue(&id.to_string()) {
-        Ok(v) => return Ok(v),
-        Err(_e) => return Err(format!("{} failed to retrieve key {}", ERROR, id)),
-      };
-    }
-    Err(format!(
-      "{} timeout while retrieving data from {} after {}s",
-      ERROR, key, timeout,
-    ))
   }
+  /// Remove and return every reply received so far for `correlation_id`.
   #[allow(dead_code)]
-  pub async fn reply(&mut self, prop: BasicProperties, data: Vec<u8>) {
+  pub fn take_replies(&mut self, correlation_id: &str) -> Vec<Vec<u8>> {
+    self.replies.take_all(correlation_id)
+  }
+  /// Stop collecting replies for `correlation_id`.
+  #[allow(dead_code)]
+  pub fn forget_replies(&mut self, correlation_id: &str) {
+    self.replies.unregister(correlation_id)
+  }
+  /// Publish a notice that expects no reply, such as an audit record or a job cancel. Its
+  /// routing key is kept as is, requests go through `publish_request_confirmed` or the
+  /// other request methods, which route them to the channel's lane.
+  #[allow(dead_code)]
+  pub async fn publish(&mut self, key: &str, data: Vec<u8>) {
+    let (data, props) = self
+      .check_out(
+        data,
+        BasicProperties::default()
+          .with_user_id(ShortString::from(self.user.clone()))
+          .with_message_id(ShortString::from(Uuid::new_v4().to_string()))
+          .with_expiration(ShortString::from(MSG_EXPIRATION.to_string())),
+      )
+      .await;
+    let _ = self
+      .channel
+      .basic_publish(&self.exchange, key, BasicPublishOptions::default(), data, props)
+      .wait();
+  }
+  /// Publish a domain event on `<subject>.<name>`. Events are persistent and do not
+  /// expire, since durable subscriber queues may be drained long after publishing.
+  #[allow(dead_code)]
+  pub async fn publish_event<E: Event>(&mut self, event: &E) {
+    let data = match serde_json::to_vec(event) {
+      Ok(data) => data,
+      Err(e) => {
+        error!("{} unable to serialize event {}: {}", ERROR, E::NAME, e);
+        return;
+      }
+    };
     let _ = self
       .channel
       .basic_publish(
-        "service",
-        prop.reply_to().clone().unwrap().as_str(),
+        &self.exchange,
+        &event.routing_key(),
         BasicPublishOptions::default(),
-        data.to_vec(),
+        data,
+        self.event_properties(E::NAME, E::VERSION, &Uuid::new_v4().to_string()),
+      )
+      .wait();
+  }
+  /// Put the channel in confirm mode, required by `publish_event_confirmed` and
+  /// `publish_request_confirmed`.
+  #[allow(dead_code)]
+  pub fn enable_confirms(&mut self) {
+    let _ = match self.channel.confirm_select(ConfirmSelectOptions::default()).wait() {
+      Ok(_) => info!("enabled publisher confirms"),
+      Err(e) => panic!("{} unable to enable publisher confirms. Error: {}", ERROR, e),
+    };
+  }
+  /// Publish an already serialized event and wait until the broker has taken
+  /// responsibility for it.
+  #[allow(dead_code)]
+  pub async fn publish_event_confirmed(
+    &mut self,
+    key: &str,
+    name: &str,
+    version: u16,
+    message_id: &str,
+    data: Vec<u8>,
+  ) -> Result<(), String> {
+    let confirm = self
+      .channel
+      .basic_publish(
+        &self.exchange,
+        key,
+        BasicPublishOptions::default(),
+        data,
+        self.event_properties(name, version, message_id),
+      )
+      .await
+      .map_err(|e| e.to_string())?;
+    confirmed(confirm, message_id).await
+  }
+  /// Publish a request nobody waits for, such as scheduled work, and wait until the broker
+  /// has taken responsibility for it. It is persistent and does not expire, and its replies
+  /// go to this channel's queue, where they are dropped. Requires confirm mode.
+  #[allow(dead_code)]
+  pub async fn publish_request_confirmed(&mut self, key: &str, data: Vec<u8>, message_id: &str) -> Result<(), String> {
+    let (data, props) = self
+      .check_out(
+        data,
         BasicProperties::default()
           .with_user_id(ShortString::from(self.user.clone()))
-          .with_expiration(ShortString::from(MSG_EXPIRATION.to_string()))
-          .with_correlation_id(prop.correlation_id().clone().unwrap()),
+          .with_message_id(ShortString::from(message_id.to_string()))
+          .with_correlation_id(ShortString::from(message_id.to_string()))
+          .with_priority(self.lane.priority())
+          .with_delivery_mode(2)
+          .with_reply_to(ShortString::from(self.queue_name.clone())),
       )
-      .wait();
+      .await;
+    let confirm = self
+      .channel
+      .basic_publish(
+        &self.exchange,
+        &self.lane.key(key),
+        BasicPublishOptions::default(),
+        data,
+        props,
+      )
+      .await
+      .map_err(|e| e.to_string())?;
+    confirmed(confirm, message_id).await
   }
+  fn event_properties(&self, name: &str, version: u16, message_id: &str) -> BasicProperties {
+    let mut headers = FieldTable::default();
+    headers.insert(
+      ShortString::from(event::SCHEMA_VERSION_HEADER.to_string()),
+      AMQPValue::ShortUInt(version),
+    );
+    headers.insert(
+      ShortString::from(event::EVENT_HEADER.to_string()),
+      AMQPValue::LongString(LongString::from(name.to_string())),
+    );
+    BasicProperties::default()
+      .with_user_id(ShortString::from(self.user.clone()))
+      .with_message_id(ShortString::from(message_id.to_string()))
+      .with_content_type(ShortString::from("application/json".to_string()))
+      .with_delivery_mode(2)
+      .with_headers(headers)
+  }
+  /// Start a streaming request of `kind` (`stream` or `job`). Its replies carry `id` as
+  /// correlation id. Jobs do not expire, their record tells the caller they are queued.
   #[allow(dead_code)]
-  fn insert_msg_to_local_queue(&mut self, id: &str, value: Vec<u8>) {
-    let data = Arc::clone(&self.messages);
-    let mut data = data.write().unwrap();
-    data.insert(id.to_string(), value);
-    drop(data);
+  pub async fn publish_stream_request(&mut self, key: &str, data: Vec<u8>, id: &str, kind: &str) {
+    let mut props = BasicProperties::default()
+      .with_user_id(ShortString::from(self.user.clone()))
+      .with_message_id(ShortString::from(id.to_string()))
+      .with_correlation_id(ShortString::from(id.to_string()))
+      .with_priority(self.lane.priority())
+      .with_kind(ShortString::from(kind.to_string()));
+    // job replies outlive the instance that asked, see `job::REPLY_KEY`
+    props = if kind == job::KIND {
+      props
+        .with_delivery_mode(2)
+        .with_reply_to(ShortString::from(job::REPLY_KEY))
+    } else {
+      props
+        .with_expiration(ShortString::from(MSG_EXPIRATION.to_string()))
+        .with_reply_to(ShortString::from(self.queue_name.clone()))
+    };
+    let (data, props) = self.check_out(data, props).await;
+    let _ = self
+      .channel
+      .basic_publish(
+        &self.exchange,
+        &self.lane.key(key),
+        BasicPublishOptions::default(),
+        data,
+        props,
+      )
+      .wait();
   }
+  /// Send one message of a streamed reply to the caller of a streaming request.
   #[allow(dead_code)]
-  fn find_msg_in_local_queue(&mut self, id: &str) -> bool {
-    let data = Arc::clone(&self.messages);
-    let data = data.read().unwrap();
-    data.clone().contains_key(id)
+  pub async fn reply_stream(&mut self, prop: &BasicProperties, data: Vec<u8>) {
+    let (reply_to, correlation_id, kind) = match (prop.reply_to(), prop.correlation_id(), prop.kind()) {
+      (Some(reply_to), Some(correlation_id), Some(kind)) => (reply_to, correlation_id, kind),
+      _ => {
+        error!("{} streamed request without reply_to, correlation id or kind", ERROR);
+        return;
+      }
+    };
+    let mut props = BasicProperties::default()
+      .with_user_id(ShortString::from(self.user.clone()))
+      .with_kind(kind.clone())
+      .with_correlation_id(correlation_id.clone());
+    if kind.as_str() == job::KIND {
+      props = props.with_delivery_mode(2);
+    }
+    let (data, props) = self.check_out(data, props).await;
+    let _ = self
+      .channel
+      .basic_publish(
+        &self.exchange,
+        reply_to.as_str(),
+        BasicPublishOptions::default(),
+        data,
+        props,
+      )
+      .wait();
   }
   #[allow(dead_code)]
-  fn get_msg_from_local_queue(&mut self, id: &str) -> Result<Vec<u8>, String> {
-    let data = Arc::clone(&self.messages);
-    let mut data = data.write().unwrap();
-    let result = match data.clone().get(id) {
-      Some(d) => d.to_vec(),
-      None => {
-        drop(data);
-        return Err(format!("{} failed to get entry by id {}", ERROR, id));
+  pub async fn reply(&mut self, prop: BasicProperties, data: Vec<u8>) {
+    // a reply without a correlation id would be taken for an event
+    let (reply_to, correlation_id) = match (prop.reply_to(), prop.correlation_id()) {
+      (Some(reply_to), Some(correlation_id)) => (reply_to, correlation_id),
+      _ => {
+        error!("{} not replying to a request without reply_to or correlation id", ERROR);
+        return;
+      }
+    };
+    let (data, props) = self
+      .check_out(
+        data,
+        BasicProperties::default()
+          .with_user_id(ShortString::from(self.user.clone()))
+          .with_expiration(ShortString::from(MSG_EXPIRATION.to_string()))
+          .with_correlation_id(correlation_id.clone()),
+      )
+      .await;
+    let _ = self
+      .channel
+      .basic_publish(
+        &self.exchange,
+        reply_to.as_str(),
+        BasicPublishOptions::default(),
+        data,
+        props,
+      )
+      .wait();
+  }
+  /// Put `data` in the blob store when it is too large to send inline, sending a
+  /// reference in the headers instead. Payloads the store does not take go inline.
+  async fn check_out(&self, data: Vec<u8>, props: BasicProperties) -> (Vec<u8>, BasicProperties) {
+    let claims = match &self.claims {
+      Some(claims) => claims.clone(),
+      None => return (data, props),
+    };
+    runtime::unblock(self.runtime.as_ref(), move || match claims.check_out(&data) {
+      Ok(Some(blob)) => {
+        // keep the headers the message already has
+        let mut headers = props.headers().clone().unwrap_or_default();
+        headers.insert(
+          ShortString::from(CLAIM_ID_HEADER.to_string()),
+          AMQPValue::LongString(LongString::from(blob.id)),
+        );
+        headers.insert(
+          ShortString::from(CLAIM_SHA256_HEADER.to_string()),
+          AMQPValue::LongString(LongString::from(blob.sha256)),
+        );
+        headers.insert(
+          ShortString::from(CLAIM_SIZE_HEADER.to_string()),
+          AMQPValue::LongLongInt(blob.size as i64),
+        );
+        (Vec::new(), props.with_headers(headers))
+      }
+      Ok(None) => (data, props),
+      Err(e) => {
+        error!("{} unable to store payload, sending it inline: {}", ERROR, e);
+        (data, props)
       }
+    })
+    .await
+  }
+  /// The payload of a message, read back from the blob store if it was put there.
+  pub async fn check_in(&self, props: &BasicProperties, data: &[u8]) -> Result<Vec<u8>, String> {
+    let blob = match claimed(props) {
+      Some(blob) => blob,
+      None => return Ok(data.to_vec()),
     };
-    data.remove(id);
-    drop(data);
-    Ok(result)
+    match &self.claims {
+      Some(claims) => {
+        let claims = claims.clone();
+        runtime::unblock(self.runtime.as_ref(), move || claims.check_in(&blob)).await
+      }
+      None => Err(format!("payload in blob {} but no blob store is configured", blob.id)),
+    }
+  }
+}
+
+async fn confirmed(confirm: PublisherConfirm, message_id: &str) -> Result<(), String> {
+  match confirm.await {
+    Ok(Confirmation::Ack(_)) => Ok(()),
+    Ok(Confirmation::Nack(_)) => Err(format!("{} broker refused message {}", ERROR, message_id)),
+    Ok(Confirmation::NotRequested) => Err(format!("{} publisher confirms are not enabled", ERROR)),
+    Err(e) => Err(e.to_string()),
+  }
+}
+
+/// The blob a message's payload was put in, `None` when the payload is inline.
+fn claimed(props: &BasicProperties) -> Option<BlobRef> {
+  let headers = props.headers().as_ref()?.inner();
+  let text = |name: &str| match headers.get(&ShortString::from(name.to_string())) {
+    Some(AMQPValue::LongString(v)) => Some(v.as_str().to_string()),
+    _ => None,
+  };
+  let size = match headers.get(&ShortString::from(CLAIM_SIZE_HEADER.to_string()))? {
+    AMQPValue::LongLongInt(v) => *v as u64,
+    AMQPValue::LongUInt(v) => *v as u64,
+    AMQPValue::LongInt(v) => *v as u64,
+    _ => return None,
+  };
+  Some(BlobRef {
+    id: text(CLAIM_ID_HEADER)?,
+    sha256: text(CLAIM_SHA256_HEADER)?,
+    size,
+  })
+}
+
+/// The kind of a streaming request or reply, `None` for plain requests, replies and events.
+pub fn streamed_kind(props: &BasicProperties) -> Option<&str> {
+  match props.kind() {
+    Some(kind) if kind.as_str() == stream::KIND || kind.as_str() == job::KIND => Some(kind.as_str()),
+    _ => None,
+  }
+}
+
+/// Schema version of an event, `None` when the message carries no version header.
+#[allow(dead_code)]
+pub fn schema_version(props: &BasicProperties) -> Option<u16> {
+  let headers = props.headers().as_ref()?;
+  match headers
+    .inner()
+    .iter()
+    .find(|(k, _)| k.as_str() == event::SCHEMA_VERSION_HEADER)?
+    .1
+  {
+    AMQPValue::ShortShortUInt(v) => Some(*v as u16),
+    AMQPValue::ShortUInt(v) => Some(*v),
+    AMQPValue::LongUInt(v) => Some(*v as u16),
+    AMQPValue::LongInt(v) => Some(*v as u16),
+    _ => None,
   }
 }
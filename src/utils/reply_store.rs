@@ -0,0 +1,239 @@
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use prometheus::{IntCounterVec, Opts, Registry};
+use serde::Deserialize;
+use std::{
+  collections::{HashMap, HashSet, VecDeque},
+  fmt,
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
+};
+use tokio::sync::Notify;
+
+/// At most `capacity` requests wait for replies at once, each keeping at most
+/// `max_replies` replies until they are read.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Settings {
+  #[serde(default = "default_capacity")]
+  pub capacity: usize,
+  #[serde(default = "default_max_replies")]
+  pub max_replies: usize,
+}
+
+impl Default for Settings {
+  fn default() -> Self {
+    Settings {
+      capacity: default_capacity(),
+      max_replies: default_max_replies(),
+    }
+  }
+}
+
+fn default_capacity() -> usize {
+  10000
+}
+
+fn default_max_replies() -> usize {
+  100
+}
+
+struct Waiter {
+  expires: Instant,
+  replies: Vec<Vec<u8>>,
+  notify: Arc<Notify>,
+}
+
+#[derive(Default)]
+struct Inner {
+  waiters: HashMap<String, Waiter>,
+  /// Recently finished correlation ids, to tell late replies from unknown ones.
+  finished: VecDeque<String>,
+  finished_ids: HashSet<String>,
+  swept: Option<Instant>,
+}
+
+/// Replies to our own requests by correlation id. Only replies for a registered waiter
+/// are kept; late replies, replies nobody asked for and replies beyond the caps are
+/// dropped and counted.
+#[derive(Clone)]
+pub struct ReplyStore {
+  settings: Settings,
+  inner: Arc<Mutex<Inner>>,
+  dropped: IntCounterVec,
+}
+
+impl fmt::Debug for ReplyStore {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "ReplyStore {{ capacity: {} }}", self.settings.capacity)
+  }
+}
+
+impl ReplyStore {
+  /// The dropped replies metric is registered with `registry`.
+  pub fn new(settings: Settings, registry: &Registry) -> ReplyStore {
+    let dropped = IntCounterVec::new(
+      Opts::new("rpc_replies_dropped_total", "Replies dropped by the reply store"),
+      &["reason"],
+    )
+    .unwrap();
+    if let Err(e) = registry.register(Box::new(dropped.clone())) {
+      error!("unable to register reply store metrics: {}", e);
+    }
+    ReplyStore {
+      settings,
+      inner: Arc::new(Mutex::new(Inner::default())),
+      dropped,
+    }
+  }
+
+  /// Wait for replies to `correlation_id` for up to `ttl`, waking `notify` whenever one
+  /// arrives. Registering again extends the wait. Returns false when the store is full.
+  pub fn register(&self, correlation_id: &str, ttl: Duration, notify: &Arc<Notify>) -> bool {
+    let mut inner = self.inner.lock().unwrap();
+    self.sweep(&mut inner);
+    let expires = Instant::now() + ttl;
+    if let Some(waiter) = inner.waiters.get_mut(correlation_id) {
+      waiter.expires = waiter.expires.max(expires);
+      waiter.notify = notify.clone();
+      return true;
+    }
+    if inner.waiters.len() >= self.settings.capacity {
+      warn!("reply store full, refusing to wait for {}", correlation_id);
+      return false;
+    }
+    inner.waiters.insert(
+      correlation_id.to_string(),
+      Waiter {
+        expires,
+        replies: Vec::new(),
+        notify: notify.clone(),
+      },
+    );
+    true
+  }
+
+  /// Stop waiting for `correlation_id`, dropping replies not read yet.
+  pub fn unregister(&self, correlation_id: &str) {
+    let mut inner = self.inner.lock().unwrap();
+    if inner.waiters.remove(correlation_id).is_some() {
+      self.finish(&mut inner, correlation_id.to_string());
+    }
+  }
+
+  /// Keep a reply if someone waits for it. Returns whether it was kept.
+  pub fn insert(&self, correlation_id: &str, reply: Vec<u8>) -> bool {
+    let mut inner = self.inner.lock().unwrap();
+    self.sweep(&mut inner);
+    let full = match inner.waiters.get_mut(correlation_id) {
+      Some(waiter) if waiter.replies.len() < self.settings.max_replies => {
+        waiter.replies.push(reply);
+        waiter.notify.notify_one();
+        return true;
+      }
+      Some(_) => true,
+      None => false,
+    };
+    let reason = if full {
+      "full"
+    } else if inner.finished_ids.contains(correlation_id) {
+      "late"
+    } else {
+      "unknown"
+    };
+    debug!("dropping {} reply {}", reason, correlation_id);
+    self.dropped.with_label_values(&[reason]).inc();
+    false
+  }
+
+  /// The first reply to `correlation_id` not read yet.
+  pub fn take_first(&self, correlation_id: &str) -> Option<Vec<u8>> {
+    let mut inner = self.inner.lock().unwrap();
+    match inner.waiters.get_mut(correlation_id) {
+      Some(waiter) if !waiter.replies.is_empty() => Some(waiter.replies.remove(0)),
+      _ => None,
+    }
+  }
+
+  /// Every reply to `correlation_id` not read yet. The waiter stays registered.
+  pub fn take_all(&self, correlation_id: &str) -> Vec<Vec<u8>> {
+    let mut inner = self.inner.lock().unwrap();
+    match inner.waiters.get_mut(correlation_id) {
+      Some(waiter) => waiter.replies.drain(..).collect(),
+      None => Vec::new(),
+    }
+  }
+
+  /// Drop waiters past their ttl, at most once a second.
+  fn sweep(&self, inner: &mut Inner) {
+    if inner.swept.map_or(false, |at| at.elapsed() < Duration::from_secs(1)) {
+      return;
+    }
+    let now = Instant::now();
+    inner.swept = Some(now);
+    let expired: Vec<String> = inner
+      .waiters
+      .iter()
+      .filter(|(_, waiter)| waiter.expires <= now)
+      .map(|(id, _)| id.clone())
+      .collect();
+    for id in expired {
+      inner.waiters.remove(&id);
+      self.finish(inner, id);
+    }
+  }
+
+  fn finish(&self, inner: &mut Inner, correlation_id: String) {
+    if inner.finished.len() >= self.settings.capacity {
+      if let Some(oldest) = inner.finished.pop_front() {
+        inner.finished_ids.remove(&oldest);
+      }
+    }
+    inner.finished_ids.insert(correlation_id.clone());
+    inner.finished.push_back(correlation_id);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn store(capacity: usize, max_replies: usize) -> ReplyStore {
+    ReplyStore::new(Settings { capacity, max_replies }, &Registry::new())
+  }
+
+  #[tokio::test]
+  async fn insert_wakes_the_waiter() {
+    let replies = store(10, 10);
+    let notify = Arc::new(Notify::new());
+    assert!(replies.register("a", Duration::from_secs(5), &notify));
+    assert!(replies.insert("a", b"one".to_vec()));
+    // the permit is kept when nobody waits yet
+    tokio::time::timeout(Duration::from_secs(1), notify.notified())
+      .await
+      .unwrap();
+    assert_eq!(replies.take_all("a"), vec![b"one".to_vec()]);
+  }
+
+  #[test]
+  fn drops_replies_nobody_waits_for() {
+    let replies = store(10, 1);
+    let notify = Arc::new(Notify::new());
+    assert!(!replies.insert("a", b"unknown".to_vec()));
+    assert!(replies.register("a", Duration::from_secs(5), &notify));
+    assert!(replies.insert("a", b"one".to_vec()));
+    assert!(!replies.insert("a", b"full".to_vec()));
+    replies.unregister("a");
+    assert!(!replies.insert("a", b"late".to_vec()));
+    assert!(replies.take_first("a").is_none());
+  }
+
+  #[test]
+  fn refuses_waiters_beyond_capacity() {
+    let replies = store(1, 10);
+    let notify = Arc::new(Notify::new());
+    assert!(replies.register("a", Duration::from_secs(5), &notify));
+    assert!(!replies.register("b", Duration::from_secs(5), &notify));
+    // registering again only extends the wait
+    assert!(replies.register("a", Duration::from_secs(5), &notify));
+  }
+}
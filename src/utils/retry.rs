@@ -0,0 +1,242 @@
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use rand::Rng;
+use serde::Deserialize;
+use std::{
+  collections::HashMap,
+  future::Future,
+  time::{Duration, Instant},
+};
+
+use crate::utils::helpers::matches_topic;
+use crate::utils::runtime::Runtime;
+
+/// Why an attempt failed, as far as retrying is concerned.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorClass {
+  /// No reply in time.
+  Timeout,
+  /// Refused by a circuit breaker or answered with `502`, `503` or `504`.
+  Unavailable,
+  /// Any other `5xx` reply, or one that could not be decoded.
+  ServerError,
+}
+
+impl ErrorClass {
+  pub fn of_status(status: u16) -> Option<ErrorClass> {
+    match status {
+      502..=504 => Some(ErrorClass::Unavailable),
+      500..=599 => Some(ErrorClass::ServerError),
+      _ => None,
+    }
+  }
+}
+
+/// How calls are retried. Only methods listed in `idempotent` are retried, at most
+/// `max_attempts` attempts in all, each waiting up to `attempt_timeout` seconds for a
+/// reply. Between attempts the caller sleeps a random time up to `base_delay`
+/// milliseconds doubled for every attempt, capped at `max_delay`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct RetryPolicy {
+  #[serde(default = "default_max_attempts")]
+  pub max_attempts: u32,
+  #[serde(default = "default_attempt_timeout")]
+  pub attempt_timeout: u64,
+  #[serde(default = "default_base_delay")]
+  pub base_delay: u64,
+  #[serde(default = "default_max_delay")]
+  pub max_delay: u64,
+  #[serde(default = "default_retry_on")]
+  pub retry_on: Vec<ErrorClass>,
+  #[serde(default = "default_idempotent")]
+  pub idempotent: Vec<String>,
+}
+
+impl Default for RetryPolicy {
+  fn default() -> Self {
+    RetryPolicy {
+      max_attempts: default_max_attempts(),
+      attempt_timeout: default_attempt_timeout(),
+      base_delay: default_base_delay(),
+      max_delay: default_max_delay(),
+      retry_on: default_retry_on(),
+      idempotent: default_idempotent(),
+    }
+  }
+}
+
+fn default_max_attempts() -> u32 {
+  1
+}
+
+fn default_attempt_timeout() -> u64 {
+  20
+}
+
+fn default_base_delay() -> u64 {
+  100
+}
+
+fn default_max_delay() -> u64 {
+  2000
+}
+
+fn default_retry_on() -> Vec<ErrorClass> {
+  vec![ErrorClass::Timeout, ErrorClass::Unavailable]
+}
+
+fn default_idempotent() -> Vec<String> {
+  vec![String::from("get"), String::from("list")]
+}
+
+impl RetryPolicy {
+  fn backoff(&self, attempt: u32) -> Duration {
+    let cap = self
+      .base_delay
+      .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+      .min(self.max_delay);
+    Duration::from_millis(rand::thread_rng().gen_range(0..=cap))
+  }
+}
+
+/// Retry policies by routing key pattern, e.g. `user.request.*`. A key matching several
+/// patterns uses the most specific one, keys matching none use `default`.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct Retries {
+  #[serde(default)]
+  pub default: RetryPolicy,
+  #[serde(default)]
+  pub keys: HashMap<String, RetryPolicy>,
+}
+
+impl Retries {
+  /// The policy for calling `method` on `key`, or `None` when the call must be tried only
+  /// once because the method is not idempotent.
+  pub fn policy(&self, key: &str, method: &str) -> Option<&RetryPolicy> {
+    let policy = self
+      .keys
+      .iter()
+      .filter(|(pattern, _)| matches_topic(pattern, key))
+      .max_by_key(|(pattern, _)| (specificity(pattern), pattern.as_str()))
+      .map_or(&self.default, |(_, policy)| policy);
+    if policy.idempotent.iter().any(|m| m == method) {
+      Some(policy)
+    } else {
+      None
+    }
+  }
+}
+
+/// How specific a routing key pattern is: literal words count most, then `*` over `#`.
+fn specificity(pattern: &str) -> (usize, usize) {
+  let words: Vec<&str> = pattern.split('.').collect();
+  let literal = words.iter().filter(|w| **w != "*" && **w != "#").count();
+  let single = words.iter().filter(|w| **w == "*").count();
+  (literal, single)
+}
+
+/// Run `attempt` until it succeeds, fails in a way the policy does not retry, runs out of
+/// attempts or the `budget` is spent. Every attempt is given the time it may wait for a
+/// reply, never more than what is left of the budget, and returns its result with the
+/// class of its failure, if any. The result of the last attempt is returned.
+pub async fn with_retries<T, F, Fut>(
+  runtime: &dyn Runtime,
+  policy: Option<&RetryPolicy>,
+  budget: Duration,
+  mut attempt: F,
+) -> T
+where
+  F: FnMut(Duration) -> Fut,
+  Fut: Future<Output = (T, Option<ErrorClass>)>,
+{
+  let deadline = Instant::now() + budget;
+  let mut attempts = 0;
+  loop {
+    attempts += 1;
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    let timeout = match policy {
+      Some(policy) => remaining.min(Duration::from_secs(policy.attempt_timeout)),
+      None => remaining,
+    };
+    let (result, failure) = attempt(timeout).await;
+    let (policy, class) = match (policy, failure) {
+      (Some(policy), Some(class)) if attempts < policy.max_attempts && policy.retry_on.contains(&class) => {
+        (policy, class)
+      }
+      _ => return result,
+    };
+    let delay = policy.backoff(attempts);
+    // an attempt needs some time left to be worth starting
+    if Instant::now() + delay + Duration::from_secs(1) >= deadline {
+      debug!("not retrying after {:?}, out of time", class);
+      return result;
+    }
+    debug!("retrying after {:?} in {:?}, attempt {}", class, delay, attempts + 1);
+    runtime.sleep(delay).await;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::runtime::Tokio;
+
+  fn attempts(max_attempts: u32) -> RetryPolicy {
+    RetryPolicy {
+      max_attempts,
+      ..RetryPolicy::default()
+    }
+  }
+
+  fn retries(patterns: &[(&str, u32)]) -> Retries {
+    Retries {
+      default: attempts(1),
+      keys: patterns.iter().map(|(p, n)| (p.to_string(), attempts(*n))).collect(),
+    }
+  }
+
+  #[test]
+  fn picks_the_most_specific_pattern() {
+    let retries = retries(&[("#", 2), ("*.request.*", 3), ("user.request.*", 4), ("user.#", 5)]);
+    assert_eq!(retries.policy("user.request.acme", "get").unwrap().max_attempts, 4);
+    assert_eq!(retries.policy("order.request.acme", "get").unwrap().max_attempts, 3);
+    assert_eq!(retries.policy("user.changed", "get").unwrap().max_attempts, 5);
+    assert_eq!(retries.policy("report", "get").unwrap().max_attempts, 2);
+  }
+
+  #[test]
+  fn uses_the_default_and_skips_non_idempotent_methods() {
+    let retries = retries(&[("user.request.*", 4)]);
+    assert_eq!(retries.policy("order.request.acme", "get").unwrap().max_attempts, 1);
+    assert!(retries.policy("user.request.acme", "create").is_none());
+  }
+
+  #[tokio::test]
+  async fn retries_only_what_the_policy_allows() {
+    let policy = RetryPolicy {
+      max_attempts: 3,
+      base_delay: 1,
+      max_delay: 1,
+      ..RetryPolicy::default()
+    };
+    let runtime = Tokio::current();
+    let mut calls = 0;
+    let result = with_retries(&runtime, Some(&policy), Duration::from_secs(5), |_| {
+      calls += 1;
+      let calls = calls;
+      async move { (calls, Some(ErrorClass::Timeout)) }
+    })
+    .await;
+    assert_eq!(result, 3);
+
+    let mut calls = 0;
+    let result = with_retries(&runtime, Some(&policy), Duration::from_secs(5), |_| {
+      calls += 1;
+      let calls = calls;
+      async move { (calls, Some(ErrorClass::ServerError)) }
+    })
+    .await;
+    assert_eq!(result, 1);
+  }
+}
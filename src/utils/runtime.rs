@@ -0,0 +1,126 @@
+use futures::{
+  channel::oneshot,
+  future::{self, BoxFuture, Either},
+  Future, FutureExt,
+};
+use std::{fmt, time::Duration};
+
+/// What code shared by the services needs from the runtime it runs on. The backend runs on
+/// tokio 1, the web gateway on actix, whose workers run tokio 0.2 and cannot drive tokio 1
+/// timers.
+pub trait Runtime: Send + Sync + fmt::Debug {
+  fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+  /// Run `job` on a thread that may block, off the async workers.
+  fn spawn_blocking(&self, job: Box<dyn FnOnce() + Send>) -> BoxFuture<'static, ()>;
+}
+
+/// Tokio 1, for the backend. Blocking jobs go to the pool of the runtime it was created on,
+/// also when they are started from threads outside of it, like the bus consumers.
+#[derive(Debug)]
+pub struct Tokio(tokio::runtime::Handle);
+
+impl Tokio {
+  /// The runtime this is called on. Panics outside of a tokio 1 runtime.
+  #[allow(dead_code)]
+  pub fn current() -> Tokio {
+    Tokio(tokio::runtime::Handle::current())
+  }
+}
+
+impl Runtime for Tokio {
+  fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+    let handle = self.0.clone();
+    // timers need the runtime entered when they are created
+    async move {
+      let sleep = {
+        let _entered = handle.enter();
+        tokio::time::sleep(duration)
+      };
+      sleep.await
+    }
+    .boxed()
+  }
+
+  fn spawn_blocking(&self, job: Box<dyn FnOnce() + Send>) -> BoxFuture<'static, ()> {
+    self.0.spawn_blocking(job).map(|_| ()).boxed()
+  }
+}
+
+/// Actix, for the web gateway: its timers and its blocking thread pool.
+#[derive(Debug)]
+pub struct Actix;
+
+impl Runtime for Actix {
+  fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+    actix_web::rt::time::delay_for(duration).boxed()
+  }
+
+  fn spawn_blocking(&self, job: Box<dyn FnOnce() + Send>) -> BoxFuture<'static, ()> {
+    actix_web::web::block(move || {
+      job();
+      Ok::<_, ()>(())
+    })
+    .map(|_| ())
+    .boxed()
+  }
+}
+
+/// The output of `future`, or `None` when it takes longer than `duration`.
+pub async fn timeout<F: Future>(runtime: &dyn Runtime, duration: Duration, future: F) -> Option<F::Output> {
+  futures::pin_mut!(future);
+  match future::select(future, runtime.sleep(duration)).await {
+    Either::Left((output, _)) => Some(output),
+    Either::Right(_) => None,
+  }
+}
+
+/// Run blocking `f` with `runtime.spawn_blocking` and wait for its result.
+pub async fn unblock<T, F>(runtime: &dyn Runtime, f: F) -> T
+where
+  T: Send + 'static,
+  F: FnOnce() -> T + Send + 'static,
+{
+  let (done, result) = oneshot::channel();
+  runtime
+    .spawn_blocking(Box::new(move || {
+      let _ = done.send(f());
+    }))
+    .await;
+  result.await.expect("blocking task panicked")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn times_out_slow_futures() {
+    let runtime = Tokio::current();
+    let slow = runtime.sleep(Duration::from_secs(5));
+    assert_eq!(timeout(&runtime, Duration::from_millis(10), slow).await, None);
+    assert_eq!(timeout(&runtime, Duration::from_secs(5), async { 1 }).await, Some(1));
+  }
+
+  #[tokio::test]
+  async fn runs_blocking_work_off_the_caller() {
+    let runtime = Tokio::current();
+    let caller = std::thread::current().id();
+    let worker = unblock(&runtime, || std::thread::current().id()).await;
+    assert_ne!(caller, worker);
+  }
+
+  #[actix_rt::test]
+  async fn runs_on_actix() {
+    let runtime = Actix;
+    assert_eq!(
+      timeout(
+        &runtime,
+        Duration::from_millis(10),
+        runtime.sleep(Duration::from_secs(5))
+      )
+      .await,
+      None
+    );
+    assert_eq!(unblock(&runtime, || 2).await, 2);
+  }
+}
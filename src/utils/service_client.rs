@@ -0,0 +1,311 @@
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use std::{
+  collections::HashMap,
+  sync::Arc,
+  time::{Duration, Instant},
+};
+use thiserror::Error;
+use tokio::sync::Notify;
+
+use crate::shared_models::request_response::{Request, Response};
+use crate::utils::circuit_breaker::{Breakers, Permit, Rejected};
+use crate::utils::helpers;
+use crate::utils::rabbitmq::MqChannel;
+use crate::utils::retry::{self, ErrorClass, Retries};
+use crate::utils::runtime;
+
+#[derive(Error, Debug, Clone)]
+pub enum CallError {
+  #[error("no reply within {0:?}")]
+  Timeout(Duration),
+  #[error("invalid reply: {0}")]
+  Decode(String),
+  #[error("{status}: {message}")]
+  Failed { status: u16, message: String },
+  #[error("not called: {0}")]
+  Unavailable(Rejected),
+}
+
+/// When to stop collecting replies before the deadline.
+#[derive(Debug, Clone, Copy)]
+pub enum Until {
+  /// Every target answered, successfully or not.
+  All,
+  Count(usize),
+  /// More than half of the targets answered successfully.
+  Quorum,
+}
+
+#[derive(Debug)]
+pub struct Reply {
+  pub target: String,
+  pub response: Response,
+}
+
+#[derive(Debug)]
+pub struct TargetError {
+  pub target: String,
+  pub error: CallError,
+}
+
+/// Outcome of a scatter-gather call. Error responses and targets that did not answer in
+/// time end up in `errors`; `satisfied` tells whether the `Until` condition was met.
+#[derive(Debug)]
+pub struct Gathered {
+  pub replies: Vec<Reply>,
+  pub errors: Vec<TargetError>,
+  pub satisfied: bool,
+}
+
+/// Request/reply client for calling other services over the bus from inside a handler.
+/// The channel must have its own reply queue with a running consumer, see
+/// `MqChannel::start_consuming`. Calls go through the circuit breaker and bulkhead of
+/// their routing key.
+#[derive(Clone)]
+pub struct ServiceClient {
+  mq: MqChannel,
+  breakers: Breakers,
+  retries: Retries,
+}
+
+impl ServiceClient {
+  pub fn new(mq: MqChannel, breakers: Breakers, retries: Retries) -> ServiceClient {
+    ServiceClient { mq, breakers, retries }
+  }
+
+  /// Call one service and wait up to `timeout` for its reply, retrying idempotent methods
+  /// as the retry policy of `key` allows. Error responses are returned as `Ok` like any
+  /// other response.
+  #[allow(dead_code)]
+  pub async fn call(&self, key: &str, req: &Request, timeout: Duration) -> Result<Response, CallError> {
+    let policy = self.retries.policy(key, &req.method);
+    let data = bincode::serialize(req).unwrap();
+    // shared by every attempt, so the receiver handles the request once
+    let message_id = helpers::new_uuid();
+    let correlation_id = helpers::new_uuid();
+    let (message_id, correlation_id) = (message_id.as_str(), correlation_id.as_str());
+    retry::with_retries(self.mq.runtime(), policy, timeout, move |attempt_timeout| {
+      self.call_once(key, data.clone(), message_id, correlation_id, attempt_timeout)
+    })
+    .await
+  }
+
+  async fn call_once(
+    &self,
+    key: &str,
+    data: Vec<u8>,
+    message_id: &str,
+    correlation_id: &str,
+    timeout: Duration,
+  ) -> (Result<Response, CallError>, Option<ErrorClass>) {
+    let permit = match self.breakers.acquire(key) {
+      Ok(permit) => permit,
+      Err(rejected) => return (Err(CallError::Unavailable(rejected)), Some(ErrorClass::Unavailable)),
+    };
+    let res = match self
+      .mq
+      .clone()
+      .request_reply_attempt(key, data, message_id, correlation_id, timeout)
+      .await
+    {
+      Ok(res) => res,
+      Err(_) => {
+        permit.timeout();
+        return (Err(CallError::Timeout(timeout)), Some(ErrorClass::Timeout));
+      }
+    };
+    match bincode::deserialize::<Response>(&res) {
+      Ok(res) => {
+        report(permit, Ok(&res));
+        let class = ErrorClass::of_status(res.status);
+        (Ok(res), class)
+      }
+      Err(e) => {
+        permit.error();
+        (Err(CallError::Decode(e.to_string())), Some(ErrorClass::ServerError))
+      }
+    }
+  }
+
+  /// Send `req` to every routing key in `keys` and collect one reply from each until
+  /// `until` is met or `deadline` passes, whichever comes first.
+  #[allow(dead_code)]
+  pub async fn scatter(&self, keys: &[String], req: &Request, until: Until, deadline: Duration) -> Gathered {
+    let mut mq = self.mq.clone();
+    let data = bincode::serialize(req).unwrap();
+    let notify = Arc::new(Notify::new());
+    let deadline_at = Instant::now() + deadline;
+    let mut gather = Gather::new(until, keys.len());
+    // correlation id -> target, for the targets that were called and did not answer yet
+    let mut waiting: HashMap<String, (&str, Permit)> = HashMap::new();
+    for key in keys {
+      // targets whose circuit is open are not called at all
+      let permit = match self.breakers.acquire(key) {
+        Ok(permit) => permit,
+        Err(rejected) => {
+          gather.fail(key, CallError::Unavailable(rejected));
+          continue;
+        }
+      };
+      let id = helpers::new_uuid();
+      if mq.publish_request(key, data.clone(), &id, deadline, &notify).await {
+        waiting.insert(id, (key.as_str(), permit));
+      } else {
+        // the reply store is full, which is a full bulkhead of a kind
+        gather.fail(key, CallError::Unavailable(Rejected::Full));
+      }
+    }
+
+    while !gather.done() && !waiting.is_empty() {
+      // a reply that came in since the last look left a permit, so none is missed
+      let remaining = deadline_at.saturating_duration_since(Instant::now());
+      if runtime::timeout(mq.runtime(), remaining, notify.notified())
+        .await
+        .is_none()
+      {
+        break;
+      }
+      let answered: Vec<(String, Vec<u8>)> = waiting
+        .keys()
+        .filter_map(|id| mq.take_replies(id).into_iter().next().map(|data| (id.clone(), data)))
+        .collect();
+      for (id, data) in answered {
+        mq.forget_replies(&id);
+        let (target, permit) = waiting.remove(&id).unwrap();
+        let decoded = bincode::deserialize::<Response>(&data);
+        report(permit, decoded.as_ref().map_err(|_| ()));
+        gather.reply(target, decoded);
+      }
+    }
+    let satisfied = gather.done();
+    for (id, (target, permit)) in waiting {
+      mq.forget_replies(&id);
+      permit.timeout();
+      gather.fail(target, CallError::Timeout(deadline));
+    }
+    let mut gathered = gather.gathered;
+    gathered.satisfied = satisfied;
+    if !satisfied {
+      warn!(
+        "scatter-gather ended unsatisfied with {} replies and {} errors",
+        gathered.replies.len(),
+        gathered.errors.len()
+      );
+    }
+    gathered
+  }
+}
+
+/// The replies of a scatter-gather call so far, and whether they meet `until`.
+struct Gather {
+  gathered: Gathered,
+  until: Until,
+  needed: usize,
+}
+
+impl Gather {
+  fn new(until: Until, targets: usize) -> Gather {
+    let needed = match until {
+      Until::All => targets,
+      Until::Count(n) => n,
+      Until::Quorum => targets / 2 + 1,
+    };
+    Gather {
+      gathered: Gathered {
+        replies: Vec::new(),
+        errors: Vec::new(),
+        satisfied: false,
+      },
+      until,
+      needed,
+    }
+  }
+
+  fn reply(&mut self, target: &str, decoded: Result<Response, bincode::Error>) {
+    match decoded {
+      Ok(res) if res.error.is_some() => self.fail(
+        target,
+        CallError::Failed {
+          status: res.status,
+          message: res.error.unwrap_or_default(),
+        },
+      ),
+      Ok(response) => self.gathered.replies.push(Reply {
+        target: target.to_string(),
+        response,
+      }),
+      Err(e) => self.fail(target, CallError::Decode(e.to_string())),
+    }
+  }
+
+  fn fail(&mut self, target: &str, error: CallError) {
+    self.gathered.errors.push(TargetError {
+      target: target.to_string(),
+      error,
+    });
+  }
+
+  fn done(&self) -> bool {
+    let answered = match self.until {
+      Until::All => self.gathered.replies.len() + self.gathered.errors.len(),
+      _ => self.gathered.replies.len(),
+    };
+    answered >= self.needed
+  }
+}
+
+fn report(permit: Permit, res: Result<&Response, ()>) {
+  match res {
+    Ok(res) if res.status < 500 => permit.success(),
+    _ => permit.error(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn ok() -> Result<Response, bincode::Error> {
+    Ok(Response::ok("test", Vec::new()))
+  }
+
+  fn failed() -> Result<Response, bincode::Error> {
+    Ok(Response::error("test", 500, String::from("boom")))
+  }
+
+  #[test]
+  fn quorum_needs_a_majority_of_successes() {
+    let mut gather = Gather::new(Until::Quorum, 3);
+    gather.reply("a", ok());
+    gather.reply("b", failed());
+    assert!(!gather.done());
+    gather.reply("c", ok());
+    assert!(gather.done());
+    assert_eq!(gather.gathered.replies.len(), 2);
+    assert_eq!(gather.gathered.errors[0].target, "b");
+  }
+
+  #[test]
+  fn all_counts_errors_as_answers() {
+    let mut gather = Gather::new(Until::All, 2);
+    gather.fail("a", CallError::Unavailable(Rejected::Full));
+    assert!(!gather.done());
+    gather.reply("b", ok());
+    assert!(gather.done());
+  }
+
+  #[test]
+  fn count_stops_at_enough_successes() {
+    let mut gather = Gather::new(Until::Count(1), 3);
+    gather.reply("a", Err(bincode::Error::new(bincode::ErrorKind::SizeLimit)));
+    assert!(!gather.done());
+    gather.reply("b", ok());
+    assert!(gather.done());
+  }
+
+  #[test]
+  fn nothing_to_wait_for_without_targets() {
+    assert!(Gather::new(Until::All, 0).done());
+  }
+}
@@ -0,0 +1,332 @@
+use lapin::{
+  options::{ExchangeDeclareOptions, QueueDeclareOptions},
+  types::{AMQPValue, FieldTable, LongString, ShortString},
+  ExchangeKind,
+};
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+use std::fmt;
+
+/// Exchanges, queues and bindings declared on startup. Exchanges and queues a service
+/// uses but that are not listed here are declared the way they always were: a durable,
+/// auto-delete topic exchange and a transient queue without arguments.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct Topology {
+  #[serde(default)]
+  pub exchanges: Vec<Exchange>,
+  #[serde(default)]
+  pub queues: Vec<Queue>,
+  #[serde(default)]
+  pub bindings: Vec<Binding>,
+  /// Arguments of the exclusive reply queue every instance declares on start. It is named
+  /// by the broker and always transient, whatever else is set.
+  #[serde(default)]
+  pub reply_queue: Option<Queue>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Exchange {
+  pub name: String,
+  /// `topic`, `direct`, `fanout`, `headers` or a plugin provided kind.
+  #[serde(default = "default_kind")]
+  pub kind: String,
+  #[serde(default = "default_true")]
+  pub durable: bool,
+  #[serde(default = "default_true")]
+  pub auto_delete: bool,
+  #[serde(default)]
+  pub internal: bool,
+  /// Receives messages no queue is bound for.
+  #[serde(default)]
+  pub alternate_exchange: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Queue {
+  /// Left out for the reply queue.
+  #[serde(default)]
+  pub name: String,
+  #[serde(default)]
+  pub durable: bool,
+  #[serde(default)]
+  pub auto_delete: bool,
+  /// Only the declaring connection may use the queue. Set for reply queues only.
+  #[serde(skip)]
+  pub exclusive: bool,
+  /// A replicated quorum queue instead of a classic one. Quorum queues are always durable.
+  #[serde(default)]
+  pub quorum: bool,
+  #[serde(default)]
+  pub max_length: Option<i64>,
+  #[serde(default)]
+  pub max_length_bytes: Option<i64>,
+  /// `drop-head`, `reject-publish` or `reject-publish-dlx` once the queue is full.
+  #[serde(default)]
+  pub overflow: Option<String>,
+  /// In milliseconds.
+  #[serde(default)]
+  pub message_ttl: Option<i64>,
+  #[serde(default)]
+  pub dead_letter_exchange: Option<String>,
+  #[serde(default)]
+  pub dead_letter_routing_key: Option<String>,
+  /// Highest message priority the queue orders by. Not supported by quorum queues.
+  #[serde(default)]
+  pub max_priority: Option<u8>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct Binding {
+  pub exchange: String,
+  pub queue: String,
+  pub routing_key: String,
+}
+
+fn default_kind() -> String {
+  String::from("topic")
+}
+
+fn default_true() -> bool {
+  true
+}
+
+impl Topology {
+  /// The topology with `exchange` and `queues` added with their defaults unless listed.
+  pub fn including(&self, exchange: &str, queues: &[String]) -> Topology {
+    self.with_defaults(Topology {
+      exchanges: vec![Exchange::named(exchange)],
+      queues: queues.iter().map(|q| Queue::named(q)).collect(),
+      ..Topology::default()
+    })
+  }
+
+  /// The reply queue of an instance, with the configured arguments.
+  pub fn reply_queue(&self) -> Queue {
+    let configured = self.reply_queue.clone().unwrap_or_else(|| Queue::named(""));
+    Queue {
+      name: String::new(),
+      durable: false,
+      auto_delete: true,
+      exclusive: true,
+      quorum: false,
+      ..configured
+    }
+  }
+
+  /// The topology with the exchanges, queues and bindings of `defaults` added unless
+  /// listed already, so configured settings take precedence.
+  pub fn with_defaults(&self, defaults: Topology) -> Topology {
+    let mut topology = self.clone();
+    for exchange in defaults.exchanges {
+      if !topology.exchanges.iter().any(|e| e.name == exchange.name) {
+        topology.exchanges.push(exchange);
+      }
+    }
+    for queue in defaults.queues {
+      if !topology.queues.iter().any(|q| q.name == queue.name) {
+        topology.queues.push(queue);
+      }
+    }
+    for binding in defaults.bindings {
+      if !topology.bindings.contains(&binding) {
+        topology.bindings.push(binding);
+      }
+    }
+    topology
+  }
+}
+
+impl Exchange {
+  pub fn named(name: &str) -> Exchange {
+    Exchange {
+      name: name.to_string(),
+      kind: default_kind(),
+      durable: true,
+      auto_delete: true,
+      internal: false,
+      alternate_exchange: None,
+    }
+  }
+
+  pub fn kind(&self) -> ExchangeKind {
+    match self.kind.as_str() {
+      "topic" => ExchangeKind::Topic,
+      "direct" => ExchangeKind::Direct,
+      "fanout" => ExchangeKind::Fanout,
+      "headers" => ExchangeKind::Headers,
+      kind => ExchangeKind::Custom(kind.to_string()),
+    }
+  }
+
+  pub fn options(&self, passive: bool) -> ExchangeDeclareOptions {
+    ExchangeDeclareOptions {
+      passive,
+      durable: self.durable,
+      auto_delete: self.auto_delete,
+      internal: self.internal,
+      nowait: false,
+    }
+  }
+
+  pub fn arguments(&self) -> FieldTable {
+    let mut args = FieldTable::default();
+    if let Some(alternate) = &self.alternate_exchange {
+      insert_str(&mut args, "alternate-exchange", alternate);
+    }
+    args
+  }
+}
+
+impl Queue {
+  pub fn named(name: &str) -> Queue {
+    Queue {
+      name: name.to_string(),
+      durable: false,
+      auto_delete: false,
+      exclusive: false,
+      quorum: false,
+      max_length: None,
+      max_length_bytes: None,
+      overflow: None,
+      message_ttl: None,
+      dead_letter_exchange: None,
+      dead_letter_routing_key: None,
+      max_priority: None,
+    }
+  }
+
+  pub fn options(&self, passive: bool) -> QueueDeclareOptions {
+    QueueDeclareOptions {
+      passive,
+      durable: self.durable || self.quorum,
+      exclusive: self.exclusive,
+      auto_delete: self.auto_delete && !self.quorum,
+      nowait: false,
+    }
+  }
+
+  pub fn arguments(&self) -> FieldTable {
+    let mut args = FieldTable::default();
+    if self.quorum {
+      insert_str(&mut args, "x-queue-type", "quorum");
+    }
+    if let Some(max_length) = self.max_length {
+      args.insert(
+        ShortString::from("x-max-length".to_string()),
+        AMQPValue::LongLongInt(max_length),
+      );
+    }
+    if let Some(max_bytes) = self.max_length_bytes {
+      args.insert(
+        ShortString::from("x-max-length-bytes".to_string()),
+        AMQPValue::LongLongInt(max_bytes),
+      );
+    }
+    if let Some(overflow) = &self.overflow {
+      insert_str(&mut args, "x-overflow", overflow);
+    }
+    if let Some(ttl) = self.message_ttl {
+      args.insert(
+        ShortString::from("x-message-ttl".to_string()),
+        AMQPValue::LongLongInt(ttl),
+      );
+    }
+    if let Some(exchange) = &self.dead_letter_exchange {
+      insert_str(&mut args, "x-dead-letter-exchange", exchange);
+    }
+    if let Some(key) = &self.dead_letter_routing_key {
+      insert_str(&mut args, "x-dead-letter-routing-key", key);
+    }
+    match self.max_priority {
+      Some(_) if self.quorum => warn!("ignoring max_priority of quorum queue {}", self.name),
+      Some(priority) => {
+        args.insert(
+          ShortString::from("x-max-priority".to_string()),
+          AMQPValue::ShortShortUInt(priority),
+        );
+      }
+      None => (),
+    }
+    args
+  }
+}
+
+fn insert_str(args: &mut FieldTable, key: &str, value: &str) {
+  args.insert(
+    ShortString::from(key.to_string()),
+    AMQPValue::LongString(LongString::from(value.to_string())),
+  );
+}
+
+/// How the broker differs from the configured topology, as found by a dry run.
+#[derive(Debug)]
+pub enum Change {
+  /// Missing on the broker and would be created.
+  Create(String),
+  /// Exists as configured.
+  Unchanged(String),
+  /// Exists with other settings; declaring it would fail until it is deleted.
+  Conflict(String, String),
+  /// Bindings cannot be read over AMQP, so they are always (re)applied.
+  Bind(String),
+}
+
+impl fmt::Display for Change {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Change::Create(what) => write!(f, "+ {}", what),
+      Change::Unchanged(what) => write!(f, "= {}", what),
+      Change::Conflict(what, why) => write!(f, "! {} ({})", what, why),
+      Change::Bind(what) => write!(f, "~ {}", what),
+    }
+  }
+}
+
+impl fmt::Display for Exchange {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "exchange {} {} durable={} auto_delete={}",
+      self.name, self.kind, self.durable, self.auto_delete
+    )?;
+    if let Some(alternate) = &self.alternate_exchange {
+      write!(f, " alternate-exchange={}", alternate)?;
+    }
+    Ok(())
+  }
+}
+
+impl fmt::Display for Queue {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let options = self.options(false);
+    let name = if self.name.is_empty() {
+      "(named by the broker)"
+    } else {
+      &self.name
+    };
+    write!(
+      f,
+      "queue {} durable={} auto_delete={}",
+      name, options.durable, options.auto_delete
+    )?;
+    if options.exclusive {
+      write!(f, " exclusive=true")?;
+    }
+    for (key, value) in self.arguments().inner() {
+      match value {
+        AMQPValue::LongString(v) => write!(f, " {}={}", key.as_str(), v.as_str())?,
+        AMQPValue::LongLongInt(v) => write!(f, " {}={}", key.as_str(), v)?,
+        AMQPValue::ShortShortUInt(v) => write!(f, " {}={}", key.as_str(), v)?,
+        v => write!(f, " {}={:?}", key.as_str(), v)?,
+      }
+    }
+    Ok(())
+  }
+}
+
+impl fmt::Display for Binding {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "binding {} -> {} on {}", self.exchange, self.queue, self.routing_key)
+  }
+}
@@ -0,0 +1,79 @@
+use actix_session::UserSession;
+use actix_web::{
+  dev::Payload,
+  http::{header, HeaderMap},
+  web, Error, FromRequest, HttpMessage, HttpRequest,
+};
+use futures::future::{ready, Ready};
+use jsonwebtoken::{decode, DecodingKey, Validation};
+#[allow(unused_imports)]
+use log::{debug, error, info};
+use serde::Deserialize;
+
+use super::router::get_user_session;
+use super::AppState;
+use crate::shared_models::principal::Principal;
+use crate::shared_models::tenant::Tenant;
+
+/// Claims of the bearer tokens accepted by the gateway.
+#[derive(Deserialize, Debug)]
+pub struct Claims {
+  pub sub: String,
+  pub company_id: String,
+  #[serde(default)]
+  pub roles: Vec<String>,
+  pub exp: usize,
+}
+
+pub fn bearer_claims(headers: &HeaderMap, secret: Option<&str>) -> Option<Claims> {
+  let secret = secret?;
+  let token = headers
+    .get(header::AUTHORIZATION)?
+    .to_str()
+    .ok()?
+    .strip_prefix("Bearer ")?;
+  match decode::<Claims>(
+    token,
+    &DecodingKey::from_secret(secret.as_bytes()),
+    &Validation::default(),
+  ) {
+    Ok(data) => Some(data.claims),
+    Err(e) => {
+      debug!("rejected bearer token: {}", e);
+      None
+    }
+  }
+}
+
+/// Identify the caller from its session or bearer token. Unidentified callers are anonymous.
+/// Takes handler requests as well as the `ServiceRequest` of a middleware.
+pub fn principal<R: HttpMessage + UserSession>(req: &R, jwt_secret: Option<&str>) -> Principal {
+  if let Some(session) = get_user_session(&req.get_session()) {
+    return Principal {
+      user_id: session.user_id,
+      tenant: Tenant::parse(&session.company_id).ok(),
+      roles: session.roles,
+    };
+  }
+  if let Some(claims) = bearer_claims(req.headers(), jwt_secret) {
+    return Principal {
+      user_id: claims.sub,
+      tenant: Tenant::parse(&claims.company_id).ok(),
+      roles: claims.roles,
+    };
+  }
+  Principal::anonymous()
+}
+
+impl FromRequest for Principal {
+  type Error = Error;
+  type Future = Ready<Result<Principal, Error>>;
+  type Config = ();
+
+  fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+    let jwt_secret = req
+      .app_data::<web::Data<AppState>>()
+      .and_then(|state| state.tenancy.jwt_secret().map(str::to_string));
+    ready(Ok(principal(req, jwt_secret.as_deref())))
+  }
+}
@@ -1,5 +1,15 @@
 use serde_derive::Deserialize;
+use std::collections::HashMap;
 use std::fs;
+use std::net::IpAddr;
+
+use crate::utils::circuit_breaker;
+use crate::utils::claim_check;
+use crate::utils::file_store;
+use crate::utils::policy::Policy;
+use crate::utils::reply_store;
+use crate::utils::retry;
+use crate::utils::topology::Topology;
 
 /// Attempt to load and parse the config file into our Config struct.
 /// If a file cannot be found, return a default Config.
@@ -21,6 +31,34 @@ pub struct Config {
   pub rabbit_mq: RabbitMq,
   pub psql: Psql,
   pub prometheus: Prometheus,
+  #[serde(default)]
+  pub response_cache: ResponseCache,
+  #[serde(default)]
+  pub rate_limit: RateLimit,
+  #[serde(default)]
+  pub tenancy: Tenancy,
+  #[serde(default)]
+  pub policy: Policy,
+  #[serde(default)]
+  pub websocket: Websocket,
+  #[serde(default)]
+  pub streams: Streams,
+  #[serde(default)]
+  pub jobs: Jobs,
+  #[serde(default)]
+  pub idempotency: Idempotency,
+  #[serde(default)]
+  pub circuit_breaker: circuit_breaker::Settings,
+  #[serde(default)]
+  pub retry: retry::Retries,
+  #[serde(default)]
+  pub replies: reply_store::Settings,
+  #[serde(default)]
+  pub topology: Topology,
+  #[serde(default)]
+  pub claim_check: claim_check::Settings,
+  #[serde(default)]
+  pub files: file_store::Settings,
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -30,6 +68,11 @@ pub struct WebServer {
   pub worker_pool: usize,
   pub templates: String,
   pub tls: Tls,
+  /// Models served by the backend CRUD service and exposed under `/api/{model}`.
+  #[serde(default)]
+  pub models: Vec<String>,
+  #[serde(default)]
+  pub cors: Cors,
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -53,6 +96,15 @@ pub struct Psql {
   pub sslmode: String,
 }
 
+impl Psql {
+  pub fn connection_string(&self) -> String {
+    format!(
+      "host={} port={} user={} password={} dbname={} sslmode={}",
+      self.host, self.port, self.user, self.password, self.database, self.sslmode
+    )
+  }
+}
+
 #[derive(Deserialize, Clone, Debug)]
 pub struct Tls {
   pub cacert: String,
@@ -60,6 +112,55 @@ pub struct Tls {
   pub key: String,
 }
 
+/// Cross origin access to the gateway. Without allowed origins only same origin requests
+/// are served.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Cors {
+  #[serde(default)]
+  pub allowed_origins: Vec<String>,
+  #[serde(default = "default_cors_methods")]
+  pub allowed_methods: Vec<String>,
+  #[serde(default = "default_cors_headers")]
+  pub allowed_headers: Vec<String>,
+  #[serde(default)]
+  pub allow_credentials: bool,
+  #[serde(default = "default_cors_max_age")]
+  pub max_age: usize,
+}
+
+impl Default for Cors {
+  fn default() -> Self {
+    Cors {
+      allowed_origins: Vec::new(),
+      allowed_methods: default_cors_methods(),
+      allowed_headers: default_cors_headers(),
+      allow_credentials: false,
+      max_age: default_cors_max_age(),
+    }
+  }
+}
+
+fn default_cors_methods() -> Vec<String> {
+  ["GET", "POST", "PUT", "DELETE"].iter().map(|m| m.to_string()).collect()
+}
+
+fn default_cors_headers() -> Vec<String> {
+  [
+    "Authorization",
+    "Content-Type",
+    "If-None-Match",
+    "X-CSRF-Token",
+    "Idempotency-Key",
+  ]
+  .iter()
+  .map(|h| h.to_string())
+  .collect()
+}
+
+fn default_cors_max_age() -> usize {
+  3600
+}
+
 #[derive(Deserialize, Clone, Debug)]
 pub struct Prometheus {
   pub endpoint: String,
@@ -78,3 +179,197 @@ pub struct RabbitMq {
   pub consumer: String,
   pub prefetch: u16,
 }
+
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct ResponseCache {
+  #[serde(default)]
+  pub routes: Vec<CacheRoute>,
+}
+
+/// Cache reads of every path starting with `path` for `ttl` seconds.
+#[derive(Deserialize, Clone, Debug)]
+pub struct CacheRoute {
+  pub path: String,
+  pub ttl: u32,
+}
+
+/// Clients are told apart by their peer address. `X-Forwarded-For` is only believed on
+/// connections from one of the `trusted_proxies`.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct RateLimit {
+  #[serde(default)]
+  pub groups: Vec<RateLimitGroup>,
+  #[serde(default)]
+  pub trusted_proxies: Vec<IpAddr>,
+}
+
+/// Allow `limit` requests per `window` seconds to paths starting with `path`, counted
+/// per `key`. Requests without a session are always counted per client IP.
+#[derive(Deserialize, Clone, Debug)]
+pub struct RateLimitGroup {
+  pub name: String,
+  pub path: String,
+  pub key: RateLimitKey,
+  pub limit: u64,
+  pub window: u64,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitKey {
+  User,
+  Tenant,
+  Ip,
+}
+
+/// How the tenant of a request is resolved: from the session, or from the `company_id`
+/// claim of a bearer token signed with `jwt_secret`. Bearer tokens with `service_role` act
+/// for the tenant named in `header` instead. A `daily_quota` of 0 disables quotas.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Tenancy {
+  pub jwt_secret: Option<String>,
+  pub header: Option<String>,
+  #[serde(default = "default_service_role")]
+  pub service_role: String,
+  #[serde(default)]
+  pub daily_quota: u64,
+  #[serde(default)]
+  pub quotas: HashMap<String, u64>,
+}
+
+impl Default for Tenancy {
+  fn default() -> Self {
+    Tenancy {
+      jwt_secret: None,
+      header: None,
+      service_role: default_service_role(),
+      daily_quota: 0,
+      quotas: HashMap::new(),
+    }
+  }
+}
+
+fn default_service_role() -> String {
+  String::from("service")
+}
+
+/// The `/ws` endpoint. The instance queue of the gateway is bound to every pattern in `topics` and clients
+/// subscribe to topics below those. A connection is closed when no pong arrives within
+/// `client_timeout` seconds; events beyond `buffer` queued frames are dropped.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Websocket {
+  #[serde(default = "default_ws_topics")]
+  pub topics: Vec<String>,
+  #[serde(default = "default_ws_heartbeat")]
+  pub heartbeat_interval: u64,
+  #[serde(default = "default_ws_timeout")]
+  pub client_timeout: u64,
+  #[serde(default = "default_ws_subscriptions")]
+  pub max_subscriptions: usize,
+  #[serde(default = "default_ws_buffer")]
+  pub buffer: usize,
+}
+
+impl Default for Websocket {
+  fn default() -> Self {
+    Websocket {
+      topics: default_ws_topics(),
+      heartbeat_interval: default_ws_heartbeat(),
+      client_timeout: default_ws_timeout(),
+      max_subscriptions: default_ws_subscriptions(),
+      buffer: default_ws_buffer(),
+    }
+  }
+}
+
+fn default_ws_topics() -> Vec<String> {
+  vec!["*.changed.*".to_string()]
+}
+
+fn default_ws_heartbeat() -> u64 {
+  5
+}
+
+fn default_ws_timeout() -> u64 {
+  15
+}
+
+fn default_ws_subscriptions() -> usize {
+  20
+}
+
+fn default_ws_buffer() -> usize {
+  64
+}
+
+/// Buffering of streamed backend replies served as server-sent events. A stream is
+/// dropped `retention` seconds after its last message; clients can only resume within the
+/// last `max_messages` messages.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Streams {
+  #[serde(default = "default_stream_retention")]
+  pub retention: u64,
+  #[serde(default = "default_stream_messages")]
+  pub max_messages: usize,
+}
+
+impl Default for Streams {
+  fn default() -> Self {
+    Streams {
+      retention: default_stream_retention(),
+      max_messages: default_stream_messages(),
+    }
+  }
+}
+
+fn default_stream_retention() -> u64 {
+  300
+}
+
+fn default_stream_messages() -> usize {
+  1000
+}
+
+/// Job records are kept in redis for `ttl` seconds after their last change.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Jobs {
+  #[serde(default = "default_job_ttl")]
+  pub ttl: u32,
+}
+
+impl Default for Jobs {
+  fn default() -> Self {
+    Jobs { ttl: default_job_ttl() }
+  }
+}
+
+fn default_job_ttl() -> u32 {
+  86400
+}
+
+/// Responses to writes carrying an `Idempotency-Key` are replayed for `window` seconds.
+/// A request holds its key for at most `lock_ttl` seconds while in flight.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Idempotency {
+  #[serde(default = "default_idempotency_window")]
+  pub window: u32,
+  #[serde(default = "default_idempotency_lock")]
+  pub lock_ttl: u32,
+}
+
+impl Default for Idempotency {
+  fn default() -> Self {
+    Idempotency {
+      window: default_idempotency_window(),
+      lock_ttl: default_idempotency_lock(),
+    }
+  }
+}
+
+fn default_idempotency_window() -> u32 {
+  86400
+}
+
+fn default_idempotency_lock() -> u32 {
+  30
+}
@@ -0,0 +1,110 @@
+use actix::prelude::{Message, Recipient, SendError};
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use std::{
+  collections::{HashMap, HashSet},
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+  },
+};
+
+use crate::shared_models::tenant::Tenant;
+use crate::utils::helpers::matches_topic;
+
+/// A bus event delivered to a websocket connection. `dropped` counts the events lost since
+/// the previous delivery because the connection did not keep up.
+#[derive(Message, Clone, Debug)]
+#[rtype(result = "()")]
+pub struct BusEvent {
+  pub topic: String,
+  pub data: Vec<u8>,
+  pub dropped: usize,
+}
+
+struct Subscriber {
+  tenant: Tenant,
+  topics: HashSet<String>,
+  recipient: Recipient<BusEvent>,
+  dropped: usize,
+}
+
+/// Fans the events received on the gateway's shared queue out to websocket connections.
+/// Event routing keys are `<topic>.<tenant>`; a connection only sees events of its own
+/// tenant whose topic matches one of its subscriptions.
+#[derive(Clone, Default)]
+pub struct EventHub {
+  next_id: Arc<AtomicUsize>,
+  subscribers: Arc<Mutex<HashMap<usize, Subscriber>>>,
+}
+
+impl EventHub {
+  pub fn connect(&self, tenant: Tenant, recipient: Recipient<BusEvent>) -> usize {
+    let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+    self.subscribers.lock().unwrap().insert(
+      id,
+      Subscriber {
+        tenant,
+        topics: HashSet::new(),
+        recipient,
+        dropped: 0,
+      },
+    );
+    id
+  }
+
+  pub fn disconnect(&self, id: usize) {
+    self.subscribers.lock().unwrap().remove(&id);
+  }
+
+  pub fn subscribe(&self, id: usize, topic: &str) {
+    if let Some(s) = self.subscribers.lock().unwrap().get_mut(&id) {
+      s.topics.insert(topic.to_string());
+    }
+  }
+
+  pub fn unsubscribe(&self, id: usize, topic: &str) {
+    if let Some(s) = self.subscribers.lock().unwrap().get_mut(&id) {
+      s.topics.remove(topic);
+    }
+  }
+
+  /// Deliver an event without blocking the consumer. A connection whose mailbox is full
+  /// loses the event, closed connections are removed.
+  pub fn dispatch(&self, key: &str, data: &[u8]) {
+    let (topic, tenant) = match key.rsplit_once('.') {
+      Some(parts) => parts,
+      None => return,
+    };
+    let mut subscribers = self.subscribers.lock().unwrap();
+    let mut closed = Vec::new();
+    for (id, s) in subscribers.iter_mut() {
+      if s.tenant.as_str() != tenant || !s.topics.iter().any(|p| matches_topic(p, topic)) {
+        continue;
+      }
+      let event = BusEvent {
+        topic: topic.to_string(),
+        data: data.to_vec(),
+        dropped: s.dropped,
+      };
+      match s.recipient.try_send(event) {
+        Ok(()) => s.dropped = 0,
+        Err(SendError::Full(_)) => s.dropped += 1,
+        Err(SendError::Closed(_)) => closed.push(*id),
+      }
+    }
+    for id in closed {
+      subscribers.remove(&id);
+    }
+  }
+}
+
+/// A subscription pattern must name at least one word and only use characters that can
+/// appear in routing keys.
+pub fn valid_topic(pattern: &str) -> bool {
+  !pattern.is_empty()
+    && pattern.len() <= 255
+    && pattern.split('.').all(|w| {
+      w == "*" || w == "#" || (!w.is_empty() && w.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+    })
+}
@@ -0,0 +1,128 @@
+use actix_redis::RespValue;
+use actix_web::HttpRequest;
+#[allow(unused_imports)]
+use log::{debug, error, info};
+use openssl::sha::sha256;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+
+use super::config;
+use super::redis::Redis;
+use crate::shared_models::request_response::Response;
+use crate::utils::helpers;
+
+const KEY_PREFIX: &str = "idempotency";
+pub const HEADER: &str = "Idempotency-Key";
+const MAX_KEY_LENGTH: usize = 255;
+
+#[derive(Serialize, Deserialize)]
+enum Entry {
+  InFlight { fingerprint: String },
+  Done { fingerprint: String, response: Response },
+}
+
+/// Replays the first response of a write sent with an `Idempotency-Key` header for
+/// `window` seconds. Keys are scoped by tenant, method and path; reusing a key for a
+/// different payload is rejected. Responses with a 5xx status are not kept so the client
+/// can retry.
+#[derive(Clone)]
+pub struct Idempotency {
+  redis: Redis,
+  window: u32,
+  lock_ttl: u32,
+}
+
+impl Idempotency {
+  pub fn new(redis: Redis, config: &config::Idempotency) -> Idempotency {
+    Idempotency {
+      redis,
+      window: config.window,
+      lock_ttl: config.lock_ttl,
+    }
+  }
+
+  pub async fn run<F>(&self, req: &HttpRequest, tenant: &str, payload: &[u8], fetch: F) -> Response
+  where
+    F: Future<Output = Response>,
+  {
+    let key = match req.headers().get(HEADER).map(|v| v.to_str()) {
+      None => return fetch.await,
+      Some(Ok(key)) if !key.is_empty() && key.len() <= MAX_KEY_LENGTH => key,
+      Some(_) => return Response::error("api_service", 400, format!("invalid {} header", HEADER)),
+    };
+    let digest = sha256(format!("{} {} {}", req.method(), req.path(), key).as_bytes());
+    let key = format!("{}:{}:{}", KEY_PREFIX, tenant, helpers::to_hex(&digest));
+    let fingerprint = helpers::to_hex(&sha256(payload));
+
+    let lock = bincode::serialize(&Entry::InFlight {
+      fingerprint: fingerprint.clone(),
+    })
+    .unwrap();
+    match self
+      .redis
+      .query(&[
+        b"SET",
+        key.as_bytes(),
+        &lock,
+        b"NX",
+        b"EX",
+        self.lock_ttl.to_string().as_bytes(),
+      ])
+      .await
+    {
+      Ok(RespValue::Nil) => return self.replay(&key, &fingerprint).await,
+      Ok(_) => (),
+      Err(e) => {
+        error!("idempotency store unavailable: {}", e);
+        return fetch.await;
+      }
+    }
+
+    let res = fetch.await;
+    if res.status >= 500 {
+      self.redis.send(&[b"DEL", key.as_bytes()]);
+      return res;
+    }
+    let entry = Entry::Done {
+      fingerprint,
+      response: res.clone(),
+    };
+    if let Err(e) = self
+      .redis
+      .set_ex(&key, &bincode::serialize(&entry).unwrap(), self.window)
+      .await
+    {
+      error!("failed to store idempotent response: {}", e);
+    }
+    res
+  }
+
+  async fn replay(&self, key: &str, fingerprint: &str) -> Response {
+    let entry = match self.redis.get(key).await {
+      Ok(Some(data)) => bincode::deserialize::<Entry>(&data).ok(),
+      Ok(None) => None,
+      Err(e) => {
+        error!("failed to read idempotent response: {}", e);
+        None
+      }
+    };
+    match entry {
+      Some(Entry::InFlight { fingerprint: f }) | Some(Entry::Done { fingerprint: f, .. }) if f != fingerprint => {
+        Response::error(
+          "api_service",
+          422,
+          format!("{} was used for a different request", HEADER),
+        )
+      }
+      Some(Entry::Done { response, .. }) => {
+        debug!("replaying idempotent response {}", key);
+        response
+      }
+      _ => Response::error(
+        "api_service",
+        409,
+        format!("a request with this {} is in progress", HEADER),
+      ),
+    }
+  }
+}
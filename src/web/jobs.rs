@@ -0,0 +1,198 @@
+use actix_redis::RespValue;
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use std::collections::HashMap;
+
+use super::config;
+use super::redis::Redis;
+use crate::shared_models::job::{self, JobState};
+use crate::shared_models::stream::{StreamEvent, StreamMessage};
+use crate::shared_models::tenant::Tenant;
+use crate::utils::helpers;
+
+/// Move a job to a new state unless it already reached a final one.
+/// KEYS[1] is the job hash, ARGV is the state, the time, the ttl and field/value pairs to set.
+const TRANSITION: &str = r#"
+local state = redis.call('HGET', KEYS[1], 'state')
+if not state or state == 'succeeded' or state == 'failed' or state == 'cancelled' then
+  return 0
+end
+redis.call('HSET', KEYS[1], 'state', ARGV[1], 'updated_at', ARGV[2])
+for i = 4, #ARGV, 2 do
+  redis.call('HSET', KEYS[1], ARGV[i], ARGV[i + 1])
+end
+redis.call('EXPIRE', KEYS[1], ARGV[3])
+return 1
+"#;
+
+/// A job as stored in redis. `progress` and `result` hold the JSON payloads of the last
+/// progress message and of the final message.
+#[derive(Debug, Clone)]
+pub struct Job {
+  pub id: String,
+  pub tenant: String,
+  pub model: String,
+  pub method: String,
+  pub state: JobState,
+  pub status: Option<u16>,
+  pub progress: Option<String>,
+  pub result: Option<String>,
+  pub error: Option<String>,
+  pub created_at: u64,
+  pub updated_at: u64,
+}
+
+/// Job records in redis, kept for `ttl` seconds after their last change. Records are
+/// updated from the streamed replies of the backend, see `shared_models::job`.
+#[derive(Clone)]
+pub struct JobStore {
+  redis: Redis,
+  ttl: u32,
+}
+
+impl JobStore {
+  pub fn new(redis: Redis, config: &config::Jobs) -> JobStore {
+    JobStore { redis, ttl: config.ttl }
+  }
+
+  pub async fn create(&self, tenant: &Tenant, model: &str, method: &str) -> Result<Job, String> {
+    let now = helpers::get_time();
+    let job = Job {
+      id: helpers::new_uuid(),
+      tenant: tenant.to_string(),
+      model: model.to_string(),
+      method: method.to_string(),
+      state: JobState::Queued,
+      status: None,
+      progress: None,
+      result: None,
+      error: None,
+      created_at: now,
+      updated_at: now,
+    };
+    let key = job::record_key(&job.id);
+    let now = now.to_string();
+    self
+      .redis
+      .query(&[
+        b"HSET",
+        key.as_bytes(),
+        b"id",
+        job.id.as_bytes(),
+        b"tenant",
+        job.tenant.as_bytes(),
+        b"model",
+        job.model.as_bytes(),
+        b"method",
+        job.method.as_bytes(),
+        b"state",
+        job.state.name().as_bytes(),
+        b"created_at",
+        now.as_bytes(),
+        b"updated_at",
+        now.as_bytes(),
+      ])
+      .await?;
+    self.redis.expire(&key, self.ttl).await?;
+    Ok(job)
+  }
+
+  /// The job with `id` if it exists and belongs to `tenant`.
+  pub async fn get(&self, id: &str, tenant: &Tenant) -> Result<Option<Job>, String> {
+    let fields = match self.redis.query(&[b"HGETALL", job::record_key(id).as_bytes()]).await? {
+      RespValue::Array(values) => values,
+      value => return Err(format!("unexpected redis reply {:?}", value)),
+    };
+    let mut record: HashMap<String, String> = HashMap::new();
+    for pair in fields.chunks(2) {
+      if let [RespValue::BulkString(field), RespValue::BulkString(value)] = pair {
+        record.insert(
+          String::from_utf8_lossy(field).to_string(),
+          String::from_utf8_lossy(value).to_string(),
+        );
+      }
+    }
+    if record.get("tenant").map(String::as_str) != Some(tenant.as_str()) {
+      return Ok(None);
+    }
+    let state = match record.get("state").and_then(|s| JobState::parse(s)) {
+      Some(state) => state,
+      None => return Err(format!("job {} has no valid state", id)),
+    };
+    let number = |field: &str| record.get(field).and_then(|v| v.parse::<u64>().ok());
+    Ok(Some(Job {
+      id: id.to_string(),
+      tenant: tenant.to_string(),
+      model: record.get("model").cloned().unwrap_or_default(),
+      method: record.get("method").cloned().unwrap_or_default(),
+      state,
+      status: number("status").map(|s| s as u16),
+      progress: record.get("progress").cloned(),
+      result: record.get("result").cloned(),
+      error: record.get("error").cloned(),
+      created_at: number("created_at").unwrap_or_default(),
+      updated_at: number("updated_at").unwrap_or_default(),
+    }))
+  }
+
+  /// Mark a job cancelled. Returns false when it already reached a final state.
+  pub async fn cancel(&self, id: &str) -> Result<bool, String> {
+    let args = self.transition(id, JobState::Cancelled, &[]);
+    let parts: Vec<&[u8]> = args.iter().map(|a| a.as_slice()).collect();
+    match self.redis.query(&parts).await? {
+      RespValue::Integer(changed) => Ok(changed == 1),
+      value => Err(format!("unexpected redis reply {:?}", value)),
+    }
+  }
+
+  /// Fold a streamed reply into the job record. Fire and forget, so it can be called from
+  /// the bus consumer thread.
+  pub fn apply(&self, id: &str, data: &[u8]) {
+    let msg: StreamMessage = match bincode::deserialize(data) {
+      Ok(msg) => msg,
+      Err(e) => {
+        error!("failed to decode job message, {}", e);
+        return;
+      }
+    };
+    debug!("job {}: {} message {}", id, msg.event.name(), msg.seq);
+    let status = msg.status.to_string();
+    let args = match msg.event {
+      StreamEvent::Progress => self.transition(id, JobState::Running, &[("progress", msg.payload.as_slice())]),
+      StreamEvent::Partial => self.transition(id, JobState::Running, &[]),
+      StreamEvent::Final => self.transition(
+        id,
+        JobState::Succeeded,
+        &[("status", status.as_bytes()), ("result", msg.payload.as_slice())],
+      ),
+      StreamEvent::Error => {
+        let error = serde_json::from_slice::<String>(&msg.payload)
+          .unwrap_or_else(|_| String::from_utf8_lossy(&msg.payload).to_string());
+        self.transition(
+          id,
+          JobState::Failed,
+          &[("status", status.as_bytes()), ("error", error.as_bytes())],
+        )
+      }
+    };
+    let parts: Vec<&[u8]> = args.iter().map(|a| a.as_slice()).collect();
+    self.redis.send(&parts);
+  }
+
+  fn transition(&self, id: &str, state: JobState, fields: &[(&str, &[u8])]) -> Vec<Vec<u8>> {
+    let mut args: Vec<Vec<u8>> = vec![
+      b"EVAL".to_vec(),
+      TRANSITION.as_bytes().to_vec(),
+      b"1".to_vec(),
+      job::record_key(id).into_bytes(),
+      state.name().as_bytes().to_vec(),
+      helpers::get_time().to_string().into_bytes(),
+      self.ttl.to_string().into_bytes(),
+    ];
+    for (field, value) in fields {
+      args.push(field.as_bytes().to_vec());
+      args.push(value.to_vec());
+    }
+    args
+  }
+}
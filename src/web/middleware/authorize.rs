@@ -0,0 +1,139 @@
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{
+  http::{header, Method, StatusCode},
+  web, Error, HttpResponse, ResponseError,
+};
+use futures::future::{ok, LocalBoxFuture, Ready};
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use std::{
+  cell::RefCell,
+  fmt,
+  rc::Rc,
+  task::{Context, Poll},
+};
+
+use super::super::auth;
+use super::super::router::routes::READINESS_PATH;
+use super::super::AppState;
+use crate::shared_models::audit;
+use crate::shared_models::tenant::Tenant;
+use crate::utils::policy::{Denial, Policy};
+
+/// Enforce the route rules of the access policy. Denied requests are answered with `403`
+/// and published as audit events.
+#[derive(Clone)]
+pub struct Authorize {
+  policy: Rc<Policy>,
+}
+
+impl Authorize {
+  pub fn new(policy: Policy) -> Authorize {
+    Authorize {
+      policy: Rc::new(policy),
+    }
+  }
+}
+
+impl<S, B> Transform<S> for Authorize
+where
+  S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+  S::Future: 'static,
+  B: 'static,
+{
+  type Request = ServiceRequest;
+  type Response = ServiceResponse<B>;
+  type Error = Error;
+  type InitError = ();
+  type Transform = AuthorizeMiddleware<S>;
+  type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+  fn new_transform(&self, service: S) -> Self::Future {
+    ok(AuthorizeMiddleware {
+      service: Rc::new(RefCell::new(service)),
+      policy: self.policy.clone(),
+    })
+  }
+}
+
+pub struct AuthorizeMiddleware<S> {
+  service: Rc<RefCell<S>>,
+  policy: Rc<Policy>,
+}
+
+impl<S, B> Service for AuthorizeMiddleware<S>
+where
+  S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+  S::Future: 'static,
+  B: 'static,
+{
+  type Request = ServiceRequest;
+  type Response = ServiceResponse<B>;
+  type Error = Error;
+  type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.service.borrow_mut().poll_ready(cx)
+  }
+
+  fn call(&mut self, req: ServiceRequest) -> Self::Future {
+    let service = self.service.clone();
+    let policy = self.policy.clone();
+    Box::pin(async move {
+      // cors preflights carry no credentials, they are answered by the cors middleware.
+      // Readiness probes carry none either
+      if !policy.enabled || is_preflight(&req) || req.path() == READINESS_PATH {
+        return service.borrow_mut().call(req).await;
+      }
+      let state = match req.app_data::<web::Data<AppState>>() {
+        Some(state) => state,
+        None => return service.borrow_mut().call(req).await,
+      };
+      let principal = auth::principal(&req, state.tenancy.jwt_secret());
+      let tenant = state.tenancy.resolve(&req).unwrap_or_else(|_| Tenant::public());
+      match policy.authorize_route(&principal, &tenant, req.method().as_str(), req.path()) {
+        Ok(()) => service.borrow_mut().call(req).await,
+        Err(denial) => {
+          warn!("{} for user {} on tenant {}", denial, principal.user_id, tenant);
+          let event = denial.audit("api_service", &principal, &tenant);
+          state
+            .mq
+            .clone()
+            .publish(audit::DENIED, serde_json::to_vec(&event).unwrap())
+            .await;
+          Err(Forbidden(denial).into())
+        }
+      }
+    })
+  }
+}
+
+fn is_preflight(req: &ServiceRequest) -> bool {
+  req.method() == Method::OPTIONS && req.headers().contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+#[derive(Debug)]
+pub struct Forbidden(pub Denial);
+
+impl fmt::Display for Forbidden {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.0)
+  }
+}
+
+impl ResponseError for Forbidden {
+  fn status_code(&self) -> StatusCode {
+    StatusCode::FORBIDDEN
+  }
+
+  fn error_response(&self) -> HttpResponse {
+    let body = object! {
+        error: 403,
+        reason: self.0.reason.code(),
+        message: self.0.to_string(),
+    };
+    HttpResponse::Forbidden()
+      .content_type("application/json")
+      .body(body.dump())
+  }
+}
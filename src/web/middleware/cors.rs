@@ -0,0 +1,193 @@
+use actix_cors::Cors;
+use actix_web::http::header;
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+
+use super::super::config;
+
+const RATE_LIMIT_HEADERS: [&str; 3] = ["x-ratelimit-limit", "x-ratelimit-remaining", "x-ratelimit-reset"];
+
+/// Build the CORS middleware wrapping the whole app. Origins are exact
+/// (`https://app.example.com`), wildcard subdomains (`https://*.example.com`) or `*` for any
+/// origin. The gateway's own origin is always allowed, browsers send it on form posts.
+pub fn cors(config: &config::Cors) -> Cors {
+  let mut cors = Cors::default()
+    .allowed_methods(config.allowed_methods.iter().map(String::as_str))
+    .expose_headers(
+      RATE_LIMIT_HEADERS
+        .iter()
+        .cloned()
+        .chain([header::ETAG.as_str(), header::RETRY_AFTER.as_str()].iter().cloned()),
+    )
+    .max_age(config.max_age);
+  if config.allowed_headers.iter().any(|h| h == "*") {
+    cors = cors.allow_any_header();
+  } else {
+    cors = cors.allowed_headers(config.allowed_headers.iter().map(String::as_str));
+  }
+  if config.allow_credentials {
+    cors = cors.supports_credentials();
+  }
+  if config.allowed_origins.iter().any(|o| o == "*") {
+    if config.allow_credentials {
+      warn!("cors allows credentials from any origin");
+    }
+    return cors.allow_any_origin();
+  }
+  let (wildcards, exact): (Vec<String>, Vec<String>) =
+    config.allowed_origins.iter().cloned().partition(|o| o.contains('*'));
+  for origin in exact.iter() {
+    cors = cors.allowed_origin(origin);
+  }
+  cors.allowed_origin_fn(move |origin, req| match origin.to_str() {
+    Ok(origin) => {
+      let host = req.headers().get(header::HOST).and_then(|h| h.to_str().ok());
+      same_origin(origin, host) || wildcards.iter().any(|pattern| matches_origin(pattern, origin))
+    }
+    Err(_) => false,
+  })
+}
+
+/// Whether `origin` names the gateway itself, as addressed by the `Host` header.
+fn same_origin(origin: &str, host: Option<&str>) -> bool {
+  match (origin.split_once("://"), host) {
+    (Some((_, origin_host)), Some(host)) => origin_host.eq_ignore_ascii_case(host),
+    _ => false,
+  }
+}
+
+/// `https://*.example.com` matches `https://app.example.com` and `https://a.b.example.com`
+/// but not `https://example.com` or `https://evil.com/.example.com`.
+fn matches_origin(pattern: &str, origin: &str) -> bool {
+  let (prefix, suffix) = match pattern.split_once('*') {
+    Some(parts) => parts,
+    None => return pattern == origin,
+  };
+  if origin.len() <= prefix.len() + suffix.len() || !origin.starts_with(prefix) || !origin.ends_with(suffix) {
+    return false;
+  }
+  let host = &origin[prefix.len()..origin.len() - suffix.len()];
+  !host.contains('/') && !host.contains(':') && !host.contains('@')
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use actix_web::{
+    http::{Method, StatusCode},
+    test, web, App, HttpResponse,
+  };
+
+  fn config() -> config::Cors {
+    config::Cors {
+      allowed_origins: vec![
+        "https://app.example.com".to_string(),
+        "https://*.example.com".to_string(),
+      ],
+      allow_credentials: true,
+      ..config::Cors::default()
+    }
+  }
+
+  #[test]
+  fn matches_exact_origins() {
+    assert!(matches_origin("https://app.example.com", "https://app.example.com"));
+    assert!(!matches_origin("https://app.example.com", "http://app.example.com"));
+    assert!(!matches_origin(
+      "https://app.example.com",
+      "https://app.example.com.evil.com"
+    ));
+  }
+
+  #[test]
+  fn matches_wildcard_subdomains() {
+    assert!(matches_origin("https://*.example.com", "https://app.example.com"));
+    assert!(matches_origin("https://*.example.com", "https://a.b.example.com"));
+    assert!(!matches_origin("https://*.example.com", "https://example.com"));
+    assert!(!matches_origin("https://*.example.com", "https://.example.com"));
+    assert!(!matches_origin("https://*.example.com", "http://app.example.com"));
+    assert!(!matches_origin(
+      "https://*.example.com",
+      "https://evil.com/.example.com"
+    ));
+    assert!(!matches_origin(
+      "https://*.example.com",
+      "https://evil.com:443.example.com"
+    ));
+    assert!(!matches_origin(
+      "https://*.example.com",
+      "https://user@evil.com.example.com"
+    ));
+  }
+
+  #[test]
+  fn recognises_the_own_origin() {
+    assert!(same_origin("https://gateway.local:8080", Some("gateway.local:8080")));
+    assert!(!same_origin("https://gateway.local", Some("gateway.local:8080")));
+    assert!(!same_origin("https://gateway.local", None));
+    assert!(!same_origin("null", Some("gateway.local")));
+  }
+
+  #[actix_rt::test]
+  async fn answers_preflight_of_allowed_origins() {
+    let mut app = test::init_service(
+      App::new()
+        .wrap(cors(&config()))
+        .route("/csrf", web::get().to(HttpResponse::Ok)),
+    )
+    .await;
+    let req = test::TestRequest::with_uri("/csrf")
+      .method(Method::OPTIONS)
+      .header(header::ORIGIN, "https://app.example.com")
+      .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+      .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "x-csrf-token")
+      .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+      res.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(),
+      "https://app.example.com"
+    );
+    assert_eq!(
+      res.headers().get(header::ACCESS_CONTROL_ALLOW_CREDENTIALS).unwrap(),
+      "true"
+    );
+  }
+
+  #[actix_rt::test]
+  async fn refuses_preflight_of_other_origins() {
+    let mut app = test::init_service(
+      App::new()
+        .wrap(cors(&config()))
+        .route("/csrf", web::get().to(HttpResponse::Ok)),
+    )
+    .await;
+    let req = test::TestRequest::with_uri("/csrf")
+      .method(Method::OPTIONS)
+      .header(header::ORIGIN, "https://evil.com")
+      .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+      .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert!(!res.status().is_success());
+    assert!(res.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+  }
+
+  #[actix_rt::test]
+  async fn exposes_responses_to_allowed_origins() {
+    let mut app = test::init_service(
+      App::new()
+        .wrap(cors(&config()))
+        .route("/csrf", web::get().to(HttpResponse::Ok)),
+    )
+    .await;
+    let req = test::TestRequest::with_uri("/csrf")
+      .header(header::ORIGIN, "https://app.example.com")
+      .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+      res.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(),
+      "https://app.example.com"
+    );
+  }
+}
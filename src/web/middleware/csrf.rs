@@ -0,0 +1,317 @@
+use actix_session::{Session, UserSession};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{
+  http::{header, Method, StatusCode},
+  web::{Bytes, BytesMut},
+  Error, HttpMessage, HttpResponse, ResponseError,
+};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use futures::StreamExt;
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use std::{
+  cell::RefCell,
+  collections::HashMap,
+  fmt,
+  rc::Rc,
+  task::{Context, Poll},
+};
+
+use super::super::auth::bearer_claims;
+use super::super::router::get_user_session;
+use crate::utils::helpers;
+
+pub const CSRF_FIELD: &str = "csrf_token";
+pub const CSRF_HEADER: &str = "x-csrf-token";
+const SESSION_KEY: &str = "csrf_token";
+const MAX_FORM_SIZE: usize = 65536;
+
+/// The synchronizer token of the session, created on first use. Templates render it with
+/// `{{ csrf_field(token=csrf_token) | safe }}`.
+pub fn token(session: &Session) -> String {
+  if let Ok(Some(token)) = session.get::<String>(SESSION_KEY) {
+    return token;
+  }
+  let token = helpers::new_uuid().replace('-', "");
+  if let Err(e) = session.set(SESSION_KEY, &token) {
+    error!("failed to store csrf token {}", e);
+  }
+  token
+}
+
+/// Tera function rendering the hidden form field carrying the csrf token.
+pub fn csrf_field(args: &HashMap<String, tera::Value>) -> tera::Result<tera::Value> {
+  match args.get("token").and_then(|t| t.as_str()) {
+    Some(token) if token.chars().all(|c| c.is_ascii_alphanumeric()) => Ok(tera::Value::String(format!(
+      "<input type=\"hidden\" name=\"{}\" value=\"{}\" />",
+      CSRF_FIELD, token
+    ))),
+    _ => Err("csrf_field expects a `token` argument".into()),
+  }
+}
+
+/// Reject unsafe requests on session cookie flows that do not echo the session's csrf token
+/// in the `X-CSRF-Token` header or the `csrf_token` form field. Requests authenticated with
+/// a valid bearer token and without a session do not rely on cookies and are exempt; with a
+/// session the caller acts as the session user, whatever bearer token comes along.
+#[derive(Clone, Default)]
+pub struct Csrf {
+  jwt_secret: Option<Rc<String>>,
+}
+
+impl Csrf {
+  pub fn new(jwt_secret: Option<String>) -> Csrf {
+    Csrf {
+      jwt_secret: jwt_secret.map(Rc::new),
+    }
+  }
+}
+
+impl<S, B> Transform<S> for Csrf
+where
+  S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+  S::Future: 'static,
+  B: 'static,
+{
+  type Request = ServiceRequest;
+  type Response = ServiceResponse<B>;
+  type Error = Error;
+  type InitError = ();
+  type Transform = CsrfMiddleware<S>;
+  type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+  fn new_transform(&self, service: S) -> Self::Future {
+    ok(CsrfMiddleware {
+      service: Rc::new(RefCell::new(service)),
+      jwt_secret: self.jwt_secret.clone(),
+    })
+  }
+}
+
+pub struct CsrfMiddleware<S> {
+  service: Rc<RefCell<S>>,
+  jwt_secret: Option<Rc<String>>,
+}
+
+impl<S, B> Service for CsrfMiddleware<S>
+where
+  S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+  S::Future: 'static,
+  B: 'static,
+{
+  type Request = ServiceRequest;
+  type Response = ServiceResponse<B>;
+  type Error = Error;
+  type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.service.borrow_mut().poll_ready(cx)
+  }
+
+  fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+    let service = self.service.clone();
+    let jwt_secret = self.jwt_secret.clone();
+    Box::pin(async move {
+      if is_safe(req.method()) || is_token_only(&req, jwt_secret.as_deref().map(String::as_str)) {
+        return service.borrow_mut().call(req).await;
+      }
+      let expected = match req.get_session().get::<String>(SESSION_KEY) {
+        Ok(Some(token)) => token,
+        _ => return Err(CsrfRejected("no csrf token in session").into()),
+      };
+      let submitted = match req.headers().get(CSRF_HEADER).and_then(|v| v.to_str().ok()) {
+        Some(token) => Some(token.to_string()),
+        None => form_token(&mut req).await?,
+      };
+      match submitted {
+        Some(token) if token.len() == expected.len() && openssl::memcmp::eq(token.as_bytes(), expected.as_bytes()) => {
+          service.borrow_mut().call(req).await
+        }
+        Some(_) => Err(CsrfRejected("invalid csrf token").into()),
+        None => Err(CsrfRejected("missing csrf token").into()),
+      }
+    })
+  }
+}
+
+fn is_safe(method: &Method) -> bool {
+  matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE)
+}
+
+/// Whether the caller is identified by a valid bearer token alone, so no cookie acts for it.
+fn is_token_only(req: &ServiceRequest, jwt_secret: Option<&str>) -> bool {
+  get_user_session(&req.get_session()).is_none() && bearer_claims(req.headers(), jwt_secret).is_some()
+}
+
+/// Read the token from an urlencoded form body and put the body back for the handler.
+async fn form_token(req: &mut ServiceRequest) -> Result<Option<String>, Error> {
+  let is_form = req
+    .headers()
+    .get(header::CONTENT_TYPE)
+    .and_then(|v| v.to_str().ok())
+    .map_or(false, |v| v.starts_with("application/x-www-form-urlencoded"));
+  if !is_form {
+    return Ok(None);
+  }
+  let mut payload = req.take_payload();
+  let mut body = BytesMut::new();
+  while let Some(chunk) = payload.next().await {
+    let chunk = chunk?;
+    if body.len() + chunk.len() > MAX_FORM_SIZE {
+      return Err(CsrfRejected("form too large").into());
+    }
+    body.extend_from_slice(&chunk);
+  }
+  let body: Bytes = body.freeze();
+  // tokens are alphanumeric so the raw value needs no percent decoding
+  let token = body
+    .split(|b| *b == b'&')
+    .filter_map(|pair| {
+      let mut parts = pair.splitn(2, |b| *b == b'=');
+      match (parts.next(), parts.next()) {
+        (Some(key), Some(value)) if key == CSRF_FIELD.as_bytes() => Some(String::from_utf8_lossy(value).to_string()),
+        _ => None,
+      }
+    })
+    .next();
+  let (_, mut restored) = actix_http::h1::Payload::create(true);
+  restored.unread_data(body);
+  req.set_payload(restored.into());
+  Ok(token)
+}
+
+#[derive(Debug)]
+pub struct CsrfRejected(&'static str);
+
+impl fmt::Display for CsrfRejected {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.0)
+  }
+}
+
+impl ResponseError for CsrfRejected {
+  fn status_code(&self) -> StatusCode {
+    StatusCode::FORBIDDEN
+  }
+
+  fn error_response(&self) -> HttpResponse {
+    let body = object! {
+        error: 403,
+        reason: "csrf",
+        message: self.0,
+    };
+    HttpResponse::Forbidden()
+      .content_type("application/json")
+      .body(body.dump())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::super::router::Cache;
+  use super::*;
+  use actix_session::CookieSession;
+  use actix_web::{cookie::Cookie, dev::Service, test, web, App};
+  use jsonwebtoken::{encode, EncodingKey, Header};
+
+  const SECRET: &str = "secret";
+
+  fn bearer() -> String {
+    let claims = serde_json::json!({
+      "sub": "api_client",
+      "company_id": "acme",
+      "exp": helpers::get_time() + 3600,
+    });
+    let token = encode(
+      &Header::default(),
+      &claims,
+      &EncodingKey::from_secret(SECRET.as_bytes()),
+    )
+    .unwrap();
+    format!("Bearer {}", token)
+  }
+
+  async fn login(session: Session) -> HttpResponse {
+    let user = Cache {
+      user_id: "1234567890".to_string(),
+      company_id: "acme".to_string(),
+      roles: vec!["member".to_string()],
+    };
+    session.set("session", bincode::serialize(&user).unwrap()).unwrap();
+    HttpResponse::Ok().body(token(&session))
+  }
+
+  async fn app() -> impl Service<Request = actix_http::Request, Response = ServiceResponse, Error = Error> {
+    test::init_service(
+      App::new()
+        .wrap(Csrf::new(Some(SECRET.to_string())))
+        .wrap(CookieSession::signed(&[0; 32]).secure(false))
+        .route("/login", web::get().to(login))
+        .route("/api/user", web::post().to(HttpResponse::Ok)),
+    )
+    .await
+  }
+
+  /// Log in and return the session cookie and the csrf token of the session.
+  async fn session(
+    app: &mut impl Service<Request = actix_http::Request, Response = ServiceResponse, Error = Error>,
+  ) -> (Cookie<'static>, String) {
+    let res = test::call_service(app, test::TestRequest::with_uri("/login").to_request()).await;
+    let cookie = res.response().cookies().next().unwrap().into_owned();
+    let token = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+    (cookie, token)
+  }
+
+  #[actix_rt::test]
+  async fn rejects_session_requests_with_a_forged_bearer_token() {
+    let mut app = app().await;
+    let (cookie, _) = session(&mut app).await;
+    let req = test::TestRequest::post()
+      .uri("/api/user")
+      .cookie(cookie)
+      .header(header::AUTHORIZATION, "Bearer x")
+      .to_request();
+    let err = app
+      .call(req)
+      .await
+      .err()
+      .expect("forged bearer token passed the csrf check");
+    assert_eq!(err.as_response_error().status_code(), StatusCode::FORBIDDEN);
+  }
+
+  #[actix_rt::test]
+  async fn rejects_session_requests_with_a_valid_bearer_token() {
+    let mut app = app().await;
+    let (cookie, _) = session(&mut app).await;
+    let req = test::TestRequest::post()
+      .uri("/api/user")
+      .cookie(cookie)
+      .header(header::AUTHORIZATION, bearer())
+      .to_request();
+    assert!(app.call(req).await.is_err());
+  }
+
+  #[actix_rt::test]
+  async fn accepts_bearer_token_requests_without_a_session() {
+    let mut app = app().await;
+    let req = test::TestRequest::post()
+      .uri("/api/user")
+      .header(header::AUTHORIZATION, bearer())
+      .to_request();
+    let res = app.call(req).await.ok().expect("bearer token request was rejected");
+    assert_eq!(res.status(), StatusCode::OK);
+  }
+
+  #[actix_rt::test]
+  async fn accepts_session_requests_echoing_the_token() {
+    let mut app = app().await;
+    let (cookie, token) = session(&mut app).await;
+    let req = test::TestRequest::post()
+      .uri("/api/user")
+      .cookie(cookie)
+      .header(CSRF_HEADER, token)
+      .to_request();
+    let res = app.call(req).await.ok().expect("csrf token was rejected");
+    assert_eq!(res.status(), StatusCode::OK);
+  }
+}
@@ -0,0 +1,5 @@
+pub mod authorize;
+pub mod cors;
+pub mod csrf;
+pub mod negotiate;
+pub mod rate_limit;
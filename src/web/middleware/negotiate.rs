@@ -0,0 +1,191 @@
+use actix_web::dev::{Body, ResponseBody, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{
+  error::InternalError,
+  http::{header, HeaderMap, HeaderValue, StatusCode},
+  web, Error, HttpRequest, HttpResponse,
+};
+use futures::future::{ok, LocalBoxFuture, Ready};
+#[allow(unused_imports)]
+use log::{debug, error, info};
+use std::{
+  cell::RefCell,
+  rc::Rc,
+  task::{Context, Poll},
+};
+use tera::Tera;
+
+use super::super::AppState;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+  Json,
+  Html,
+  Text,
+}
+
+/// Pick the response format from the `Accept` header, honouring quality values.
+/// Missing or wildcard headers get JSON.
+pub fn negotiate(headers: &HeaderMap) -> Format {
+  let accept = match headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()) {
+    Some(accept) => accept,
+    None => return Format::Json,
+  };
+  let mut ranges: Vec<(f32, &str)> = accept
+    .split(',')
+    .map(|range| {
+      let mut parts = range.split(';').map(str::trim);
+      let media = parts.next().unwrap_or("");
+      let quality = parts
+        .filter_map(|p| p.strip_prefix("q="))
+        .filter_map(|q| q.parse::<f32>().ok())
+        .next()
+        .unwrap_or(1.0);
+      (quality, media)
+    })
+    .filter(|(quality, _)| *quality > 0.0)
+    .collect();
+  // stable sort keeps the client's order between equal qualities
+  ranges.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+  for (_, media) in ranges {
+    match media {
+      "application/json" | "application/*" | "*/*" => return Format::Json,
+      "text/html" | "application/xhtml+xml" | "text/*" => return Format::Html,
+      "text/plain" => return Format::Text,
+      _ => continue,
+    }
+  }
+  Format::Json
+}
+
+/// An error response in the format the client asked for. HTML uses the `<status>.html`
+/// template when there is one and `error.html` otherwise.
+pub fn error_response(req: &HttpRequest, tmpl: Option<&Tera>, status: StatusCode, message: &str) -> HttpResponse {
+  let (content_type, body) = render(req.headers(), req.path(), tmpl, status, message);
+  HttpResponse::build(status).content_type(content_type).body(body)
+}
+
+fn render(
+  headers: &HeaderMap,
+  path: &str,
+  tmpl: Option<&Tera>,
+  status: StatusCode,
+  message: &str,
+) -> (&'static str, String) {
+  match (negotiate(headers), tmpl) {
+    (Format::Html, Some(tmpl)) => {
+      let mut ctx = tera::Context::new();
+      ctx.insert("status", &status.as_u16());
+      ctx.insert("reason", status.canonical_reason().unwrap_or(""));
+      ctx.insert("message", message);
+      ctx.insert("url", path);
+      let page = format!("{}.html", status.as_u16());
+      let template = if tmpl.get_template_names().any(|t| t == page) {
+        page.as_str()
+      } else {
+        "error.html"
+      };
+      match tmpl.render(template, &ctx) {
+        Ok(s) => ("text/html", s),
+        Err(e) => {
+          error!("failed to load template: {}", e);
+          text(status, message)
+        }
+      }
+    }
+    (Format::Html, None) | (Format::Text, _) => text(status, message),
+    (Format::Json, _) => {
+      let body = object! {
+          error: status.as_u16(),
+          message: message,
+          url: path,
+      };
+      ("application/json", body.dump())
+    }
+  }
+}
+
+fn text(status: StatusCode, message: &str) -> (&'static str, String) {
+  ("text/plain; charset=utf-8", format!("{}: {}", status, message))
+}
+
+/// Render every error response, including errors raised by inner middleware, in the format
+/// negotiated with the client. Successful responses are left alone.
+#[derive(Clone, Default)]
+pub struct Negotiate;
+
+impl<S, B> Transform<S> for Negotiate
+where
+  S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+  S::Future: 'static,
+  B: 'static,
+{
+  type Request = ServiceRequest;
+  type Response = ServiceResponse<B>;
+  type Error = Error;
+  type InitError = ();
+  type Transform = NegotiateMiddleware<S>;
+  type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+  fn new_transform(&self, service: S) -> Self::Future {
+    ok(NegotiateMiddleware {
+      service: Rc::new(RefCell::new(service)),
+    })
+  }
+}
+
+pub struct NegotiateMiddleware<S> {
+  service: Rc<RefCell<S>>,
+}
+
+impl<S, B> Service for NegotiateMiddleware<S>
+where
+  S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+  S::Future: 'static,
+  B: 'static,
+{
+  type Request = ServiceRequest;
+  type Response = ServiceResponse<B>;
+  type Error = Error;
+  type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.service.borrow_mut().poll_ready(cx)
+  }
+
+  fn call(&mut self, req: ServiceRequest) -> Self::Future {
+    let service = self.service.clone();
+    // an error leaves no request behind, so keep what rendering it takes
+    let (headers, path) = (req.headers().clone(), req.path().to_string());
+    let state = req.app_data::<web::Data<AppState>>().cloned();
+    Box::pin(async move {
+      let res = match service.borrow_mut().call(req).await {
+        Ok(res) => res,
+        Err(e) if negotiate(&headers) == Format::Json => return Err(e),
+        Err(e) => {
+          let status = e.as_response_error().status_code();
+          let message = e.to_string();
+          let (content_type, body) = render(&headers, &path, state.as_ref().map(|s| &s.tmpl), status, &message);
+          let response = HttpResponse::build(status).content_type(content_type).body(body);
+          return Err(InternalError::from_response(message, response).into());
+        }
+      };
+      let status = res.status();
+      let is_html = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .map_or(false, |v| v.as_bytes().starts_with(b"text/html"));
+      if !(status.is_client_error() || status.is_server_error()) || negotiate(&headers) == Format::Json || is_html {
+        return Ok(res);
+      }
+      let message = status.canonical_reason().unwrap_or("error").to_string();
+      let (content_type, body) = render(&headers, &path, state.as_ref().map(|s| &s.tmpl), status, &message);
+      Ok(res.map_body(|head, _| {
+        head.headers.remove(header::CONTENT_ENCODING);
+        head
+          .headers
+          .insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+        ResponseBody::Other(Body::from(body))
+      }))
+    })
+  }
+}
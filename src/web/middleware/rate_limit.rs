@@ -0,0 +1,274 @@
+use actix_session::UserSession;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{
+  http::{header, HeaderName, HeaderValue, StatusCode},
+  Error, HttpResponse, ResponseError,
+};
+use futures::future::{ok, LocalBoxFuture, Ready};
+#[allow(unused_imports)]
+use log::{debug, error, info};
+use std::{
+  cell::RefCell,
+  fmt,
+  net::IpAddr,
+  rc::Rc,
+  task::{Context, Poll},
+};
+
+use super::super::config::{RateLimit, RateLimitGroup, RateLimitKey};
+use super::super::redis::Redis;
+use super::super::router::get_user_session;
+use crate::utils::helpers;
+
+const KEY_PREFIX: &str = "rate_limit";
+const X_RATELIMIT_LIMIT: &str = "x-ratelimit-limit";
+const X_RATELIMIT_REMAINING: &str = "x-ratelimit-remaining";
+const X_RATELIMIT_RESET: &str = "x-ratelimit-reset";
+
+/// Increment the current window and read the previous one in a single round trip.
+const SLIDING_WINDOW_SCRIPT: &str = r#"
+local current = redis.call('INCR', KEYS[1])
+if current == 1 then
+  redis.call('EXPIRE', KEYS[1], ARGV[1] * 2)
+end
+local previous = tonumber(redis.call('GET', KEYS[2]) or '0')
+return {current, previous}
+"#;
+
+/// Sliding window rate limiter shared by every worker through Redis. Requests are matched
+/// to the first group whose path prefix matches; unmatched requests are not limited.
+/// When Redis is unavailable requests are let through.
+#[derive(Clone)]
+pub struct RateLimiter {
+  inner: Rc<Inner>,
+}
+
+struct Inner {
+  redis: Redis,
+  groups: Vec<RateLimitGroup>,
+  trusted_proxies: Vec<IpAddr>,
+}
+
+impl RateLimiter {
+  pub fn new(redis: Redis, config: RateLimit) -> RateLimiter {
+    RateLimiter {
+      inner: Rc::new(Inner {
+        redis,
+        groups: config.groups,
+        trusted_proxies: config.trusted_proxies,
+      }),
+    }
+  }
+}
+
+impl<S, B> Transform<S> for RateLimiter
+where
+  S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+  S::Future: 'static,
+  B: 'static,
+{
+  type Request = ServiceRequest;
+  type Response = ServiceResponse<B>;
+  type Error = Error;
+  type InitError = ();
+  type Transform = RateLimiterMiddleware<S>;
+  type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+  fn new_transform(&self, service: S) -> Self::Future {
+    ok(RateLimiterMiddleware {
+      service: Rc::new(RefCell::new(service)),
+      inner: self.inner.clone(),
+    })
+  }
+}
+
+pub struct RateLimiterMiddleware<S> {
+  service: Rc<RefCell<S>>,
+  inner: Rc<Inner>,
+}
+
+impl<S, B> Service for RateLimiterMiddleware<S>
+where
+  S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+  S::Future: 'static,
+  B: 'static,
+{
+  type Request = ServiceRequest;
+  type Response = ServiceResponse<B>;
+  type Error = Error;
+  type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.service.borrow_mut().poll_ready(cx)
+  }
+
+  fn call(&mut self, req: ServiceRequest) -> Self::Future {
+    let service = self.service.clone();
+    let inner = self.inner.clone();
+    Box::pin(async move {
+      let group = match inner.groups.iter().find(|g| req.path().starts_with(&g.path)) {
+        Some(group) => group,
+        None => return service.borrow_mut().call(req).await,
+      };
+      let client = client_key(&req, group, &inner.trusted_proxies);
+      let usage = match check(&inner.redis, group, &client).await {
+        Ok(usage) => usage,
+        Err(e) => {
+          error!("rate limiter unavailable: {}", e);
+          return service.borrow_mut().call(req).await;
+        }
+      };
+      if usage.used > usage.limit {
+        return Err(usage.into());
+      }
+      let mut res = service.borrow_mut().call(req).await?;
+      usage.set_headers(res.headers_mut());
+      Ok(res)
+    })
+  }
+}
+
+/// The limit state of one client after counting its current request.
+#[derive(Debug)]
+pub struct Usage {
+  limit: u64,
+  used: u64,
+  reset: u64,
+}
+
+impl Usage {
+  fn set_headers(&self, headers: &mut actix_web::http::HeaderMap) {
+    for (name, value) in [
+      (X_RATELIMIT_LIMIT, self.limit),
+      (X_RATELIMIT_REMAINING, self.limit.saturating_sub(self.used)),
+      (X_RATELIMIT_RESET, self.reset),
+    ]
+    .iter()
+    {
+      headers.insert(HeaderName::from_static(name), HeaderValue::from(*value));
+    }
+  }
+}
+
+impl fmt::Display for Usage {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "rate limit of {} requests exceeded, retry in {}s", self.limit, self.reset)
+  }
+}
+
+impl ResponseError for Usage {
+  fn status_code(&self) -> StatusCode {
+    StatusCode::TOO_MANY_REQUESTS
+  }
+
+  fn error_response(&self) -> HttpResponse {
+    let body = object! {
+        error: 429,
+        message: self.to_string(),
+    };
+    let mut res = HttpResponse::TooManyRequests()
+      .header(header::RETRY_AFTER, self.reset)
+      .content_type("application/json")
+      .body(body.dump());
+    self.set_headers(res.headers_mut());
+    res
+  }
+}
+
+async fn check(redis: &Redis, group: &RateLimitGroup, client: &str) -> Result<Usage, String> {
+  let now = helpers::get_time();
+  let window = group.window.max(1);
+  let current_window = now / window;
+  let elapsed = now % window;
+  let current_key = format!("{}:{}:{}:{}", KEY_PREFIX, group.name, client, current_window);
+  let previous_key = format!("{}:{}:{}:{}", KEY_PREFIX, group.name, client, current_window - 1);
+  let reply = redis
+    .query(&[
+      b"EVAL",
+      SLIDING_WINDOW_SCRIPT.as_bytes(),
+      b"2",
+      current_key.as_bytes(),
+      previous_key.as_bytes(),
+      window.to_string().as_bytes(),
+    ])
+    .await?;
+  let (current, previous) = match reply {
+    actix_redis::RespValue::Array(values) => match values.as_slice() {
+      [actix_redis::RespValue::Integer(c), actix_redis::RespValue::Integer(p)] => (*c as u64, *p as u64),
+      _ => return Err(format!("unexpected rate limit reply {:?}", values)),
+    },
+    value => return Err(format!("unexpected rate limit reply {:?}", value)),
+  };
+  // weight the previous window by how much of it still overlaps the sliding window
+  Ok(Usage {
+    limit: group.limit,
+    used: previous * (window - elapsed) / window + current,
+    reset: window - elapsed,
+  })
+}
+
+fn client_key(req: &ServiceRequest, group: &RateLimitGroup, trusted_proxies: &[IpAddr]) -> String {
+  let session = get_user_session(&req.get_session());
+  let key = match (&group.key, session) {
+    (RateLimitKey::User, Some(s)) => Some(format!("user:{}", s.user_id)),
+    (RateLimitKey::Tenant, Some(s)) => Some(format!("tenant:{}", s.company_id)),
+    _ => None,
+  };
+  key.unwrap_or_else(|| format!("ip:{}", client_ip(req, trusted_proxies)))
+}
+
+/// The peer address, or the client a trusted proxy forwarded the request for. Headers on
+/// other connections are ignored, any client could send a fresh address every time.
+fn client_ip(req: &ServiceRequest, trusted_proxies: &[IpAddr]) -> String {
+  let peer = match req.peer_addr() {
+    Some(addr) => addr.ip(),
+    None => return "unknown".to_string(),
+  };
+  if !trusted_proxies.contains(&peer) {
+    return peer.to_string();
+  }
+  let forwarded: Vec<&str> = req
+    .headers()
+    .get_all(HeaderName::from_static("x-forwarded-for"))
+    .filter_map(|v| v.to_str().ok())
+    .collect();
+  forwarded_client(&forwarded.join(","), trusted_proxies)
+    .unwrap_or(peer)
+    .to_string()
+}
+
+/// The last address of an `X-Forwarded-For` chain that is not a trusted proxy. Entries
+/// further left were added by the client itself and prove nothing.
+fn forwarded_client(chain: &str, trusted_proxies: &[IpAddr]) -> Option<IpAddr> {
+  for entry in chain.rsplit(',').map(str::trim) {
+    let ip = entry.parse::<IpAddr>().ok()?;
+    if !trusted_proxies.contains(&ip) {
+      return Some(ip);
+    }
+  }
+  None
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn ips(ips: &[&str]) -> Vec<IpAddr> {
+    ips.iter().map(|ip| ip.parse().unwrap()).collect()
+  }
+
+  #[test]
+  fn takes_the_address_the_trusted_proxy_saw() {
+    let proxies = ips(&["10.0.0.1", "10.0.0.2"]);
+    let client = forwarded_client("1.2.3.4, 5.6.7.8, 10.0.0.2", &proxies);
+    assert_eq!(client, Some("5.6.7.8".parse().unwrap()));
+  }
+
+  #[test]
+  fn ignores_chains_without_a_client() {
+    let proxies = ips(&["10.0.0.1"]);
+    assert_eq!(forwarded_client("10.0.0.1", &proxies), None);
+    assert_eq!(forwarded_client("", &proxies), None);
+    assert_eq!(forwarded_client("1.2.3.4, junk", &proxies), None);
+  }
+}
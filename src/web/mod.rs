@@ -1,13 +1,46 @@
+use crate::utils::circuit_breaker::Breakers;
+use crate::utils::file_store::FileStore;
+use crate::utils::policy::Policy;
 use crate::utils::rabbitmq::MqChannel;
+use crate::utils::retry::Retries;
 use prometheus::IntCounterVec;
+use std::collections::HashSet;
 use tera::Tera;
 
+use events::EventHub;
+use idempotency::Idempotency;
+use jobs::JobStore;
+use response_cache::ResponseCache;
+use streams::StreamStore;
+use tenant::Tenancy;
+
+mod auth;
 mod config;
+mod events;
+mod idempotency;
+mod jobs;
+mod middleware;
+mod redis;
+mod response_cache;
+mod streams;
 pub mod router;
 pub mod run;
+mod tenant;
 
 pub struct AppState {
   pub mq: MqChannel,
   pub tmpl: Tera,
   pub metrics: IntCounterVec,
+  pub models: HashSet<String>,
+  pub cache: ResponseCache,
+  pub tenancy: Tenancy,
+  pub policy: Policy,
+  pub events: EventHub,
+  pub websocket: config::Websocket,
+  pub streams: StreamStore,
+  pub jobs: JobStore,
+  pub idempotency: Idempotency,
+  pub breakers: Breakers,
+  pub retries: Retries,
+  pub files: Option<FileStore>,
 }
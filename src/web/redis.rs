@@ -0,0 +1,66 @@
+use actix::Addr;
+use actix_redis::{Command, RedisActor, RespValue};
+
+/// Thin wrapper around the shared `RedisActor` exposing the handful of commands the web
+/// server needs. Errors are flattened to strings like the rest of the bus layer.
+#[derive(Clone)]
+pub struct Redis {
+  addr: Addr<RedisActor>,
+}
+
+impl Redis {
+  pub fn start(host: &str, port: u16) -> Redis {
+    Redis {
+      addr: RedisActor::start(format!("{}:{}", host, port)),
+    }
+  }
+
+  pub async fn query(&self, parts: &[&[u8]]) -> Result<RespValue, String> {
+    match self.addr.send(command(parts)).await {
+      Ok(Ok(RespValue::Error(e))) => Err(e),
+      Ok(Ok(value)) => Ok(value),
+      Ok(Err(e)) => Err(e.to_string()),
+      Err(e) => Err(e.to_string()),
+    }
+  }
+
+  /// Fire and forget, usable from threads outside the actix system.
+  pub fn send(&self, parts: &[&[u8]]) {
+    self.addr.do_send(command(parts));
+  }
+
+  pub async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+    match self.query(&[b"GET", key.as_bytes()]).await? {
+      RespValue::BulkString(value) => Ok(Some(value)),
+      RespValue::Nil => Ok(None),
+      value => Err(format!("unexpected redis reply {:?}", value)),
+    }
+  }
+
+  pub async fn set_ex(&self, key: &str, value: &[u8], ttl: u32) -> Result<(), String> {
+    self
+      .query(&[b"SET", key.as_bytes(), value, b"EX", ttl.to_string().as_bytes()])
+      .await
+      .map(|_| ())
+  }
+
+  pub async fn incr(&self, key: &str) -> Result<i64, String> {
+    match self.query(&[b"INCR", key.as_bytes()]).await? {
+      RespValue::Integer(value) => Ok(value),
+      value => Err(format!("unexpected redis reply {:?}", value)),
+    }
+  }
+
+  pub async fn expire(&self, key: &str, ttl: u32) -> Result<(), String> {
+    self
+      .query(&[b"EXPIRE", key.as_bytes(), ttl.to_string().as_bytes()])
+      .await
+      .map(|_| ())
+  }
+}
+
+fn command(parts: &[&[u8]]) -> Command {
+  Command(RespValue::Array(
+    parts.iter().map(|p| RespValue::BulkString(p.to_vec())).collect(),
+  ))
+}
@@ -0,0 +1,106 @@
+use actix_web::HttpRequest;
+#[allow(unused_imports)]
+use log::{debug, error, info};
+use openssl::sha::sha256;
+use std::future::Future;
+
+use super::config::CacheRoute;
+use super::redis::Redis;
+use crate::shared_models::request_response::Response;
+use crate::utils::helpers;
+
+const KEY_PREFIX: &str = "response_cache";
+
+/// Opt-in cache for read-only routes. Entries are keyed by method, path, query and tenant
+/// under a per-model generation counter, so bumping the counter invalidates every cached
+/// read of that model without scanning keys.
+#[derive(Clone)]
+pub struct ResponseCache {
+  redis: Redis,
+  routes: Vec<CacheRoute>,
+}
+
+impl ResponseCache {
+  pub fn new(redis: Redis, routes: Vec<CacheRoute>) -> ResponseCache {
+    ResponseCache { redis, routes }
+  }
+
+  pub fn ttl_for(&self, path: &str) -> Option<u32> {
+    self.routes.iter().find(|r| path.starts_with(&r.path)).map(|r| r.ttl)
+  }
+
+  /// Serve `fetch` through the cache when the request path has a configured TTL.
+  /// Redis failures are logged and fall back to `fetch`.
+  pub async fn fetch<F>(&self, req: &HttpRequest, model: &str, tenant: &str, fetch: F) -> Response
+  where
+    F: Future<Output = Response>,
+  {
+    let ttl = match self.ttl_for(req.path()) {
+      Some(ttl) => ttl,
+      None => return fetch.await,
+    };
+    let key = match self.key(req, model, tenant).await {
+      Ok(key) => key,
+      Err(e) => {
+        error!("response cache unavailable: {}", e);
+        return fetch.await;
+      }
+    };
+    match self.redis.get(&key).await {
+      Ok(Some(data)) => match bincode::deserialize::<Response>(&data) {
+        Ok(res) => {
+          debug!("response cache hit {}", key);
+          return res;
+        }
+        Err(e) => error!("invalid response cache entry {}: {}", key, e),
+      },
+      Ok(None) => debug!("response cache miss {}", key),
+      Err(e) => error!("failed to read response cache: {}", e),
+    };
+    let res = fetch.await;
+    if res.status == 200 && res.error.is_none() {
+      if let Err(e) = self.redis.set_ex(&key, &bincode::serialize(&res).unwrap(), ttl).await {
+        error!("failed to write response cache: {}", e);
+      }
+    }
+    res
+  }
+
+  /// Drop every cached read of `model`. Safe to call from outside the actix system.
+  pub fn invalidate(&self, model: &str) {
+    debug!("invalidating response cache of {}", model);
+    self.redis.send(&[b"INCR", generation_key(model).as_bytes()]);
+  }
+
+  /// Drop every cached read of `model` before returning, so the writer's next read sees
+  /// its own write. Writes made elsewhere still arrive through `invalidate`.
+  pub async fn invalidate_now(&self, model: &str) {
+    debug!("invalidating response cache of {}", model);
+    if let Err(e) = self.redis.incr(&generation_key(model)).await {
+      error!("failed to invalidate response cache of {}: {}", model, e);
+    }
+  }
+
+  async fn key(&self, req: &HttpRequest, model: &str, tenant: &str) -> Result<String, String> {
+    let generation = match self.redis.get(&generation_key(model)).await? {
+      Some(g) => String::from_utf8_lossy(&g).to_string(),
+      None => "0".to_string(),
+    };
+    let digest = sha256(format!("{} {} {} {}", req.method(), req.path(), req.query_string(), tenant).as_bytes());
+    Ok(format!(
+      "{}:{}:{}:{}",
+      KEY_PREFIX,
+      model,
+      generation,
+      helpers::to_hex(&digest)
+    ))
+  }
+}
+
+pub fn etag(res: &Response) -> String {
+  format!("\"{}\"", helpers::to_hex(&sha256(&res.payload)[..16]))
+}
+
+fn generation_key(model: &str) -> String {
+  format!("{}:{}:generation", KEY_PREFIX, model)
+}
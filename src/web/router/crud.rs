@@ -0,0 +1,178 @@
+use super::super::AppState;
+use super::{cached_response, call_backend, error_response, list_query, response_to_http};
+use crate::shared_models::audit;
+use crate::shared_models::crud::{self, RecordId, Update};
+use crate::shared_models::principal::Principal;
+use crate::shared_models::request_response::{Request, Response};
+use crate::shared_models::tenant::Tenant;
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse};
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use serde_json::Value;
+
+pub fn dispatcher(app: &mut web::ServiceConfig) {
+  app.service(
+    web::resource("/{model}")
+      .route(web::get().to(list))
+      .route(web::post().to(create)),
+  );
+  app.service(
+    web::resource("/{model}/{id}")
+      .route(web::get().to(get))
+      .route(web::put().to(update))
+      .route(web::delete().to(delete)),
+  );
+}
+
+async fn list(
+  req: HttpRequest,
+  web::Path(model): web::Path<String>,
+  query: web::Query<Vec<(String, String)>>,
+  principal: Principal,
+  tenant: Tenant,
+  state: web::Data<AppState>,
+) -> HttpResponse {
+  let query = match list_query::parse(&query) {
+    Ok(query) => query,
+    Err(e) => return error_response(StatusCode::BAD_REQUEST, &e),
+  };
+  let payload = serde_json::to_vec(&query).unwrap();
+  if let Err(res) = authorize(&state, &principal, &tenant, &model, crud::LIST).await {
+    return response_to_http(res);
+  }
+  let fetch = send(&state, &principal, &tenant, model.clone(), crud::LIST, payload);
+  cached_response(&req, state.cache.fetch(&req, &model, tenant.as_str(), fetch).await)
+}
+
+async fn create(
+  req: HttpRequest,
+  web::Path(model): web::Path<String>,
+  body: web::Json<Value>,
+  principal: Principal,
+  tenant: Tenant,
+  state: web::Data<AppState>,
+) -> HttpResponse {
+  let payload = serde_json::to_vec(&body.into_inner()).unwrap();
+  let fetch = forward(&state, &principal, &tenant, model, crud::CREATE, payload.clone());
+  response_to_http(state.idempotency.run(&req, tenant.as_str(), &payload, fetch).await)
+}
+
+async fn get(
+  req: HttpRequest,
+  web::Path((model, id)): web::Path<(String, String)>,
+  principal: Principal,
+  tenant: Tenant,
+  state: web::Data<AppState>,
+) -> HttpResponse {
+  let payload = serde_json::to_vec(&RecordId { id }).unwrap();
+  if let Err(res) = authorize(&state, &principal, &tenant, &model, crud::GET).await {
+    return response_to_http(res);
+  }
+  let fetch = send(&state, &principal, &tenant, model.clone(), crud::GET, payload);
+  cached_response(&req, state.cache.fetch(&req, &model, tenant.as_str(), fetch).await)
+}
+
+async fn update(
+  req: HttpRequest,
+  web::Path((model, id)): web::Path<(String, String)>,
+  body: web::Json<Value>,
+  principal: Principal,
+  tenant: Tenant,
+  state: web::Data<AppState>,
+) -> HttpResponse {
+  let payload = serde_json::to_vec(&Update {
+    id,
+    data: body.into_inner(),
+  })
+  .unwrap();
+  let fetch = forward(&state, &principal, &tenant, model, crud::UPDATE, payload.clone());
+  response_to_http(state.idempotency.run(&req, tenant.as_str(), &payload, fetch).await)
+}
+
+async fn delete(
+  req: HttpRequest,
+  web::Path((model, id)): web::Path<(String, String)>,
+  principal: Principal,
+  tenant: Tenant,
+  state: web::Data<AppState>,
+) -> HttpResponse {
+  let payload = serde_json::to_vec(&RecordId { id }).unwrap();
+  let fetch = forward(&state, &principal, &tenant, model, crud::DELETE, payload.clone());
+  response_to_http(state.idempotency.run(&req, tenant.as_str(), &payload, fetch).await)
+}
+
+/// Check the access policy for `model.method`. Cached reads call this before looking at
+/// the cache and then `send`, everything else goes through `forward`.
+pub(super) async fn authorize(
+  state: &AppState,
+  principal: &Principal,
+  tenant: &Tenant,
+  model: &str,
+  method: &str,
+) -> Result<(), Response> {
+  if let Err(denial) = state.policy.authorize_method(principal, tenant, model, method) {
+    warn!("{} for user {} on tenant {}", denial, principal.user_id, tenant);
+    let event = denial.audit("api_service", principal, tenant);
+    state
+      .mq
+      .clone()
+      .publish(audit::DENIED, serde_json::to_vec(&event).unwrap())
+      .await;
+    return Err(Response::error("api_service", 403, denial.to_string()));
+  }
+  Ok(())
+}
+
+/// Authorize `model.method` and send it to the backend.
+async fn forward(
+  state: &AppState,
+  principal: &Principal,
+  tenant: &Tenant,
+  model: String,
+  method: &str,
+  payload: Vec<u8>,
+) -> Response {
+  if !state.models.contains(&model) {
+    return Response::error("api_service", 404, String::from("unknown model"));
+  }
+  if let Err(res) = authorize(state, principal, tenant, &model, method).await {
+    return res;
+  }
+  send(state, principal, tenant, model, method, payload).await
+}
+
+/// Send `model.method` to the backend, the caller has already authorized it.
+async fn send(
+  state: &AppState,
+  principal: &Principal,
+  tenant: &Tenant,
+  model: String,
+  method: &str,
+  payload: Vec<u8>,
+) -> Response {
+  if !state.models.contains(&model) {
+    return Response::error("api_service", 404, String::from("unknown model"));
+  }
+  if let Err(e) = state.tenancy.check_quota(tenant).await {
+    return Response::error("api_service", 429, e);
+  }
+  debug!("model: {}, method: {}, tenant: {}", model, method, tenant);
+  let key = format!("{}.request.{}", model, tenant);
+  let req = Request {
+    request_user: String::from("api_service"),
+    principal: principal.clone(),
+    tenant: tenant.clone(),
+    model: model.clone(),
+    method: method.to_string(),
+    payload,
+  };
+  let res = call_backend(state, &key, req).await;
+  state
+    .metrics
+    .with_label_values(&[&model, method, &res.status.to_string(), tenant.as_str()])
+    .inc();
+  if res.error.is_none() && matches!(method, crud::CREATE | crud::UPDATE | crud::DELETE) {
+    state.cache.invalidate_now(&model).await;
+  }
+  res
+}
@@ -1,10 +1,64 @@
+use super::super::middleware::csrf;
 use super::super::AppState;
-use super::{get_user_session, set_user_session, Cache};
+use super::crud::authorize;
+use super::{call_backend, error_response, get_user_session, response_to_http, set_user_session, Cache};
+use crate::shared_models::crud::{self, RecordId};
+use crate::shared_models::file::{self, FileRef};
+use crate::shared_models::principal::Principal;
+use crate::shared_models::request_response::{Request, Response};
+use crate::shared_models::tenant::Tenant;
+use crate::utils::claim_check::BlobStore;
+use crate::utils::file_store::FileStore;
+use crate::utils::helpers;
 
+use actix_multipart::{Field, Multipart};
 use actix_session::Session;
-use actix_web::{error, web, web::Data, Error, HttpResponse, Result};
+use actix_web::{
+  dev::BodyEncoding,
+  error,
+  error::BlockingError,
+  http::{
+    header::{self, Charset, ContentDisposition, ContentEncoding, DispositionParam, DispositionType, ExtendedValue},
+    StatusCode,
+  },
+  web::{self, Bytes, Data},
+  Error, HttpRequest, HttpResponse, Result,
+};
+use futures::{stream::unfold, StreamExt};
 use log::{debug, error, info};
-use std::collections::HashMap;
+use openssl::sha::Sha256;
+use serde_derive::Deserialize;
+use std::{collections::HashMap, sync::Arc};
+
+/// Optional hex encoded SHA-256 of an upload, checked once it is stored.
+const CHECKSUM_HEADER: &str = "x-checksum-sha256";
+/// Room for the multipart framing around the file in the length of an upload request.
+const MULTIPART_OVERHEAD: u64 = 64 * 1024;
+/// Downloads are read from the file store in pieces of this many bytes.
+const DOWNLOAD_CHUNK: u64 = 256 * 1024;
+/// Content types a browser may show in place. Anything else, html and svg above all,
+/// could run script in the origin of the gateway and is always sent as an attachment.
+const INLINE_TYPES: &[&str] = &[
+  "application/pdf",
+  "audio/mpeg",
+  "image/gif",
+  "image/jpeg",
+  "image/png",
+  "image/webp",
+  "text/plain",
+  "video/mp4",
+];
+
+/// File routes mounted under `/api`, before the model routes so `/files/{id}` is not
+/// taken for a model.
+pub fn dispatcher(app: &mut web::ServiceConfig) {
+  app.route("/files", web::post().to(upload));
+  app.service(
+    web::resource("/files/{id}")
+      .route(web::get().to(download))
+      .route(web::delete().to(delete)),
+  );
+}
 
 // store tera template in application state
 pub async fn file(
@@ -21,15 +75,30 @@ pub async fn file(
       let _s = Cache {
         user_id: "1234567890".to_string(),
         company_id: "qwertyuiop".to_string(),
+        roles: vec!["member".to_string()],
       };
-      match set_user_session(session, _s) {
+      match set_user_session(&session, _s) {
         Ok(_) => debug!("session saved"),
         Err(e) => error!("failed to save session {}", e),
       };
     }
   };
-  let s = if let Some(name) = query.get("name") {
-    let mut ctx = tera::Context::new();
+  render_page(&state, &session, query.get("name"))
+}
+
+// html form submissions, guarded by the csrf middleware
+pub async fn submit(
+  state: Data<AppState>,
+  form: web::Form<HashMap<String, String>>,
+  session: Session,
+) -> Result<HttpResponse, Error> {
+  render_page(&state, &session, form.get("name"))
+}
+
+fn render_page(state: &AppState, session: &Session, name: Option<&String>) -> Result<HttpResponse, Error> {
+  let mut ctx = tera::Context::new();
+  ctx.insert("csrf_token", &csrf::token(session));
+  let s = if let Some(name) = name {
     ctx.insert("name", &name.to_owned());
     ctx.insert("text", &"Welcome!".to_owned());
     state
@@ -39,8 +108,423 @@ pub async fn file(
   } else {
     state
       .tmpl
-      .render("index.html", &tera::Context::new())
+      .render("index.html", &ctx)
       .map_err(|_| error::ErrorInternalServerError("Template error"))?
   };
   Ok(HttpResponse::Ok().content_type("text/html").body(s))
 }
+
+/// Store the file of a `multipart/form-data` request and register it with the backend,
+/// answering `201 Created` with its `FileRef`. The content is streamed into the file
+/// store as it arrives and never held in memory as a whole. One file per request; other
+/// form fields are ignored.
+async fn upload(
+  req: HttpRequest,
+  mut payload: Multipart,
+  principal: Principal,
+  tenant: Tenant,
+  state: web::Data<AppState>,
+) -> HttpResponse {
+  let files = match &state.files {
+    Some(files) => files,
+    None => return error_response(StatusCode::SERVICE_UNAVAILABLE, "file uploads are not enabled"),
+  };
+  if let Err(res) = authorize(&state, &principal, &tenant, file::SUBJECT, crud::CREATE).await {
+    return response_to_http(res);
+  }
+  if let Err(e) = state.tenancy.check_quota(&tenant).await {
+    return error_response(StatusCode::TOO_MANY_REQUESTS, &e);
+  }
+  let declared = req
+    .headers()
+    .get(header::CONTENT_LENGTH)
+    .and_then(|v| v.to_str().ok())
+    .and_then(|v| v.parse::<u64>().ok());
+  if declared.map_or(false, |len| len > files.settings.max_size + MULTIPART_OVERHEAD) {
+    return error_response(StatusCode::PAYLOAD_TOO_LARGE, "file too large");
+  }
+
+  let mut stored: Option<FileRef> = None;
+  while let Some(field) = payload.next().await {
+    let field = match field {
+      Ok(field) => field,
+      Err(e) => return discard(files, stored, StatusCode::BAD_REQUEST, &e.to_string()).await,
+    };
+    let name = field
+      .content_disposition()
+      .and_then(|cd| cd.get_filename().map(file_name));
+    match (name, stored.is_some()) {
+      (Some(_), true) => return discard(files, stored, StatusCode::BAD_REQUEST, "one file per upload").await,
+      (Some(name), false) => match store(files, field, name).await {
+        Ok(file) => stored = Some(file),
+        Err((status, message)) => return error_response(status, &message),
+      },
+      (None, _) => skip(field).await,
+    }
+  }
+  let file = match stored {
+    Some(file) => file,
+    None => return error_response(StatusCode::BAD_REQUEST, "no file in upload"),
+  };
+  let expected = req
+    .headers()
+    .get(CHECKSUM_HEADER)
+    .and_then(|v| v.to_str().ok())
+    .map(|v| v.trim().to_ascii_lowercase());
+  if expected.map_or(false, |sha256| sha256 != file.sha256) {
+    return discard(files, Some(file), StatusCode::UNPROCESSABLE_ENTITY, "checksum mismatch").await;
+  }
+
+  let res = call_files(
+    &state,
+    principal,
+    &tenant,
+    crud::CREATE,
+    serde_json::to_vec(&file).unwrap(),
+  )
+  .await;
+  if res.error.is_some() {
+    // the backend does not know the file, so nobody could ever delete its content
+    if let Err(e) = delete_blob(&files.store, &file.blob).await {
+      error!("unable to remove the content of rejected upload {}: {}", file.id, e);
+    }
+    return response_to_http(res);
+  }
+  HttpResponse::Created()
+    .header(header::LOCATION, format!("/api/files/{}", file.id))
+    .content_type("application/json")
+    .body(res.payload)
+}
+
+/// Stream one file field into the file store, enforcing the size and type limits. The
+/// writer removes what it wrote when it is dropped unfinished, also when the client goes
+/// away in the middle of the upload.
+async fn store(files: &FileStore, mut field: Field, name: String) -> Result<FileRef, (StatusCode, String)> {
+  let content_type = field.content_type().essence_str().to_string();
+  if !files.allows(&content_type) {
+    return Err((
+      StatusCode::UNSUPPORTED_MEDIA_TYPE,
+      format!("{} files are not accepted", content_type),
+    ));
+  }
+  let unavailable = |e: String| {
+    error!("unable to store upload: {}", e);
+    (StatusCode::SERVICE_UNAVAILABLE, String::from("unable to store file"))
+  };
+  let blob = helpers::new_uuid();
+  let (store, id) = (files.store.clone(), blob.clone());
+  let mut writer = blocking(move || store.create(&id)).await.map_err(unavailable)?;
+  let mut hasher = Sha256::new();
+  let mut size = 0;
+  while let Some(chunk) = field.next().await {
+    let chunk = chunk.map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    size += chunk.len() as u64;
+    if size > files.settings.max_size {
+      return Err((StatusCode::PAYLOAD_TOO_LARGE, String::from("file too large")));
+    }
+    hasher.update(&chunk);
+    writer = blocking(move || writer.write(&chunk).map(|_| writer))
+      .await
+      .map_err(unavailable)?;
+  }
+  blocking(move || writer.finish()).await.map_err(unavailable)?;
+  debug!("stored upload {} of {} bytes as {}", name, size, blob);
+  Ok(FileRef {
+    id: helpers::new_uuid(),
+    blob,
+    name,
+    content_type,
+    size,
+    sha256: helpers::to_hex(&hasher.finish()),
+    created_at: 0,
+  })
+}
+
+async fn skip(mut field: Field) {
+  while let Some(Ok(_)) = field.next().await {}
+}
+
+/// Remove the content of an upload that is not kept and answer with an error.
+async fn discard(files: &FileStore, stored: Option<FileRef>, status: StatusCode, message: &str) -> HttpResponse {
+  if let Some(file) = stored {
+    if let Err(e) = delete_blob(&files.store, &file.blob).await {
+      error!("unable to remove the content of discarded upload {}: {}", file.id, e);
+    }
+  }
+  error_response(status, message)
+}
+
+async fn delete_blob(store: &Arc<dyn BlobStore>, blob: &str) -> Result<(), String> {
+  let (store, blob) = (store.clone(), blob.to_string());
+  blocking(move || store.delete(&blob)).await
+}
+
+/// Run blocking file store I/O on the thread pool of actix, off the async workers.
+async fn blocking<T, F>(f: F) -> Result<T, String>
+where
+  T: Send + 'static,
+  F: FnOnce() -> Result<T, String> + Send + 'static,
+{
+  web::block(f).await.map_err(|e| match e {
+    BlockingError::Error(e) => e,
+    BlockingError::Canceled => String::from("blocking task canceled"),
+  })
+}
+
+/// The last path segment of a client supplied file name, without characters that could
+/// break out of a `Content-Disposition` header.
+fn file_name(name: &str) -> String {
+  let name = name.rsplit(|c| c == '/' || c == '\\').next().unwrap_or_default();
+  let name: String = name.chars().filter(|c| !c.is_control() && *c != '"').collect();
+  if name.is_empty() {
+    String::from("file")
+  } else {
+    name
+  }
+}
+
+#[derive(Deserialize)]
+struct DownloadQuery {
+  /// Shown by the browser instead of saved, for the content types in `INLINE_TYPES`.
+  #[serde(default)]
+  inline: bool,
+}
+
+/// Serve the content of a file. A single byte range is answered with `206 Partial
+/// Content`; several ranges at once are answered with the whole file.
+async fn download(
+  req: HttpRequest,
+  web::Path(id): web::Path<String>,
+  query: web::Query<DownloadQuery>,
+  principal: Principal,
+  tenant: Tenant,
+  state: web::Data<AppState>,
+) -> HttpResponse {
+  let files = match &state.files {
+    Some(files) => files,
+    None => return error_response(StatusCode::SERVICE_UNAVAILABLE, "file downloads are not enabled"),
+  };
+  let res = call_files(
+    &state,
+    principal,
+    &tenant,
+    crud::GET,
+    serde_json::to_vec(&RecordId { id }).unwrap(),
+  )
+  .await;
+  if res.error.is_some() {
+    return response_to_http(res);
+  }
+  let file: FileRef = match serde_json::from_slice(&res.payload) {
+    Ok(file) => file,
+    Err(e) => {
+      error!("invalid file from backend, {}", e);
+      return error_response(StatusCode::BAD_GATEWAY, "invalid response from backend");
+    }
+  };
+  let range = match req.headers().get(header::RANGE).and_then(|v| v.to_str().ok()) {
+    Some(value) => match byte_range(value, file.size) {
+      Ok(range) => range,
+      Err(()) => {
+        return HttpResponse::RangeNotSatisfiable()
+          .header(header::CONTENT_RANGE, format!("bytes */{}", file.size))
+          .finish()
+      }
+    },
+    None => None,
+  };
+  let (start, end) = range.unwrap_or((0, file.size));
+
+  let disposition = ContentDisposition {
+    disposition: if query.inline && shown_inline(&file.content_type) {
+      DispositionType::Inline
+    } else {
+      DispositionType::Attachment
+    },
+    parameters: file_name_params(&file.name),
+  };
+  let mut resp = match range {
+    Some(_) => HttpResponse::PartialContent(),
+    None => HttpResponse::Ok(),
+  };
+  if range.is_some() {
+    resp.header(
+      header::CONTENT_RANGE,
+      format!("bytes {}-{}/{}", start, end - 1, file.size),
+    );
+  }
+  resp
+    // the length of what is sent, the whole file unless a range was asked for
+    .no_chunking(end - start)
+    // compressing would change the length and the meaning of the range
+    .encoding(ContentEncoding::Identity)
+    .header(header::ACCEPT_RANGES, "bytes")
+    .header(header::ETAG, format!("\"{}\"", file.sha256))
+    // the content type is the uploader's word, the browser must neither guess another
+    // one nor run what it shows
+    .header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+    .header(header::CONTENT_SECURITY_POLICY, "sandbox")
+    .set(disposition)
+    .content_type(file.content_type.as_str());
+
+  let store = files.store.clone();
+  let blob = file.blob;
+  resp.streaming(Box::pin(unfold(start, move |offset| {
+    let (store, blob) = (store.clone(), blob.clone());
+    async move {
+      if offset >= end {
+        return None;
+      }
+      let len = DOWNLOAD_CHUNK.min(end - offset);
+      let id = blob.clone();
+      match blocking(move || store.read_range(&id, offset, len)).await {
+        Ok(Some(data)) if !data.is_empty() => {
+          let next = offset + data.len() as u64;
+          Some((Ok::<_, Error>(Bytes::from(data)), next))
+        }
+        Ok(_) => {
+          error!("content of blob {} ended at {} of {}", blob, offset, end);
+          None
+        }
+        Err(e) => {
+          error!("unable to read blob {}: {}", blob, e);
+          Some((Err(error::ErrorInternalServerError("unable to read file")), end))
+        }
+      }
+    }
+  })))
+}
+
+fn shown_inline(content_type: &str) -> bool {
+  INLINE_TYPES.iter().any(|t| t.eq_ignore_ascii_case(content_type))
+}
+
+/// `filename` with the name as far as it is ASCII, which every client understands, and
+/// `filename*` with the whole name for names that are not.
+fn file_name_params(name: &str) -> Vec<DispositionParam> {
+  if name.is_ascii() {
+    return vec![DispositionParam::Filename(name.to_string())];
+  }
+  let ascii = name.chars().map(|c| if c.is_ascii() { c } else { '_' }).collect();
+  vec![
+    DispositionParam::Filename(ascii),
+    DispositionParam::FilenameExt(ExtendedValue {
+      charset: Charset::Ext(String::from("UTF-8")),
+      language_tag: None,
+      value: name.as_bytes().to_vec(),
+    }),
+  ]
+}
+
+/// The byte range asked for by a `Range` header as `[start, end)`. `Ok(None)` for headers
+/// answered with the whole file, like several ranges at once, `Err` when the range lies
+/// outside of the file.
+fn byte_range(value: &str, size: u64) -> Result<Option<(u64, u64)>, ()> {
+  let spec = match value.trim().strip_prefix("bytes=") {
+    Some(spec) if !spec.contains(',') => spec,
+    _ => return Ok(None),
+  };
+  let (first, last) = match spec.split_once('-') {
+    Some((first, last)) => (first.trim(), last.trim()),
+    None => return Ok(None),
+  };
+  let range = match (first.parse::<u64>(), last.parse::<u64>()) {
+    // the last `last` bytes
+    (Err(_), Ok(0)) if first.is_empty() => return Err(()),
+    (Err(_), Ok(suffix)) if first.is_empty() => (size.saturating_sub(suffix), size),
+    (Ok(start), Err(_)) if last.is_empty() => (start, size),
+    (Ok(start), Ok(last)) if start <= last => (start, (last + 1).min(size)),
+    _ => return Ok(None),
+  };
+  if range.0 >= size {
+    return Err(());
+  }
+  Ok(Some(range))
+}
+
+/// Forget a file and remove its content.
+async fn delete(
+  web::Path(id): web::Path<String>,
+  principal: Principal,
+  tenant: Tenant,
+  state: web::Data<AppState>,
+) -> HttpResponse {
+  let payload = serde_json::to_vec(&RecordId { id }).unwrap();
+  response_to_http(call_files(&state, principal, &tenant, crud::DELETE, payload).await)
+}
+
+async fn call_files(
+  state: &AppState,
+  principal: Principal,
+  tenant: &Tenant,
+  method: &str,
+  payload: Vec<u8>,
+) -> Response {
+  if let Err(res) = authorize(state, &principal, tenant, file::SUBJECT, method).await {
+    return res;
+  }
+  let key = format!("{}.request.{}", file::SUBJECT, tenant);
+  let req = Request {
+    request_user: String::from("api_service"),
+    principal,
+    tenant: tenant.clone(),
+    model: file::SUBJECT.to_string(),
+    method: method.to_string(),
+    payload,
+  };
+  let res = call_backend(state, &key, req).await;
+  state
+    .metrics
+    .with_label_values(&[file::SUBJECT, method, &res.status.to_string(), tenant.as_str()])
+    .inc();
+  res
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn byte_ranges() {
+    assert_eq!(byte_range("bytes=0-99", 1000), Ok(Some((0, 100))));
+    assert_eq!(byte_range("bytes=500-", 1000), Ok(Some((500, 1000))));
+    assert_eq!(byte_range("bytes=-100", 1000), Ok(Some((900, 1000))));
+    assert_eq!(byte_range("bytes=-2000", 1000), Ok(Some((0, 1000))));
+    assert_eq!(byte_range("bytes=900-2000", 1000), Ok(Some((900, 1000))));
+    assert_eq!(byte_range(" bytes=0-0 ", 1000), Ok(Some((0, 1))));
+  }
+
+  #[test]
+  fn whole_file_for_ranges_it_does_not_serve() {
+    assert_eq!(byte_range("bytes=0-1,5-6", 1000), Ok(None));
+    assert_eq!(byte_range("items=0-1", 1000), Ok(None));
+    assert_eq!(byte_range("bytes=5", 1000), Ok(None));
+    assert_eq!(byte_range("bytes=9-5", 1000), Ok(None));
+    assert_eq!(byte_range("bytes=a-b", 1000), Ok(None));
+  }
+
+  #[test]
+  fn ranges_outside_of_the_file_are_not_satisfiable() {
+    assert_eq!(byte_range("bytes=1000-", 1000), Err(()));
+    assert_eq!(byte_range("bytes=1000-1001", 1000), Err(()));
+    assert_eq!(byte_range("bytes=-0", 1000), Err(()));
+    assert_eq!(byte_range("bytes=0-", 0), Err(()));
+  }
+
+  #[test]
+  fn only_safe_types_are_shown_inline() {
+    assert!(shown_inline("image/png"));
+    assert!(shown_inline("Application/PDF"));
+    assert!(!shown_inline("text/html"));
+    assert!(!shown_inline("image/svg+xml"));
+    assert!(!shown_inline("application/xhtml+xml"));
+  }
+
+  #[test]
+  fn names_outside_of_ascii_get_an_extended_file_name() {
+    assert_eq!(file_name_params("report.pdf").len(), 1);
+    let params = file_name_params("résumé.pdf");
+    assert_eq!(params[0], DispositionParam::Filename(String::from("r_sum_.pdf")));
+    assert!(matches!(&params[1], DispositionParam::FilenameExt(v) if v.value == "résumé.pdf".as_bytes()));
+  }
+}
@@ -0,0 +1,111 @@
+use super::super::jobs::Job;
+use super::super::AppState;
+use super::{error_response, streams};
+use crate::shared_models::job;
+use crate::shared_models::principal::Principal;
+use crate::shared_models::tenant::Tenant;
+use actix_web::{
+  http::{header, StatusCode},
+  web, HttpResponse,
+};
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+
+pub fn dispatcher(app: &mut web::ServiceConfig) {
+  app.route("/jobs/{model}/{method}", web::post().to(enqueue));
+  app.service(
+    web::resource("/jobs/{id}")
+      .route(web::get().to(get))
+      .route(web::delete().to(cancel)),
+  );
+}
+
+/// Enqueue a backend call and answer `202 Accepted` with the job record right away.
+async fn enqueue(
+  web::Path((model, method)): web::Path<(String, String)>,
+  body: Option<web::Json<serde_json::Value>>,
+  principal: Principal,
+  tenant: Tenant,
+  state: web::Data<AppState>,
+) -> HttpResponse {
+  if let Err(res) = streams::check(&state, &principal, &tenant, &model, &method).await {
+    return res;
+  }
+  let job = match state.jobs.create(&tenant, &model, &method).await {
+    Ok(job) => job,
+    Err(e) => {
+      error!("failed to create job, {}", e);
+      return error_response(StatusCode::SERVICE_UNAVAILABLE, "unable to create job");
+    }
+  };
+  streams::publish(&state, principal, &tenant, &model, &method, body, &job.id, job::KIND).await;
+  HttpResponse::Accepted()
+    .header(header::LOCATION, format!("/api/jobs/{}", job.id))
+    .content_type("application/json")
+    .body(to_json(&job).dump())
+}
+
+async fn get(web::Path(id): web::Path<String>, tenant: Tenant, state: web::Data<AppState>) -> HttpResponse {
+  match find(&state, &id, &tenant).await {
+    Ok(job) => HttpResponse::Ok()
+      .content_type("application/json")
+      .body(to_json(&job).dump()),
+    Err(res) => res,
+  }
+}
+
+/// Cancel a job that has not finished yet. A running job is asked to stop and a queued
+/// one is skipped by the backend that picks it up; messages sent after that no longer
+/// change the record.
+async fn cancel(web::Path(id): web::Path<String>, tenant: Tenant, state: web::Data<AppState>) -> HttpResponse {
+  let job = match find(&state, &id, &tenant).await {
+    Ok(job) => job,
+    Err(res) => return res,
+  };
+  match state.jobs.cancel(&job.id).await {
+    Ok(true) => (),
+    Ok(false) => return error_response(StatusCode::CONFLICT, "job already finished"),
+    Err(e) => {
+      error!("failed to cancel job {}, {}", id, e);
+      return error_response(StatusCode::SERVICE_UNAVAILABLE, "unable to cancel job");
+    }
+  }
+  state.mq.clone().publish(job::CANCEL, job.id.as_bytes().to_vec()).await;
+  match find(&state, &id, &tenant).await {
+    Ok(job) => HttpResponse::Ok()
+      .content_type("application/json")
+      .body(to_json(&job).dump()),
+    Err(res) => res,
+  }
+}
+
+async fn find(state: &AppState, id: &str, tenant: &Tenant) -> Result<Job, HttpResponse> {
+  match state.jobs.get(id, tenant).await {
+    Ok(Some(job)) => Ok(job),
+    Ok(None) => Err(error_response(StatusCode::NOT_FOUND, "unknown job")),
+    Err(e) => {
+      error!("failed to load job {}, {}", id, e);
+      Err(error_response(StatusCode::SERVICE_UNAVAILABLE, "unable to load job"))
+    }
+  }
+}
+
+fn to_json(job: &Job) -> json::JsonValue {
+  let payload = |p: &Option<String>| {
+    p.as_deref()
+      .and_then(|p| json::parse(p).ok())
+      .unwrap_or(json::JsonValue::Null)
+  };
+  object! {
+      id: job.id.clone(),
+      model: job.model.clone(),
+      method: job.method.clone(),
+      state: job.state.name(),
+      status: job.status,
+      progress: payload(&job.progress),
+      result: payload(&job.result),
+      error: job.error.clone(),
+      created_at: job.created_at,
+      updated_at: job.updated_at,
+  }
+}
@@ -0,0 +1,127 @@
+use crate::shared_models::list::{Filter, FilterOp, ListQuery, Sort, SortDirection};
+
+/// Translate a query string of the form
+/// `?limit=20&cursor=..&sort=-name,created_at&filter[name]=bob&filter[created_at][gte]=1600000000`
+/// into a `ListQuery`. Unrelated parameters are ignored.
+pub fn parse(pairs: &[(String, String)]) -> Result<ListQuery, String> {
+  let mut query = ListQuery::default();
+  for (key, value) in pairs {
+    match key.as_str() {
+      "limit" => {
+        query.limit = value
+          .parse()
+          .map_err(|_| format!("limit must be a positive integer, got {}", value))?
+      }
+      "cursor" => query.cursor = Some(value.clone()),
+      "sort" => {
+        for field in value.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+          query.sort.push(parse_sort(field));
+        }
+      }
+      k if k.starts_with("filter[") => query.filters.push(parse_filter(k, value)?),
+      _ => {}
+    }
+  }
+  Ok(query)
+}
+
+fn parse_sort(field: &str) -> Sort {
+  match field.strip_prefix('-') {
+    Some(f) => Sort {
+      field: f.to_string(),
+      direction: SortDirection::Desc,
+    },
+    None => Sort {
+      field: field.trim_start_matches('+').to_string(),
+      direction: SortDirection::Asc,
+    },
+  }
+}
+
+/// `filter[field]` filters on equality, `filter[field][op]` uses one of the `FilterOp`s.
+/// Fields of the model data compare as text, so `filter[price][gt]=9` does not match "10".
+fn parse_filter(key: &str, value: &str) -> Result<Filter, String> {
+  let parts: Vec<&str> = key
+    .trim_start_matches("filter")
+    .split(']')
+    .filter(|p| !p.is_empty())
+    .map(|p| p.trim_start_matches('['))
+    .collect();
+  let (field, op) = match parts.as_slice() {
+    [field] => (field, FilterOp::Eq),
+    [field, op] => (
+      field,
+      FilterOp::parse(op).ok_or_else(|| format!("unknown filter operator {}", op))?,
+    ),
+    _ => return Err(format!("invalid filter {}", key)),
+  };
+  if field.is_empty() {
+    return Err(format!("invalid filter {}", key));
+  }
+  Ok(Filter {
+    field: field.to_string(),
+    op,
+    value: value.to_string(),
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+    pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+  }
+
+  #[test]
+  fn parses_every_parameter() {
+    let query = parse(&pairs(&[
+      ("limit", "5"),
+      ("cursor", "abc"),
+      ("sort", "-name, +email,created_at"),
+      ("filter[name]", "bob"),
+      ("filter[created_at][gte]", "1600000000"),
+      ("page", "3"),
+    ]))
+    .unwrap();
+    assert_eq!(query.limit, 5);
+    assert_eq!(query.cursor.as_deref(), Some("abc"));
+    let sort: Vec<(&str, SortDirection)> = query.sort.iter().map(|s| (s.field.as_str(), s.direction)).collect();
+    assert_eq!(
+      sort,
+      vec![
+        ("name", SortDirection::Desc),
+        ("email", SortDirection::Asc),
+        ("created_at", SortDirection::Asc)
+      ]
+    );
+    assert_eq!(
+      query.filters,
+      vec![
+        Filter {
+          field: "name".to_string(),
+          op: FilterOp::Eq,
+          value: "bob".to_string(),
+        },
+        Filter {
+          field: "created_at".to_string(),
+          op: FilterOp::Gte,
+          value: "1600000000".to_string(),
+        },
+      ]
+    );
+  }
+
+  #[test]
+  fn defaults_without_parameters() {
+    assert_eq!(parse(&[]).unwrap(), ListQuery::default());
+  }
+
+  #[test]
+  fn rejects_invalid_parameters() {
+    assert!(parse(&pairs(&[("limit", "-1")])).is_err());
+    assert!(parse(&pairs(&[("filter[name][like]", "bob")])).is_err());
+    assert!(parse(&pairs(&[("filter[]", "bob")])).is_err());
+    assert!(parse(&pairs(&[("filter[a][eq][b]", "bob")])).is_err());
+  }
+}
@@ -1,28 +1,57 @@
 use actix_session::Session;
-use actix_web::Error;
+use actix_web::{
+  http::{header, HeaderValue, StatusCode},
+  Error, HttpRequest, HttpResponse,
+};
 #[allow(unused_imports)]
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
-use std::{fmt, str};
+use std::{fmt, str, time::Duration};
 
+use super::response_cache;
+use super::AppState;
+use crate::shared_models::request_response::{Request, Response};
+use crate::utils::helpers;
+use crate::utils::retry::{self, ErrorClass};
+
+mod crud;
 mod file_server;
+mod jobs;
+mod list_query;
 pub mod routes;
+mod streams;
+mod websocket;
+
+const RPC_TIMEOUT: u64 = 20;
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
-struct Cache {
-  user_id: String,
-  company_id: String,
+pub(crate) struct Cache {
+  pub(crate) user_id: String,
+  pub(crate) company_id: String,
+  pub(crate) roles: Vec<String>,
 }
 
 impl fmt::Display for Cache {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    write!(f, "user_id: {}, company_id: {}", self.user_id, self.company_id)
+    write!(
+      f,
+      "user_id: {}, company_id: {}, roles: {}",
+      self.user_id,
+      self.company_id,
+      self.roles.join(",")
+    )
   }
 }
 
-fn get_user_session(session: &Session) -> Option<Cache> {
+pub(crate) fn get_user_session(session: &Session) -> Option<Cache> {
   match session.get::<Vec<u8>>("session") {
-    Ok(data) => data.map(|x| bincode::deserialize(&x).unwrap()),
+    Ok(data) => data.and_then(|x| match bincode::deserialize(&x) {
+      Ok(cache) => Some(cache),
+      Err(e) => {
+        error!("error decoding cache value {}", e);
+        None
+      }
+    }),
     Err(e) => {
       error!("error retrieving cache value {}", e);
       None
@@ -30,7 +59,117 @@ fn get_user_session(session: &Session) -> Option<Cache> {
   }
 }
 
-fn set_user_session(session: Session, data: Cache) -> Result<(), Error> {
+fn set_user_session(session: &Session, data: Cache) -> Result<(), Error> {
   let encoded: Vec<u8> = bincode::serialize(&data).unwrap();
   session.set("session", encoded)
 }
+
+/// Send a request to the backend over the bus, retrying idempotent methods as the retry
+/// policy of `key` allows within `RPC_TIMEOUT`. Transport failures are returned as error
+/// responses, and calls refused by the circuit breaker of `key` fail fast with `503`.
+async fn call_backend(state: &AppState, key: &str, req: Request) -> Response {
+  let policy = state.retries.policy(key, &req.method);
+  let data: Vec<u8> = bincode::serialize(&req).unwrap();
+  // shared by every attempt, so the backend handles the request once
+  let message_id = helpers::new_uuid();
+  let correlation_id = helpers::new_uuid();
+  let (message_id, correlation_id) = (message_id.as_str(), correlation_id.as_str());
+  retry::with_retries(
+    state.mq.runtime(),
+    policy,
+    Duration::from_secs(RPC_TIMEOUT),
+    move |timeout| call_once(state, key, data.clone(), message_id, correlation_id, timeout),
+  )
+  .await
+}
+
+async fn call_once(
+  state: &AppState,
+  key: &str,
+  data: Vec<u8>,
+  message_id: &str,
+  correlation_id: &str,
+  timeout: Duration,
+) -> (Response, Option<ErrorClass>) {
+  let permit = match state.breakers.acquire(key) {
+    Ok(permit) => permit,
+    Err(rejected) => {
+      warn!("not calling {}: {}", key, rejected);
+      let res = Response::error("api_service", 503, format!("backend unavailable, {}", rejected));
+      return (res, Some(ErrorClass::Unavailable));
+    }
+  };
+  let mut mq = state.mq.clone();
+  match mq
+    .request_reply_attempt(key, data, message_id, correlation_id, timeout)
+    .await
+  {
+    Ok(data) => match bincode::deserialize::<Response>(&data) {
+      Ok(res) => {
+        if res.status >= 500 {
+          permit.error();
+        } else {
+          permit.success();
+        }
+        let class = ErrorClass::of_status(res.status);
+        (res, class)
+      }
+      Err(e) => {
+        permit.error();
+        error!("failed to decode rpc response, {}", e);
+        let res = Response::error("api_service", 502, String::from("invalid response from backend"));
+        (res, Some(ErrorClass::ServerError))
+      }
+    },
+    Err(e) => {
+      permit.timeout();
+      error!("failed to execute rpc, {}", e);
+      let res = Response::error("api_service", 504, String::from("backend did not respond"));
+      (res, Some(ErrorClass::Timeout))
+    }
+  }
+}
+
+/// Like `response_to_http` for cacheable reads: successful responses carry an `ETag` and
+/// a matching `If-None-Match` is answered with `304 Not Modified`.
+fn cached_response(req: &HttpRequest, res: Response) -> HttpResponse {
+  if res.status != 200 || res.error.is_some() {
+    return response_to_http(res);
+  }
+  let etag = response_cache::etag(&res);
+  let not_modified = req
+    .headers()
+    .get(header::IF_NONE_MATCH)
+    .and_then(|v| v.to_str().ok())
+    .map(|v| v.split(',').any(|t| t.trim() == etag || t.trim() == "*"))
+    .unwrap_or(false);
+  let mut resp = if not_modified {
+    HttpResponse::NotModified().finish()
+  } else {
+    response_to_http(res)
+  };
+  if let Ok(value) = HeaderValue::from_str(&etag) {
+    resp.headers_mut().insert(header::ETAG, value);
+  }
+  resp
+}
+
+fn response_to_http(res: Response) -> HttpResponse {
+  let status = StatusCode::from_u16(res.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+  match res.error {
+    Some(e) => error_response(status, &e),
+    None => HttpResponse::build(status)
+      .content_type("application/json")
+      .body(res.payload),
+  }
+}
+
+fn error_response(status: StatusCode, message: &str) -> HttpResponse {
+  let body = object! {
+      error: status.as_u16(),
+      message: message,
+  };
+  HttpResponse::build(status)
+    .content_type("application/json")
+    .body(body.dump())
+}
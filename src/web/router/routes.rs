@@ -1,6 +1,11 @@
+use super::super::middleware::csrf;
 use super::super::AppState;
-use super::file_server;
-use crate::shared_models::request_response::{Request, Response};
+use super::{call_backend, crud, file_server, jobs, response_to_http, streams, websocket};
+use crate::shared_models::principal::Principal;
+use crate::shared_models::request_response::Request;
+use crate::shared_models::tenant::Tenant;
+use crate::utils::circuit_breaker::State;
+use actix_session::Session;
 use actix_web::{web, HttpResponse, Responder};
 #[allow(unused_imports)]
 use log::{debug, error, info};
@@ -9,32 +14,75 @@ use std::str;
 pub fn dispatcher(app: &mut web::ServiceConfig) {
   app.route("/curl/{id}", web::get().to(curl_test));
   app.route("/", web::get().to(file_server::file));
+  app.route("/", web::post().to(file_server::submit));
+  app.route("/csrf", web::get().to(csrf_token));
+  app.route("/ws", web::get().to(websocket::connect));
+  app.route(READINESS_PATH, web::get().to(readiness));
 }
 
-async fn curl_test(web::Path(id): web::Path<String>, state: web::Data<AppState>) -> impl Responder {
+/// Readiness probe, answered without authorization.
+pub const READINESS_PATH: &str = "/ready";
+
+/// Routes mounted under the `/api` scope.
+pub fn api_dispatcher(app: &mut web::ServiceConfig) {
+  // registered first so /streams/{id}, /jobs/{id} and /files/{id} are not taken for a model
+  streams::dispatcher(app);
+  jobs::dispatcher(app);
+  file_server::dispatcher(app);
+  crud::dispatcher(app);
+}
+
+// lets script clients fetch the token they must send in the X-CSRF-Token header
+async fn csrf_token(session: Session) -> impl Responder {
+  let body = object! {
+      csrf_token: csrf::token(&session),
+  };
+  HttpResponse::Ok().content_type("application/json").body(body.dump())
+}
+
+async fn curl_test(
+  web::Path(id): web::Path<String>,
+  principal: Principal,
+  tenant: Option<Tenant>,
+  state: web::Data<AppState>,
+) -> impl Responder {
   debug!("model: {:?}", &id);
-  state.metrics.with_label_values(&["endpoint", "method", "status"]).inc();
+  let tenant = tenant.unwrap_or_else(Tenant::public);
+  state
+    .metrics
+    .with_label_values(&["endpoint", "method", "status", tenant.as_str()])
+    .inc();
+  let key = format!("backend.request.{}", tenant);
   let _req = Request {
     request_user: String::from("api_service"),
+    principal,
+    tenant,
     model: String::from("backend"),
     method: String::from("fetch"),
     payload: id.as_bytes().to_vec(),
   };
-  let req: Vec<u8> = bincode::serialize(&_req).unwrap();
-  match state
-    .mq
-    .clone()
-    .request_reply_with_timeout("backend.request", req, 20)
-    .await
-  {
-    Ok(data) => {
-      let res: Response = bincode::deserialize(&data).unwrap();
-      info!("{:?}", res);
-      HttpResponse::Ok().json(str::from_utf8(&res.payload).unwrap())
-    }
-    Err(e) => {
-      error!("failed to erecute rpc, {}", e);
-      HttpResponse::Ok().json("error")
-    }
+  let res = call_backend(&state, &key, _req).await;
+  info!("{:?}", res);
+  match res.error {
+    None => HttpResponse::Ok().json(str::from_utf8(&res.payload).unwrap_or_default()),
+    Some(_) => response_to_http(res),
   }
 }
+
+/// Ready as long as the gateway serves requests. Open circuits are reported in the body
+/// and the `rpc_circuit_state` gauge only: every gateway sees the same failing backend, so
+/// taking them out of the load balancer would turn a partial outage into a full one.
+async fn readiness(state: web::Data<AppState>) -> HttpResponse {
+  let circuits = state.breakers.states();
+  let mut body = json::JsonValue::new_object();
+  for (key, circuit) in &circuits {
+    body[key.as_str()] = circuit.name().into();
+  }
+  let degraded = circuits.iter().any(|(_, circuit)| *circuit == State::Open);
+  let body = object! {
+      ready: true,
+      degraded: degraded,
+      circuits: body,
+  };
+  HttpResponse::Ok().content_type("application/json").body(body.dump())
+}
@@ -0,0 +1,208 @@
+use super::super::streams::{ReadError, StreamStore};
+use super::super::AppState;
+use super::{crud, error_response, response_to_http};
+use crate::shared_models::job;
+use crate::shared_models::lane::Lane;
+use crate::shared_models::principal::Principal;
+use crate::shared_models::request_response::{Request, Response};
+use crate::shared_models::stream::{self, StreamMessage};
+use crate::shared_models::tenant::Tenant;
+use actix_web::{
+  http::{header, StatusCode},
+  rt::time::delay_for,
+  web::{self, Bytes},
+  Error, HttpRequest, HttpResponse,
+};
+use futures::stream::unfold;
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use std::time::Duration;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Polls without a message after which a keep-alive comment is sent.
+const KEEP_ALIVE_POLLS: u32 = 150;
+
+pub fn dispatcher(app: &mut web::ServiceConfig) {
+  app.route("/streams/{model}/{method}", web::post().to(start));
+  app.route("/streams/{id}", web::get().to(events));
+}
+
+/// Start a streaming backend operation. The body is the request payload, e.g. the list
+/// query of an `export`. Answers `202 Accepted` with the URL of the event stream.
+async fn start(
+  web::Path((model, method)): web::Path<(String, String)>,
+  body: Option<web::Json<serde_json::Value>>,
+  principal: Principal,
+  tenant: Tenant,
+  state: web::Data<AppState>,
+) -> HttpResponse {
+  if let Err(res) = check(&state, &principal, &tenant, &model, &method).await {
+    return res;
+  }
+  let id = match state.streams.open(&tenant).await {
+    Ok(id) => id,
+    Err(e) => {
+      error!("unable to open stream: {}", e);
+      return error_response(StatusCode::SERVICE_UNAVAILABLE, "streams unavailable");
+    }
+  };
+  publish(&state, principal, &tenant, &model, &method, body, &id, stream::KIND).await;
+  let url = format!("/api/streams/{}", id);
+  let body = object! {
+      stream_id: id,
+      url: url.clone(),
+  };
+  HttpResponse::Accepted()
+    .header(header::LOCATION, url)
+    .content_type("application/json")
+    .body(body.dump())
+}
+
+/// The checks `forward` does for plain requests: known model, access policy and quota.
+pub(super) async fn check(
+  state: &AppState,
+  principal: &Principal,
+  tenant: &Tenant,
+  model: &str,
+  method: &str,
+) -> Result<(), HttpResponse> {
+  if !state.models.contains(model) {
+    return Err(error_response(StatusCode::NOT_FOUND, "unknown model"));
+  }
+  crud::authorize(state, principal, tenant, model, method)
+    .await
+    .map_err(response_to_http)?;
+  if let Err(e) = state.tenancy.check_quota(tenant).await {
+    return Err(response_to_http(Response::error("api_service", 429, e)));
+  }
+  Ok(())
+}
+
+/// Publish a streaming request of `kind` whose replies carry `id`.
+#[allow(clippy::too_many_arguments)]
+pub(super) async fn publish(
+  state: &AppState,
+  principal: Principal,
+  tenant: &Tenant,
+  model: &str,
+  method: &str,
+  body: Option<web::Json<serde_json::Value>>,
+  id: &str,
+  kind: &str,
+) {
+  debug!(
+    "{} {}: model: {}, method: {}, tenant: {}",
+    kind, id, model, method, tenant
+  );
+  let payload = match body {
+    Some(body) => serde_json::to_vec(&body.into_inner()).unwrap(),
+    None => Vec::new(),
+  };
+  let req = Request {
+    request_user: String::from("api_service"),
+    principal,
+    tenant: tenant.clone(),
+    model: model.to_string(),
+    method: method.to_string(),
+    payload,
+  };
+  let key = format!("{}.request.{}", model, tenant);
+  let mut mq = state.mq.clone();
+  // nobody waits on a job, so it must not hold up interactive requests
+  if kind == job::KIND {
+    mq.use_lane(Lane::Batch);
+  }
+  mq.publish_stream_request(&key, bincode::serialize(&req).unwrap(), id, kind)
+    .await;
+  state
+    .metrics
+    .with_label_values(&[model, method, "202", tenant.as_str()])
+    .inc();
+}
+
+/// Serve the messages of a stream as server-sent events until its final message. Clients
+/// resume with `Last-Event-ID` while the stream is still buffered.
+async fn events(
+  req: HttpRequest,
+  web::Path(id): web::Path<String>,
+  tenant: Tenant,
+  state: web::Data<AppState>,
+) -> HttpResponse {
+  let after = match req.headers().get("Last-Event-ID").map(|v| v.to_str()) {
+    None => 0,
+    Some(Ok(v)) => match v.trim().parse::<u64>() {
+      Ok(after) => after,
+      Err(_) => return error_response(StatusCode::BAD_REQUEST, "invalid Last-Event-ID"),
+    },
+    Some(Err(_)) => return error_response(StatusCode::BAD_REQUEST, "invalid Last-Event-ID"),
+  };
+  match state.streams.read(&id, &tenant, after).await {
+    Ok(_) => (),
+    Err(ReadError::NotFound) => return error_response(StatusCode::NOT_FOUND, "unknown stream"),
+    Err(ReadError::Gone) => return error_response(StatusCode::GONE, "stream position no longer available"),
+    Err(ReadError::Unavailable(e)) => {
+      error!("unable to read stream {}: {}", id, e);
+      return error_response(StatusCode::SERVICE_UNAVAILABLE, "streams unavailable");
+    }
+  }
+  let cursor = Cursor {
+    store: state.streams.clone(),
+    id,
+    tenant,
+    after,
+    idle: 0,
+  };
+  HttpResponse::Ok()
+    .content_type("text/event-stream")
+    .header(header::CACHE_CONTROL, "no-cache")
+    // keeps the compression middleware from buffering events
+    .header(header::CONTENT_ENCODING, "identity")
+    .streaming(Box::pin(unfold(Some(cursor), next_events)))
+}
+
+struct Cursor {
+  store: StreamStore,
+  id: String,
+  tenant: Tenant,
+  after: u64,
+  idle: u32,
+}
+
+async fn next_events(cursor: Option<Cursor>) -> Option<(Result<Bytes, Error>, Option<Cursor>)> {
+  let mut cursor = cursor?;
+  loop {
+    let messages = match cursor.store.read(&cursor.id, &cursor.tenant, cursor.after).await {
+      Ok(messages) => messages,
+      Err(e) => {
+        warn!("stream {} ended unexpectedly: {:?}", cursor.id, e);
+        return None;
+      }
+    };
+    if let Some(last) = messages.last() {
+      cursor.after = last.seq;
+      cursor.idle = 0;
+      let done = messages.iter().any(|m| m.event.is_last());
+      let body: String = messages.iter().map(frame).collect();
+      return Some((Ok(Bytes::from(body)), if done { None } else { Some(cursor) }));
+    }
+    if cursor.idle >= KEEP_ALIVE_POLLS {
+      cursor.idle = 0;
+      return Some((Ok(Bytes::from_static(b": keep-alive\n\n")), Some(cursor)));
+    }
+    cursor.idle += 1;
+    delay_for(POLL_INTERVAL).await;
+  }
+}
+
+fn frame(msg: &StreamMessage) -> String {
+  let data = String::from_utf8_lossy(&msg.payload);
+  let mut frame = format!("id: {}\nevent: {}\n", msg.seq, msg.event.name());
+  for line in data.lines() {
+    frame.push_str(&format!("data: {}\n", line));
+  }
+  if data.is_empty() {
+    frame.push_str("data: \n");
+  }
+  frame.push('\n');
+  frame
+}
@@ -0,0 +1,198 @@
+use super::super::events::{self, BusEvent, EventHub};
+use super::super::middleware::negotiate;
+use super::super::AppState;
+use crate::shared_models::crud;
+use crate::shared_models::principal::{Principal, ANONYMOUS};
+use crate::shared_models::tenant::Tenant;
+use crate::utils::policy::Policy;
+use actix::{Actor, ActorContext, AsyncContext, Handler, StreamHandler};
+use actix_web::{http::StatusCode, web, Error, HttpRequest, HttpResponse};
+use actix_web_actors::ws;
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+use std::{
+  collections::HashSet,
+  str,
+  time::{Duration, Instant},
+};
+
+/// Frames sent by the client, e.g. `{"action": "subscribe", "topic": "user.changed"}`.
+#[derive(Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+enum Command {
+  Subscribe { topic: String },
+  Unsubscribe { topic: String },
+}
+
+/// Upgrade an authenticated request to a websocket that receives the bus events of the
+/// caller's tenant.
+pub async fn connect(
+  req: HttpRequest,
+  stream: web::Payload,
+  principal: Principal,
+  tenant: Tenant,
+  state: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+  if principal.user_id == ANONYMOUS {
+    return Ok(negotiate::error_response(
+      &req,
+      Some(&state.tmpl),
+      StatusCode::UNAUTHORIZED,
+      "login required",
+    ));
+  }
+  let config = &state.websocket;
+  let session = WsSession {
+    id: None,
+    hub: state.events.clone(),
+    policy: state.policy.clone(),
+    principal,
+    tenant,
+    topics: HashSet::new(),
+    last_heartbeat: Instant::now(),
+    heartbeat_interval: Duration::from_secs(config.heartbeat_interval),
+    client_timeout: Duration::from_secs(config.client_timeout),
+    max_subscriptions: config.max_subscriptions,
+    buffer: config.buffer,
+  };
+  ws::start(session, &req, stream)
+}
+
+struct WsSession {
+  id: Option<usize>,
+  hub: EventHub,
+  policy: Policy,
+  principal: Principal,
+  tenant: Tenant,
+  topics: HashSet<String>,
+  last_heartbeat: Instant,
+  heartbeat_interval: Duration,
+  client_timeout: Duration,
+  max_subscriptions: usize,
+  buffer: usize,
+}
+
+impl WsSession {
+  fn heartbeat(&self, ctx: &mut ws::WebsocketContext<Self>) {
+    ctx.run_interval(self.heartbeat_interval, |session, ctx| {
+      if Instant::now().duration_since(session.last_heartbeat) > session.client_timeout {
+        debug!("websocket of user {} timed out", session.principal.user_id);
+        ctx.stop();
+        return;
+      }
+      ctx.ping(b"");
+    });
+  }
+
+  fn handle_command(&mut self, text: &str) -> Result<json::JsonValue, String> {
+    let id = self.id.ok_or_else(|| "not connected".to_string())?;
+    match serde_json::from_str(text).map_err(|e| e.to_string())? {
+      Command::Subscribe { topic } => {
+        if !events::valid_topic(&topic) {
+          return Err(format!("invalid topic {}", topic));
+        }
+        if !self.topics.contains(&topic) && self.topics.len() >= self.max_subscriptions {
+          return Err(format!("at most {} subscriptions allowed", self.max_subscriptions));
+        }
+        // receiving changes of a model requires read access to it
+        let model = topic.split('.').next().unwrap_or_default();
+        self
+          .policy
+          .authorize_method(&self.principal, &self.tenant, model, crud::GET)
+          .map_err(|denial| denial.to_string())?;
+        self.hub.subscribe(id, &topic);
+        self.topics.insert(topic.clone());
+        Ok(object! {
+            "type": "subscribed",
+            topic: topic,
+        })
+      }
+      Command::Unsubscribe { topic } => {
+        self.hub.unsubscribe(id, &topic);
+        self.topics.remove(&topic);
+        Ok(object! {
+            "type": "unsubscribed",
+            topic: topic,
+        })
+      }
+    }
+  }
+}
+
+impl Actor for WsSession {
+  type Context = ws::WebsocketContext<Self>;
+
+  fn started(&mut self, ctx: &mut Self::Context) {
+    // events beyond the buffer are dropped by the hub instead of piling up in memory
+    ctx.set_mailbox_capacity(self.buffer);
+    self.heartbeat(ctx);
+    self.id = Some(self.hub.connect(self.tenant.clone(), ctx.address().recipient()));
+  }
+
+  fn stopped(&mut self, _: &mut Self::Context) {
+    if let Some(id) = self.id {
+      self.hub.disconnect(id);
+    }
+  }
+}
+
+impl Handler<BusEvent> for WsSession {
+  type Result = ();
+
+  fn handle(&mut self, event: BusEvent, ctx: &mut Self::Context) {
+    let data = str::from_utf8(&event.data)
+      .ok()
+      .and_then(|s| json::parse(s).ok())
+      .unwrap_or_else(|| json::JsonValue::from(String::from_utf8_lossy(&event.data).to_string()));
+    let frame = object! {
+        "type": "event",
+        topic: event.topic,
+        data: data,
+        dropped: event.dropped,
+    };
+    ctx.text(frame.dump());
+  }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsSession {
+  fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+    let msg = match msg {
+      Ok(msg) => msg,
+      Err(e) => {
+        warn!("websocket protocol error: {}", e);
+        ctx.stop();
+        return;
+      }
+    };
+    match msg {
+      ws::Message::Ping(msg) => {
+        self.last_heartbeat = Instant::now();
+        ctx.pong(&msg);
+      }
+      ws::Message::Pong(_) => self.last_heartbeat = Instant::now(),
+      ws::Message::Text(text) => {
+        let reply = self.handle_command(&text).unwrap_or_else(|e| {
+          object! {
+              "type": "error",
+              message: e,
+          }
+        });
+        ctx.text(reply.dump());
+      }
+      ws::Message::Binary(_) => ctx.text(
+        object! {
+            "type": "error",
+            message: "binary frames are not supported",
+        }
+        .dump(),
+      ),
+      ws::Message::Close(reason) => {
+        ctx.close(reason);
+        ctx.stop();
+      }
+      ws::Message::Continuation(_) => ctx.stop(),
+      ws::Message::Nop => (),
+    }
+  }
+}
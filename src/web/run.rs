@@ -1,5 +1,5 @@
-use actix_redis::{RedisActor, RedisSession};
-use actix_web::http::header;
+use actix_redis::RedisSession;
+use actix_web::http::StatusCode;
 use actix_web::middleware::{Compress, Logger};
 use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
 use actix_web_prom::PrometheusMetrics;
@@ -8,20 +8,36 @@ use futures::executor::block_on;
 use log::{debug, error, info};
 use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
 use prometheus::{opts, IntCounterVec};
-use std::{
-  collections::{HashMap, HashSet},
-  sync::{Arc, RwLock},
-  thread,
-};
+use std::{collections::HashSet, sync::Arc, thread};
 use tera::Tera;
 
 use super::config;
+use super::events::EventHub;
+use super::idempotency::Idempotency;
+use super::jobs::JobStore;
+use super::middleware::authorize::Authorize;
+use super::middleware::cors::cors;
+use super::middleware::csrf::{self, Csrf};
+use super::middleware::negotiate::{self, Negotiate};
+use super::middleware::rate_limit::RateLimiter;
+use super::redis::Redis;
+use super::response_cache::ResponseCache;
+use super::streams::StreamStore;
 use super::router;
+use super::tenant::Tenancy;
 use super::AppState;
+use crate::shared_models::{job, stream};
+use crate::utils::circuit_breaker::Breakers;
+use crate::utils::claim_check::ClaimCheck;
+use crate::utils::file_store::FileStore;
 use crate::utils::rabbitmq::{MqChannel, MqLogin};
+use crate::utils::reply_store::ReplyStore;
+use crate::utils::runtime;
 
-const WEBSERVICE_KEY: &str = "reply.web_service";
+const WEBSERVICE_KEY: &str = job::REPLY_KEY;
+const MODEL_CHANGED_KEY: &str = "*.changed.*";
 
+/// Bound to the queue shared by every instance.
 const SERVICEKEYS: [&str; 1] = [WEBSERVICE_KEY];
 
 pub fn get_service_keys() -> HashSet<String> {
@@ -46,6 +62,11 @@ pub async fn start_server() -> std::io::Result<()> {
         .takes_value(true)
         .help("Relative location of config file"),
     )
+    .arg(
+      clap::Arg::with_name("dry-run")
+        .long("dry-run")
+        .help("Print how the broker differs from the configured topology and exit"),
+    )
     .get_matches();
   let _config = matches
     .value_of("config")
@@ -59,6 +80,8 @@ pub async fn start_server() -> std::io::Result<()> {
   let _mq_config = app_config.rabbit_mq;
   let _server_config = app_config.web_server.clone();
 
+  let prometheus = PrometheusMetrics::new("api", Some(&app_config.prometheus.endpoint), None);
+
   // Configure RabbitMq
   let rabbit_mq_login = MqLogin {
     user: _mq_config.user,
@@ -66,21 +89,49 @@ pub async fn start_server() -> std::io::Result<()> {
     host: _mq_config.host,
     port: _mq_config.port,
   };
-  let mq_data: HashMap<String, Vec<u8>> = HashMap::new();
-  let mq_messages = Arc::new(RwLock::new(mq_data));
-  let mut channel: MqChannel = MqChannel::connect(rabbit_mq_login, mq_messages);
-  channel.register_exchange(&_mq_config.exchange);
+  let replies = ReplyStore::new(app_config.replies.clone(), &prometheus.registry);
+  let mut channel: MqChannel = MqChannel::connect(rabbit_mq_login, replies, Arc::new(runtime::Actix));
+  let topology = app_config
+    .topology
+    .including(&_mq_config.exchange, &[_mq_config.queue.clone()]);
+  if matches.is_present("dry-run") {
+    for change in channel.diff_topology(&topology) {
+      println!("{}", change);
+    }
+    return Ok(());
+  }
+  channel.declare_topology(&topology);
+  channel.use_exchange(&_mq_config.exchange);
+  channel.use_claim_check(ClaimCheck::open(&app_config.claim_check, &app_config.psql.connection_string()));
+  let file_store = FileStore::open(&app_config.files, &app_config.psql.connection_string());
   channel.set_qos(_mq_config.prefetch);
-  channel.register_queue(&_mq_config.queue);
-  let routing_keys = get_service_keys();
-  channel.bind_queue(&_mq_config.queue, &_mq_config.exchange, &_mq_config.queue);
-  for key in routing_keys {
-    channel.bind_queue(&_mq_config.queue, &_mq_config.exchange, &key);
+  // work for the web service as a whole goes to one instance through the shared queue
+  let mut work_channel = channel.clone();
+  work_channel.use_queue(&_mq_config.queue);
+  for key in get_service_keys() {
+    work_channel.bind_queue(&_mq_config.queue, &_mq_config.exchange, &key);
+  }
+  // replies and events go to a queue of this instance, so replies reach the instance that
+  // asked and every instance sees every event
+  let instance_queue = channel.register_reply_queue(&topology.reply_queue(), &_mq_config.exchange);
+  channel.bind_queue(&instance_queue, &_mq_config.exchange, MODEL_CHANGED_KEY);
+  // events forwarded to websocket clients
+  for topic in &app_config.websocket.topics {
+    channel.bind_queue(&instance_queue, &_mq_config.exchange, topic);
+  }
+
+  // Redis Cache configuration
+  let redis = Redis::start(&_session_conf.host, _session_conf.port);
+  let response_cache = ResponseCache::new(redis.clone(), app_config.response_cache.routes.clone());
+  let rate_limit = app_config.rate_limit.clone();
+
+  let events = EventHub::default();
+  let streams = StreamStore::new(redis.clone(), &app_config.streams);
+  let jobs = JobStore::new(redis.clone(), &app_config.jobs);
+  for mut consuming_channel in vec![channel.clone(), work_channel] {
+    let on_message = bus_handler(response_cache.clone(), events.clone(), streams.clone(), jobs.clone());
+    thread::spawn(move || block_on(consuming_channel.start_consuming(on_message)));
   }
-  let mut consuming_channel = channel.clone();
-  thread::spawn(move || {
-    block_on(consuming_channel.start_consuming());
-  });
   info!("Rabbitmq loaded and ready");
 
   // configure tls
@@ -93,23 +144,39 @@ pub async fn start_server() -> std::io::Result<()> {
     .unwrap();
 
   // prometheus config
-  let prometheus = PrometheusMetrics::new("api", Some(&app_config.prometheus.endpoint), None);
   let counter_opts = opts!(
     &app_config.prometheus.metrics,
     &app_config.prometheus.description
   )
   .namespace("api");
-  let counter = IntCounterVec::new(counter_opts, &["endpoint", "method", "status"]).unwrap();
+  let counter = IntCounterVec::new(counter_opts, &["endpoint", "method", "status", "tenant"]).unwrap();
+  let tenancy_config = app_config.tenancy.clone();
+  let policy = app_config.policy.clone();
+  let idempotency = Idempotency::new(redis.clone(), &app_config.idempotency);
+  let websocket_config = app_config.websocket.clone();
+  let breakers = Breakers::new(app_config.circuit_breaker.clone(), &prometheus.registry);
+  let retries = app_config.retry.clone();
   // Run http server
   HttpServer::new(move || {
-    let tera = Tera::new(&_server_config.templates).unwrap();
-    // Redis Cache configuration
-    let redis_addr = RedisActor::start("127.0.0.1:6379");
+    let mut tera = Tera::new(&_server_config.templates).unwrap();
+    tera.register_function("csrf_field", csrf::csrf_field);
     // Configure App State
     let state = AppState {
       mq: channel.clone(),
       tmpl: tera,
       metrics: counter.clone(),
+      models: _server_config.models.iter().cloned().collect(),
+      cache: response_cache.clone(),
+      tenancy: Tenancy::new(tenancy_config.clone(), redis.clone()),
+      policy: policy.clone(),
+      events: events.clone(),
+      websocket: websocket_config.clone(),
+      streams: streams.clone(),
+      jobs: jobs.clone(),
+      idempotency: idempotency.clone(),
+      breakers: breakers.clone(),
+      retries: retries.clone(),
+      files: file_store.clone(),
     };
     // Configure Session
     let session = RedisSession::new(
@@ -123,11 +190,18 @@ pub async fn start_server() -> std::io::Result<()> {
       .wrap(Logger::default())
       .wrap(Logger::new("%a %{User-Agent}i"))
       .wrap(Compress::default())
+      .wrap(Csrf::new(tenancy_config.jwt_secret.clone()))
+      .wrap(Authorize::new(policy.clone()))
+      .wrap(RateLimiter::new(redis.clone(), rate_limit.clone()))
+      .wrap(Negotiate)
       .wrap(session)
       .wrap(prometheus.clone())
+      // outermost, so preflights are answered first and every response, errors of the
+      // middleware above included, carries the CORS headers
+      .wrap(cors(&_server_config.cors))
       .data(state)
-      .data(redis_addr)
       .configure(router::routes::dispatcher)
+      .service(web::scope("/api").configure(router::routes::api_dispatcher))
       .default_service(web::to(default_service))
   })
   .workers(app_config.web_server.worker_pool)
@@ -146,36 +220,23 @@ pub async fn start_server() -> std::io::Result<()> {
   .await
 }
 
-async fn default_service(state: web::Data<AppState>, _req: HttpRequest) -> HttpResponse {
-  let x: &str = _req
-    .headers()
-    .get(header::USER_AGENT)
-    .unwrap()
-    .to_str()
-    .unwrap();
-  match x.contains("curl") {
-    true => {
-      let err_string = object! {
-          error: 404,
-          message: "unknown path",
-          url: _req.path(),
-      };
-      HttpResponse::Ok().json(err_string.dump())
+/// Handle messages from the bus that are not replies to a request of this instance.
+fn bus_handler(cache: ResponseCache, events: EventHub, streams: StreamStore, jobs: JobStore) -> impl Fn(&str, &[u8]) {
+  move |key: &str, data: &[u8]| {
+    match key.split_once('.') {
+      Some((stream::KIND, id)) => return streams.push(id, data),
+      Some((job::KIND, id)) => return jobs.apply(id, data),
+      _ => (),
     }
-    false => {
-      let ctx = tera::Context::new();
-      match state.tmpl.render("404.html", &ctx) {
-        Ok(s) => return HttpResponse::Ok().content_type("text/html").body(s),
-        Err(e) => {
-          error!("failed to load template: {}", e);
-          let err_string = object! {
-              error: "404",
-              message: "unknown path",
-              url: _req.path(),
-          };
-          HttpResponse::Ok().json(err_string.dump())
-        }
-      }
+    // <model>.changed.<tenant>
+    let parts: Vec<&str> = key.split('.').collect();
+    if parts.len() == 3 && parts[1] == "changed" {
+      cache.invalidate(parts[0]);
     }
+    events.dispatch(key, data);
   }
 }
+
+async fn default_service(state: web::Data<AppState>, req: HttpRequest) -> HttpResponse {
+  negotiate::error_response(&req, Some(&state.tmpl), StatusCode::NOT_FOUND, "unknown path")
+}
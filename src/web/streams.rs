@@ -0,0 +1,159 @@
+use actix_redis::RespValue;
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+
+use super::config;
+use super::redis::Redis;
+use crate::shared_models::stream::StreamMessage;
+use crate::shared_models::tenant::Tenant;
+use crate::utils::helpers;
+
+const KEY_PREFIX: &str = "stream";
+
+/// Add a message to a stream that is still open, dropping the oldest beyond the limit.
+/// KEYS[1] is the stream hash, KEYS[2] the messages scored by sequence number, ARGV is the
+/// sequence number, the message, the message limit and the retention.
+const PUSH: &str = r#"
+if redis.call('EXISTS', KEYS[1]) == 0 then
+  return 0
+end
+redis.call('ZADD', KEYS[2], ARGV[1], ARGV[2])
+local excess = redis.call('ZCARD', KEYS[2]) - tonumber(ARGV[3])
+if excess > 0 then
+  local dropped = redis.call('ZRANGE', KEYS[2], excess - 1, excess - 1, 'WITHSCORES')
+  redis.call('ZREMRANGEBYRANK', KEYS[2], 0, excess - 1)
+  redis.call('HSET', KEYS[1], 'dropped', dropped[2])
+end
+redis.call('EXPIRE', KEYS[1], ARGV[4])
+redis.call('EXPIRE', KEYS[2], ARGV[4])
+return 1
+"#;
+
+#[derive(Debug, PartialEq)]
+pub enum ReadError {
+  /// Unknown, expired or owned by another tenant.
+  NotFound,
+  /// The messages after the requested position are no longer buffered.
+  Gone,
+  Unavailable(String),
+}
+
+/// Buffers streamed replies in redis so SSE clients can read them from any gateway and
+/// resume after a disconnect. A stream is kept for `retention` seconds after its last
+/// message.
+#[derive(Clone)]
+pub struct StreamStore {
+  redis: Redis,
+  retention: u32,
+  max_messages: usize,
+}
+
+impl StreamStore {
+  pub fn new(redis: Redis, config: &config::Streams) -> StreamStore {
+    StreamStore {
+      redis,
+      retention: config.retention as u32,
+      max_messages: config.max_messages,
+    }
+  }
+
+  /// Register a new stream of `tenant` and return its id, used as correlation id.
+  pub async fn open(&self, tenant: &Tenant) -> Result<String, String> {
+    let id = helpers::new_uuid();
+    let key = stream_key(&id);
+    self
+      .redis
+      .query(&[
+        b"HSET",
+        key.as_bytes(),
+        b"tenant",
+        tenant.as_str().as_bytes(),
+        b"dropped",
+        b"0",
+      ])
+      .await?;
+    self.redis.expire(&key, self.retention).await?;
+    Ok(id)
+  }
+
+  /// Append a message received from the bus. Messages of unknown streams are ignored.
+  pub fn push(&self, id: &str, data: &[u8]) {
+    let msg: StreamMessage = match bincode::deserialize(data) {
+      Ok(msg) => msg,
+      Err(e) => {
+        error!("failed to decode stream message, {}", e);
+        return;
+      }
+    };
+    let (key, messages) = (stream_key(id), messages_key(id));
+    let (seq, max, retention) = (
+      msg.seq.to_string(),
+      self.max_messages.to_string(),
+      self.retention.to_string(),
+    );
+    self.redis.send(&[
+      b"EVAL",
+      PUSH.as_bytes(),
+      b"2",
+      key.as_bytes(),
+      messages.as_bytes(),
+      seq.as_bytes(),
+      data,
+      max.as_bytes(),
+      retention.as_bytes(),
+    ]);
+  }
+
+  /// The buffered messages of a stream after sequence number `after`.
+  pub async fn read(&self, id: &str, tenant: &Tenant, after: u64) -> Result<Vec<StreamMessage>, ReadError> {
+    let key = stream_key(id);
+    let stream = self
+      .redis
+      .query(&[b"HMGET", key.as_bytes(), b"tenant", b"dropped"])
+      .await
+      .map_err(ReadError::Unavailable)?;
+    let (owner, dropped) = match stream {
+      RespValue::Array(values) if values.len() == 2 => (text(&values[0]), text(&values[1])),
+      value => return Err(ReadError::Unavailable(format!("unexpected redis reply {:?}", value))),
+    };
+    if owner.as_deref() != Some(tenant.as_str()) {
+      return Err(ReadError::NotFound);
+    }
+    if after < dropped.and_then(|d| d.parse::<u64>().ok()).unwrap_or(0) {
+      return Err(ReadError::Gone);
+    }
+    let min = format!("({}", after);
+    let messages = self
+      .redis
+      .query(&[b"ZRANGEBYSCORE", messages_key(id).as_bytes(), min.as_bytes(), b"+inf"])
+      .await
+      .map_err(ReadError::Unavailable)?;
+    match messages {
+      RespValue::Array(values) => Ok(
+        values
+          .iter()
+          .filter_map(|value| match value {
+            RespValue::BulkString(data) => bincode::deserialize(data).ok(),
+            _ => None,
+          })
+          .collect(),
+      ),
+      value => Err(ReadError::Unavailable(format!("unexpected redis reply {:?}", value))),
+    }
+  }
+}
+
+fn text(value: &RespValue) -> Option<String> {
+  match value {
+    RespValue::BulkString(value) => Some(String::from_utf8_lossy(value).to_string()),
+    _ => None,
+  }
+}
+
+fn stream_key(id: &str) -> String {
+  format!("{}:{}", KEY_PREFIX, id)
+}
+
+fn messages_key(id: &str) -> String {
+  format!("{}:{}:messages", KEY_PREFIX, id)
+}
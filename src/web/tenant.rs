@@ -0,0 +1,132 @@
+use actix_session::UserSession;
+use actix_web::{dev::Payload, error, web, Error, FromRequest, HttpMessage, HttpRequest};
+use futures::future::{ready, Ready};
+#[allow(unused_imports)]
+use log::{debug, error, info};
+
+use super::auth::{bearer_claims, Claims};
+use super::config;
+use super::redis::Redis;
+use super::router::get_user_session;
+use super::AppState;
+use crate::shared_models::tenant::Tenant;
+use crate::utils::helpers;
+
+const QUOTA_PREFIX: &str = "tenant_quota";
+const DAY: u64 = 86400;
+
+pub struct Tenancy {
+  config: config::Tenancy,
+  redis: Redis,
+}
+
+impl Tenancy {
+  pub fn new(config: config::Tenancy, redis: Redis) -> Tenancy {
+    Tenancy { config, redis }
+  }
+
+  /// Count one request against the tenant's daily quota. Errors when the quota is used up;
+  /// an unreachable Redis does not block requests.
+  pub async fn check_quota(&self, tenant: &Tenant) -> Result<(), String> {
+    let quota = *self.config.quotas.get(tenant.as_str()).unwrap_or(&self.config.daily_quota);
+    if quota == 0 {
+      return Ok(());
+    }
+    let key = format!("{}:{}:{}", QUOTA_PREFIX, tenant, helpers::get_time() / DAY);
+    let used = match self.redis.incr(&key).await {
+      Ok(used) => used as u64,
+      Err(e) => {
+        error!("tenant quota unavailable: {}", e);
+        return Ok(());
+      }
+    };
+    if used == 1 {
+      if let Err(e) = self.redis.expire(&key, DAY as u32).await {
+        error!("failed to expire tenant quota {}: {}", key, e);
+      }
+    }
+    if used > quota {
+      return Err(format!("daily quota of {} requests exceeded for tenant {}", quota, tenant));
+    }
+    Ok(())
+  }
+
+  pub fn jwt_secret(&self) -> Option<&str> {
+    self.config.jwt_secret.as_deref()
+  }
+
+  /// The tenant a request acts for. Anonymous requests have none, whatever headers they send.
+  pub fn resolve<R: HttpMessage + UserSession>(&self, req: &R) -> Result<Tenant, String> {
+    if let Some(session) = get_user_session(&req.get_session()) {
+      return Tenant::parse(&session.company_id);
+    }
+    let claims = bearer_claims(req.headers(), self.jwt_secret()).ok_or("no tenant for request")?;
+    let requested = match self.config.header.as_ref().and_then(|h| req.headers().get(h.as_str())) {
+      Some(value) => Some(value.to_str().map_err(|e| e.to_string())?),
+      None => None,
+    };
+    claimed_tenant(&claims, requested, &self.config.service_role)
+  }
+}
+
+/// The tenant of a bearer token: the one named in the tenant header for service
+/// principals, the token's own for everyone else, who may not name another.
+fn claimed_tenant(claims: &Claims, requested: Option<&str>, service_role: &str) -> Result<Tenant, String> {
+  let own = Tenant::parse(&claims.company_id);
+  match requested {
+    Some(requested) if claims.roles.iter().any(|r| r == service_role) => Tenant::parse(requested),
+    Some(requested) if own.as_ref().map_or(true, |own| own.as_str() != requested) => {
+      Err(format!("{} may not act for tenant {}", claims.sub, requested))
+    }
+    _ => own,
+  }
+}
+
+/// Handlers acting on tenant owned data take a `Tenant` argument; requests without a
+/// resolvable tenant are rejected with `401` before the handler runs.
+impl FromRequest for Tenant {
+  type Error = Error;
+  type Future = Ready<Result<Tenant, Error>>;
+  type Config = ();
+
+  fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+    let tenant = match req.app_data::<web::Data<AppState>>() {
+      Some(state) => state.tenancy.resolve(req),
+      None => Err("application state missing".to_string()),
+    };
+    ready(tenant.map_err(error::ErrorUnauthorized))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn claims(company_id: &str, roles: &[&str]) -> Claims {
+    Claims {
+      sub: String::from("user"),
+      company_id: company_id.to_string(),
+      roles: roles.iter().map(|r| r.to_string()).collect(),
+      exp: 0,
+    }
+  }
+
+  #[test]
+  fn users_act_for_their_own_tenant() {
+    let user = claims("acme", &["member"]);
+    assert_eq!(claimed_tenant(&user, None, "service"), Tenant::parse("acme"));
+    assert_eq!(claimed_tenant(&user, Some("acme"), "service"), Tenant::parse("acme"));
+    assert!(claimed_tenant(&user, Some("globex"), "service").is_err());
+  }
+
+  #[test]
+  fn services_act_for_the_requested_tenant() {
+    let service = claims("platform", &["service"]);
+    assert_eq!(
+      claimed_tenant(&service, Some("globex"), "service"),
+      Tenant::parse("globex")
+    );
+    assert_eq!(claimed_tenant(&service, None, "service"), Tenant::parse("platform"));
+    assert!(claimed_tenant(&service, Some("not a tenant"), "service").is_err());
+  }
+}